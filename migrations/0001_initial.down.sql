@@ -0,0 +1,5 @@
+DROP VIEW lec_qcount;
+DROP TABLE answers;
+DROP TABLE questions;
+DROP TABLE lectures;
+DROP TABLE users;
@@ -0,0 +1,3 @@
+UPDATE users SET policy = (SELECT policies.policy FROM policies WHERE policies.id = users.policy);
+UPDATE answers SET policy = (SELECT policies.policy FROM policies WHERE policies.id = answers.policy);
+DROP TABLE policies;
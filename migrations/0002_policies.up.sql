@@ -0,0 +1,4 @@
+-- Policies move to their own table, keyed by a hash of their JSON. The
+-- `policy` column of users and answers then holds that id; existing rows are
+-- converted by the migration code (see `convert_policies`).
+CREATE TABLE policies (id varchar(64), policy TEXT, PRIMARY KEY (id));
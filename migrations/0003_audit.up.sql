@@ -0,0 +1,5 @@
+-- Every insert, update and delete through the backend appends a row here
+-- (see src/backend/audit.rs). `row_key`, `old_values` and `new_values` are
+-- JSON objects keyed by column name; `policy` is the id of the changed row's
+-- policy, if its table is policied.
+CREATE TABLE audit_log (id varchar(32), at datetime, actor varchar(255), action varchar(16), tbl varchar(255), row_key text, old_values text, new_values text, policy TEXT, PRIMARY KEY (id));
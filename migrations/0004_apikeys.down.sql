@@ -0,0 +1,6 @@
+-- Each active key becomes a users row again; revoked keys are dropped.
+CREATE TABLE users_old (email varchar(255), apikey varchar(255), is_admin tinyint, policy TEXT, PRIMARY KEY (apikey));
+INSERT INTO users_old SELECT users.email, apikeys.apikey, users.is_admin, users.policy FROM users JOIN apikeys ON (apikeys.email = users.email) WHERE apikeys.revoked_at IS NULL;
+DROP TABLE users;
+ALTER TABLE users_old RENAME TO users;
+DROP TABLE apikeys;
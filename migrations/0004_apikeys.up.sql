@@ -0,0 +1,10 @@
+-- API keys move to their own table, so that a user can have several and each
+-- can be revoked. The keys derived from the email address and the server
+-- secret are kept, with an unknown creation time, and stay valid until they
+-- are revoked or rotated.
+CREATE TABLE apikeys (apikey varchar(255), email varchar(255), created_at datetime, revoked_at datetime, PRIMARY KEY (apikey));
+INSERT INTO apikeys SELECT apikey, email, NULL, NULL FROM users;
+CREATE TABLE users_new (email varchar(255), is_admin tinyint, policy TEXT, PRIMARY KEY (email));
+INSERT INTO users_new SELECT email, MAX(is_admin), MIN(policy) FROM users GROUP BY email;
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
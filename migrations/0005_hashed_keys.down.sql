@@ -0,0 +1,4 @@
+-- The keys cannot be recovered from their hashes, so all of them are lost;
+-- users have to request new ones.
+DROP TABLE apikeys;
+CREATE TABLE apikeys (apikey varchar(255), email varchar(255), created_at datetime, revoked_at datetime, PRIMARY KEY (apikey));
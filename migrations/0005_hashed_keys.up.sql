@@ -0,0 +1,5 @@
+-- API keys are stored as salted SHA-256 hashes, looked up by their first
+-- characters, which are kept in the clear. The existing keys are hashed by
+-- the migration code (see `convert_hashed_keys`), which then replaces
+-- `apikeys` with this table.
+CREATE TABLE apikeys_hashed (prefix varchar(16), hash varchar(64), salt varchar(32), email varchar(255), created_at datetime, revoked_at datetime, PRIMARY KEY (prefix));
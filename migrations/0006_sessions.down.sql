@@ -0,0 +1,5 @@
+DROP TABLE sessions;
+CREATE TABLE apikeys_old (prefix varchar(16), hash varchar(64), salt varchar(32), email varchar(255), created_at datetime, revoked_at datetime, PRIMARY KEY (prefix));
+INSERT INTO apikeys_old SELECT prefix, hash, salt, email, created_at, revoked_at FROM apikeys;
+DROP TABLE apikeys;
+ALTER TABLE apikeys_old RENAME TO apikeys;
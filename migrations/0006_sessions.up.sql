@@ -0,0 +1,6 @@
+-- API keys get an expiry time (NULL for keys that do not expire, such as the
+-- ones issued before this migration), and logins create server-side sessions.
+-- A session is stored under the SHA-256 hash of its cookie's token, with the
+-- prefix of the API key it was created with.
+ALTER TABLE apikeys ADD COLUMN expires_at datetime;
+CREATE TABLE sessions (id varchar(64), email varchar(255), key_prefix varchar(16), created_at datetime, last_seen datetime, ended_at datetime, PRIMARY KEY (id));
@@ -0,0 +1,2 @@
+DELETE FROM sessions WHERE key_prefix IS NULL;
+DROP TABLE login_links;
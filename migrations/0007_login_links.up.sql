@@ -0,0 +1,4 @@
+-- Users can log in with a one-time link sent to their email address instead
+-- of an API key. A link is stored under the SHA-256 hash of its token; the
+-- sessions it creates have no API key.
+CREATE TABLE login_links (id varchar(64), email varchar(255), created_at datetime, expires_at datetime, used_at datetime, PRIMARY KEY (id));
@@ -0,0 +1,3 @@
+-- The course roster uploaded by admins. Emails are stored in lower case;
+-- registration can be restricted to the addresses listed here.
+CREATE TABLE roster (email varchar(255), name varchar(255), section varchar(64), role varchar(32), PRIMARY KEY (email));
@@ -1,22 +1,38 @@
-use crate::apikey::ApiKey;
-use crate::backend::MySqlBackend;
+use crate::apikey::{self, ApiKey, ApiKeyError, KeyRow};
+use crate::backend::{AsyncBackend, BackendError, Page, QueryStat, Value};
 use crate::config::Config;
-use crate::questions::{LectureQuestion, LectureQuestionsContext};
-use mysql::from_value;
+use crate::questions::{LectureQuestion, LectureQuestionsContext, QuestionRow};
+use crate::roster::{self, RosterStatusRow};
+use crate::session::{self, SessionRow};
+use crate::templates;
+use beaver::filter::Context;
+use beaver::policy::Policy;
+use chrono::naive::NaiveDateTime;
+use chrono::Local;
 use rocket::form::Form;
+use rocket::futures::StreamExt;
 use rocket::http::Status;
-use rocket::outcome::IntoOutcome;
-use rocket::request::{self, FromRequest, Request};
-use rocket::response::Redirect;
+use rocket::outcome::{IntoOutcome, Outcome};
+use rocket::request::{self, FlashMessage, FromRequest, Request};
+use rocket::response::{Flash, Redirect};
 use rocket::State;
 use rocket_dyn_templates::Template;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
 
-pub(crate) struct Admin;
+/// Users shown per page on the users view.
+const USERS_PER_PAGE: u64 = 50;
+
+/// Entries shown per page on the audit log view.
+const AUDIT_ENTRIES_PER_PAGE: u64 = 50;
+
+pub(crate) struct Admin {
+    pub user: String,
+}
 
 #[derive(Debug)]
 pub(crate) enum AdminError {
+    /// There is no valid session (see `ApiKey`)
+    Session(ApiKeyError),
     Unauthorized,
 }
 
@@ -25,11 +41,17 @@ impl<'r> FromRequest<'r> for Admin {
     type Error = AdminError;
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let apikey = request.guard::<ApiKey>().await.unwrap();
+        let apikey = match request.guard::<ApiKey>().await {
+            Outcome::Success(apikey) => apikey,
+            Outcome::Failure((status, e)) => {
+                return Outcome::Failure((status, AdminError::Session(e)))
+            }
+            Outcome::Forward(()) => return Outcome::Forward(()),
+        };
         let cfg = request.guard::<&State<Config>>().await.unwrap();
 
         let res = if cfg.admins.contains(&apikey.user) {
-            Some(Admin)
+            Some(Admin { user: apikey.user })
         } else {
             None
         };
@@ -50,60 +72,208 @@ pub(crate) struct AdminLecAdd {
     lec_label: String,
 }
 
+#[derive(Debug, FromForm)]
+pub(crate) struct UserForm {
+    email: String,
+}
+
+#[derive(Debug, FromForm)]
+pub(crate) struct EndSessionForm {
+    id: String,
+}
+
+#[derive(Debug, FromForm)]
+pub(crate) struct RosterUploadForm {
+    csv: String,
+}
+
+pub(crate) struct Lecture {
+    pub id: u64,
+    pub label: String,
+}
+
+crate::from_row!(Lecture {
+    id: "id",
+    label: "label",
+});
+
 #[derive(Debug, Serialize)]
 pub(crate) struct User {
-    email: String,
-    apikey: String,
-    is_admin: u8,
+    pub email: String,
+    pub is_admin: bool,
+}
+
+crate::from_row!(User {
+    email: "email",
+    is_admin: "is_admin",
+});
+
+/// A row of the users view.
+#[derive(Debug, Serialize)]
+pub(crate) struct UserListRow {
+    pub email: String,
+    pub is_admin: bool,
+    pub active_keys: u64,
+    /// Comma-separated prefixes of the active keys
+    pub key_prefixes: Option<String>,
 }
 
+crate::from_row!(UserListRow {
+    email: "email",
+    is_admin: "is_admin",
+    active_keys: "active_keys",
+    key_prefixes: "key_prefixes",
+});
+
 #[derive(Serialize)]
 struct UserContext {
-    users: Vec<User>,
+    users: Vec<UserListRow>,
+    page: u64,
+    prev_page: Option<u64>,
+    next_page: Option<u64>,
+    flash: Option<String>,
+    parent: &'static str,
+}
+
+#[derive(Serialize)]
+struct SessionEntry {
+    id: String,
+    email: String,
+    key_prefix: Option<String>,
+    created: String,
+    last_seen: String,
+}
+
+#[derive(Serialize)]
+struct SessionsContext {
+    sessions: Vec<SessionEntry>,
+    flash: Option<String>,
+    parent: &'static str,
+}
+
+#[derive(Clone, Serialize)]
+struct RosterViewEntry {
+    email: String,
+    name: String,
+    section: String,
+    role: String,
+    registered: bool,
+}
+
+#[derive(Serialize)]
+struct RosterContext {
+    entries: Vec<RosterViewEntry>,
+    /// Students on the roster who never registered
+    unregistered: Vec<RosterViewEntry>,
+    flash: Option<String>,
+    parent: &'static str,
+}
+
+#[derive(Serialize)]
+struct StatsContext {
+    queries: Vec<QueryStat>,
+    parent: &'static str,
+}
+
+/// A row of the `audit_log` table.
+pub(crate) struct AuditEntry {
+    at: NaiveDateTime,
+    actor: String,
+    action: String,
+    table: String,
+    key: String,
+    old_values: Option<String>,
+    new_values: Option<String>,
+}
+
+crate::from_row!(AuditEntry {
+    at: "at",
+    actor: "actor",
+    action: "action",
+    table: "tbl",
+    key: "row_key",
+    old_values: "old_values",
+    new_values: "new_values",
+});
+
+#[derive(Serialize)]
+struct AuditEntryView {
+    at: String,
+    actor: String,
+    action: String,
+    table: String,
+    key: String,
+    old_values: Option<String>,
+    new_values: Option<String>,
+    redacted: bool,
+}
+
+#[derive(Serialize)]
+struct AuditContext {
+    entries: Vec<AuditEntryView>,
+    table: Option<String>,
+    actor: Option<String>,
+    page: u64,
+    prev_page: Option<u64>,
+    next_page: Option<u64>,
+    parent: &'static str,
+}
+
+#[derive(Serialize)]
+struct LectureDeleteContext {
+    lec_id: u8,
+    lec_label: String,
+    num_qs: usize,
+    num_answers: usize,
     parent: &'static str,
 }
 
 #[get("/")]
-pub(crate) fn lec_add(_adm: Admin) -> Template {
+pub(crate) fn lec_add(_adm: Admin, flash: Option<FlashMessage<'_>>) -> Template {
     let mut ctx = HashMap::new();
-    ctx.insert("parent", String::from("layout"));
-    Template::render("admin/lecadd", &ctx)
+    if let Some(flash) = flash {
+        ctx.insert("flash", flash.message().to_string());
+    }
+    ctx.insert("parent", String::from(templates::LAYOUT));
+    Template::render(templates::ADMIN_LECADD, &ctx)
 }
 
 #[post("/", data = "<data>")]
-pub(crate) fn lec_add_submit(
-    _adm: Admin,
+pub(crate) async fn lec_add_submit(
+    adm: Admin,
     data: Form<AdminLecAdd>,
-    backend: &State<Arc<Mutex<MySqlBackend>>>,
-) -> Redirect {
+    backend: &State<AsyncBackend>,
+) -> Result<Redirect, Flash<Redirect>> {
+    let backend = backend.acting_as(&adm.user);
     // insert into MySql if not exists
-    let mut bg = backend.lock().unwrap();
-    bg.insert(
+    backend.insert(
         "lectures",
         vec![
             (data.lec_id as u64).into(),
             data.lec_label.to_string().into(),
         ],
-    );
-    drop(bg);
+    ).await
+    .map_err(|e| e.flash(backend.log(), Redirect::to("/admin/lec/add")))?;
 
-    Redirect::to("/leclist")
+    Ok(Redirect::to("/leclist"))
 }
 
 #[get("/<num>")]
-pub(crate) fn lec(_adm: Admin, num: u8, backend: &State<Arc<Mutex<MySqlBackend>>>) -> Template {
-    let mut bg = backend.lock().unwrap();
-    let res = bg.query_exec("qs_by_lec", vec![(num as u64).into()]);
-    drop(bg);
+pub(crate) async fn lec(
+    _adm: Admin,
+    num: u8,
+    flash: Option<FlashMessage<'_>>,
+    backend: &State<AsyncBackend>,
+) -> Result<Template, BackendError> {
+    let res = backend
+        .query_as::<QuestionRow>("qs_by_lec", vec![(num as u64).into()])
+        .await?;
     let mut qs: Vec<_> = res
         .into_iter()
-        .map(|r| {
-            let id: u64 = from_value(r[1].clone());
-            LectureQuestion {
-                id: id,
-                prompt: from_value(r[2].clone()),
-                answer: None,
-            }
+        .map(|r| LectureQuestion {
+            id: r.q,
+            prompt: r.question,
+            answer: None,
         })
         .collect();
     qs.sort_by(|a, b| a.id.cmp(&b.id));
@@ -111,100 +281,445 @@ pub(crate) fn lec(_adm: Admin, num: u8, backend: &State<Arc<Mutex<MySqlBackend>>
     let ctx = LectureQuestionsContext {
         lec_id: num,
         questions: qs,
-        parent: "layout",
+        flash: flash.map(|f| f.message().to_string()),
+        parent: templates::LAYOUT,
     };
-    Template::render("admin/lec", &ctx)
+    Ok(Template::render(templates::ADMIN_LEC, &ctx))
 }
 
 #[post("/<num>", data = "<data>")]
-pub(crate) fn addq(
-    _adm: Admin,
+pub(crate) async fn addq(
+    adm: Admin,
     num: u8,
     data: Form<AddLectureQuestionForm>,
-    backend: &State<Arc<Mutex<MySqlBackend>>>,
-) -> Redirect {
-    let mut bg = backend.lock().unwrap();
-    bg.insert(
+    backend: &State<AsyncBackend>,
+) -> Result<Redirect, Flash<Redirect>> {
+    let backend = backend.acting_as(&adm.user);
+    backend.insert(
         "questions",
         vec![
             (num as u64).into(),
             (data.q_id as u64).into(),
             data.q_prompt.to_string().into(),
         ],
-    );
-    drop(bg);
+    ).await
+    .map_err(|e| e.flash(backend.log(), Redirect::to(format!("/admin/lec/{}", num))))?;
 
-    Redirect::to(format!("/admin/lec/{}", num))
+    Ok(Redirect::to(format!("/admin/lec/{}", num)))
 }
 
 #[get("/<num>/<qnum>")]
-pub(crate) fn editq(
+pub(crate) async fn editq(
     _adm: Admin,
     num: u8,
     qnum: u8,
-    backend: &State<Arc<Mutex<MySqlBackend>>>,
-) -> Template {
-    let mut bg = backend.lock().unwrap();
-    let res = bg.query_exec("qs_by_lec", vec![(num as u64).into()]);
-    drop(bg);
+    backend: &State<AsyncBackend>,
+) -> Result<Template, BackendError> {
+    let res = backend
+        .query_as::<QuestionRow>("qs_by_lec", vec![(num as u64).into()])
+        .await?;
 
     let mut ctx = HashMap::new();
     for r in res {
-        if r[1] == (qnum as u64).into() {
-            ctx.insert("lec_qprompt", from_value(r[2].clone()));
+        if r.q == qnum as u64 {
+            ctx.insert("lec_qprompt", r.question);
         }
     }
     ctx.insert("lec_id", format!("{}", num));
     ctx.insert("lec_qnum", format!("{}", qnum));
-    ctx.insert("parent", String::from("layout"));
-    Template::render("admin/lecedit", &ctx)
+    ctx.insert("parent", String::from(templates::LAYOUT));
+    Ok(Template::render(templates::ADMIN_LEC_EDIT, &ctx))
 }
 
 #[post("/editq/<num>", data = "<data>")]
-pub(crate) fn editq_submit(
-    _adm: Admin,
+pub(crate) async fn editq_submit(
+    adm: Admin,
     num: u8,
     data: Form<AddLectureQuestionForm>,
-    backend: &State<Arc<Mutex<MySqlBackend>>>,
-) -> Redirect {
-    let mut bg = backend.lock().unwrap();
-    bg.update(
+    backend: &State<AsyncBackend>,
+) -> Result<Redirect, Flash<Redirect>> {
+    let backend = backend.acting_as(&adm.user);
+    backend.update(
         "questions",
         vec![(num as u64).into(), (data.q_id as u64).into()],
         vec![(2, data.q_prompt.to_string().into())],
-    );
-    drop(bg);
+    ).await
+    .map_err(|e| e.flash(backend.log(), Redirect::to(format!("/admin/lec/{}", num))))?;
+
+    Ok(Redirect::to(format!("/admin/lec/{}", num)))
+}
+
+#[get("/<num>/delete")]
+pub(crate) async fn lec_delete(
+    _adm: Admin,
+    num: u8,
+    backend: &State<AsyncBackend>,
+) -> Result<Option<Template>, BackendError> {
+    let key: Value = (num as u64).into();
+    let lec = match backend
+        .query_as::<Lecture>("lecture", vec![key.clone()])
+        .await?
+        .pop()
+    {
+        Some(lec) => lec,
+        None => return Ok(None),
+    };
+    let qs = backend
+        .query_as::<QuestionRow>("qs_by_lec", vec![key.clone()])
+        .await?;
+    let mut answers = backend.query_stream("answers_by_lec", vec![key]);
+    let mut num_answers = 0;
+    while let Some(row) = answers.next().await {
+        row?;
+        num_answers += 1;
+    }
+
+    let ctx = LectureDeleteContext {
+        lec_id: num,
+        lec_label: lec.label,
+        num_qs: qs.len(),
+        num_answers: num_answers,
+        parent: templates::LAYOUT,
+    };
+    Ok(Some(Template::render(templates::ADMIN_LEC_DELETE, &ctx)))
+}
+
+// ranked below `editq_submit`, whose path would otherwise collide
+#[post("/<num>/delete", rank = 2)]
+pub(crate) async fn lec_delete_submit(
+    adm: Admin,
+    num: u8,
+    backend: &State<AsyncBackend>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let backend = backend.acting_as(&adm.user);
+    let key: Value = (num as u64).into();
+    backend
+        .with_transaction(move |be| {
+            be.delete_where("answers", vec![(1, key.clone())])?;
+            be.delete_where("questions", vec![(0, key.clone())])?;
+            be.delete("lectures", vec![key])
+        })
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to(format!("/admin/lec/{}", num))))?;
+
+    Ok(Flash::success(
+        Redirect::to("/leclist"),
+        format!("Deleted lecture {} with its questions and answers.", num),
+    ))
+}
+
+#[post("/<num>/<qnum>/delete")]
+pub(crate) async fn delq(
+    adm: Admin,
+    num: u8,
+    qnum: u8,
+    backend: &State<AsyncBackend>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let backend = backend.acting_as(&adm.user);
+    let lec: Value = (num as u64).into();
+    let q: Value = (qnum as u64).into();
+    backend
+        .with_transaction(move |be| {
+            be.delete_where("answers", vec![(1, lec.clone()), (2, q.clone())])?;
+            be.delete("questions", vec![lec, q])
+        })
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to(format!("/admin/lec/{}", num))))?;
+
+    Ok(Flash::success(
+        Redirect::to(format!("/admin/lec/{}", num)),
+        format!("Deleted question {} and its answers.", qnum),
+    ))
+}
+
+#[get("/?<page>")]
+pub(crate) async fn get_registered_users(
+    _adm: Admin,
+    page: Option<u64>,
+    flash: Option<FlashMessage<'_>>,
+    backend: &State<AsyncBackend>,
+) -> Result<Template, BackendError> {
+    let page_num = page.unwrap_or(1).max(1);
+    let page = Page::number(page_num, USERS_PER_PAGE);
+    let mut users = backend
+        .query_page_as::<UserListRow>("all_users", vec![], page)
+        .await?;
+    let has_more = page.truncate(&mut users);
+
+    let ctx = UserContext {
+        users: users,
+        page: page_num,
+        prev_page: if page_num > 1 { Some(page_num - 1) } else { None },
+        next_page: if has_more { Some(page_num + 1) } else { None },
+        flash: flash.map(|f| f.message().to_string()),
+        parent: templates::LAYOUT,
+    };
+    Ok(Template::render(templates::ADMIN_USERS, &ctx))
+}
+
+#[post("/delete", data = "<data>")]
+pub(crate) async fn delete_user(
+    adm: Admin,
+    data: Form<UserForm>,
+    backend: &State<AsyncBackend>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    if data.email == adm.user {
+        return Err(Flash::error(
+            Redirect::to("/admin/users"),
+            "You cannot delete your own account.",
+        ));
+    }
+    let backend = backend.acting_as(&adm.user);
+    let email = data.email.clone();
+    let deleted = backend
+        .with_transaction(move |be| {
+            let key: Value = email.into();
+            if be.query_as::<User>("user_by_email", vec![key.clone()])?.is_empty() {
+                return Ok(false);
+            }
+            be.delete_where("answers", vec![(0, key.clone())])?;
+            be.delete_where("apikeys", vec![(3, key.clone())])?;
+            be.delete_where("sessions", vec![(1, key.clone())])?;
+            be.delete_where("login_links", vec![(1, key.clone())])?;
+            be.delete("users", vec![key])?;
+            Ok(true)
+        })
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to("/admin/users")))?;
+
+    if deleted {
+        Ok(Flash::success(
+            Redirect::to("/admin/users"),
+            format!("Deleted user {} and their answers.", data.email),
+        ))
+    } else {
+        Err(Flash::error(Redirect::to("/admin/users"), "No such user."))
+    }
+}
+
+/// Revokes all of a user's API keys, e.g. after one has leaked.
+#[post("/revoke", data = "<data>")]
+pub(crate) async fn revoke_keys(
+    adm: Admin,
+    data: Form<UserForm>,
+    backend: &State<AsyncBackend>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let backend = backend.acting_as(&adm.user);
+    let email = data.email.clone();
+    let revoked = backend
+        .with_transaction(move |be| {
+            let keys = be.query_as::<KeyRow>("apikeys_by_email", vec![email.into()])?;
+            let mut revoked = 0;
+            for k in keys.into_iter().filter(|k| k.revoked_at.is_none()) {
+                apikey::revoke_key(be, &k.prefix)?;
+                revoked += 1;
+            }
+            Ok(revoked)
+        })
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to("/admin/users")))?;
 
-    Redirect::to(format!("/admin/lec/{}", num))
+    Ok(Flash::success(
+        Redirect::to("/admin/users"),
+        format!("Revoked {} API key(s) of {}.", revoked, data.email),
+    ))
 }
 
 #[get("/")]
-pub(crate) fn get_registered_users(
+pub(crate) fn query_stats(_adm: Admin, backend: &State<AsyncBackend>) -> Template {
+    let ctx = StatsContext {
+        queries: backend.stats().snapshot(),
+        parent: templates::LAYOUT,
+    };
+    Template::render(templates::ADMIN_STATS, &ctx)
+}
+
+#[get("/?<page>&<table>&<actor>")]
+pub(crate) async fn audit_log(
+    adm: Admin,
+    page: Option<u64>,
+    table: Option<String>,
+    actor: Option<String>,
+    backend: &State<AsyncBackend>,
+) -> Result<Template, BackendError> {
+    // the filter form submits empty fields for "any"
+    let table = table.filter(|t| !t.is_empty());
+    let actor = actor.filter(|a| !a.is_empty());
+    let page_num = page.unwrap_or(1).max(1);
+    let page = Page::number(page_num, AUDIT_ENTRIES_PER_PAGE);
+    let keys: Vec<Value> = vec![
+        table.clone().into(),
+        table.clone().into(),
+        actor.clone().into(),
+        actor.clone().into(),
+    ];
+    let mut entries = backend
+        .query_page_as_maybe_policied::<AuditEntry>("audit_log", keys, page)
+        .await?;
+    let has_more = page.truncate(&mut entries);
+
+    // entries of policied rows are only shown if the row itself may be
+    let ctxt = Context::CustomContext(Box::new(adm));
+    let entries = entries
+        .into_iter()
+        .map(|(e, policy)| {
+            let redacted = match policy {
+                Some(p) => p.check(&ctxt).is_err(),
+                None => false,
+            };
+            AuditEntryView {
+                at: e.at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                actor: e.actor,
+                action: e.action,
+                table: e.table,
+                key: e.key,
+                old_values: if redacted { None } else { e.old_values },
+                new_values: if redacted { None } else { e.new_values },
+                redacted: redacted,
+            }
+        })
+        .collect();
+
+    let ctx = AuditContext {
+        entries: entries,
+        table: table,
+        actor: actor,
+        page: page_num,
+        prev_page: if page_num > 1 { Some(page_num - 1) } else { None },
+        next_page: if has_more { Some(page_num + 1) } else { None },
+        parent: templates::LAYOUT,
+    };
+    Ok(Template::render(templates::ADMIN_AUDIT, &ctx))
+}
+
+#[get("/")]
+pub(crate) async fn sessions(
     _adm: Admin,
-    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    flash: Option<FlashMessage<'_>>,
+    backend: &State<AsyncBackend>,
     config: &State<Config>,
-) -> Template {
-    let mut bg = backend.lock().unwrap();
-    let res = bg.query_exec("all_users", vec![]);
-    drop(bg);
+) -> Result<Template, BackendError> {
+    let now = Local::now().naive_local();
+    let fmt = |t: NaiveDateTime| t.format("%Y-%m-%d %H:%M").to_string();
+    // sessions that timed out are only marked as ended on their next use
+    let sessions = backend
+        .query_as::<SessionRow>("active_sessions", vec![])
+        .await?
+        .into_iter()
+        .filter(|s| !s.is_expired(config, now))
+        .map(|s| SessionEntry {
+            id: s.id,
+            email: s.email,
+            key_prefix: s.key_prefix,
+            created: fmt(s.created_at),
+            last_seen: fmt(s.last_seen),
+        })
+        .collect();
 
-    let users: Vec<_> = res
+    let ctx = SessionsContext {
+        sessions: sessions,
+        flash: flash.map(|f| f.message().to_string()),
+        parent: templates::LAYOUT,
+    };
+    Ok(Template::render(templates::ADMIN_SESSIONS, &ctx))
+}
+
+#[post("/end", data = "<data>")]
+pub(crate) async fn end_session(
+    adm: Admin,
+    data: Form<EndSessionForm>,
+    backend: &State<AsyncBackend>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let id = data.id.clone();
+    backend
+        .acting_as(&adm.user)
+        .run(move |be| session::end(be, &id))
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to("/admin/sessions")))?;
+
+    Ok(Flash::success(Redirect::to("/admin/sessions"), "Session ended."))
+}
+
+#[post("/end_user", data = "<data>")]
+pub(crate) async fn end_user_sessions(
+    adm: Admin,
+    data: Form<UserForm>,
+    backend: &State<AsyncBackend>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let email = data.email.clone();
+    let ended = backend
+        .acting_as(&adm.user)
+        .with_transaction(move |be| {
+            let sessions = be.query_as::<SessionRow>("sessions_by_email", vec![email.into()])?;
+            for s in &sessions {
+                session::end(be, &s.id)?;
+            }
+            Ok(sessions.len())
+        })
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to("/admin/sessions")))?;
+
+    Ok(Flash::success(
+        Redirect::to("/admin/sessions"),
+        format!("Ended {} session(s) of {}.", ended, data.email),
+    ))
+}
+
+#[get("/")]
+pub(crate) async fn roster(
+    _adm: Admin,
+    flash: Option<FlashMessage<'_>>,
+    backend: &State<AsyncBackend>,
+) -> Result<Template, BackendError> {
+    let entries: Vec<_> = backend
+        .query_as::<RosterStatusRow>("roster_status", vec![])
+        .await?
         .into_iter()
-        .map(|r| User {
-            email: from_value(r[0].clone()),
-            apikey: from_value(r[2].clone()),
-            is_admin: if config.admins.contains(&from_value(r[0].clone())) {
-                1
-            } else {
-                0
-            }, // r[1].clone().into(), this type conversion does not work
+        .map(|r| RosterViewEntry {
+            email: r.email,
+            name: r.name,
+            section: r.section,
+            role: r.role,
+            registered: r.registered > 0,
         })
         .collect();
+    let unregistered = entries
+        .iter()
+        .filter(|e| !e.registered && e.role == "student")
+        .cloned()
+        .collect();
 
-    let ctx = UserContext {
-        users: users,
-        parent: "layout",
+    let ctx = RosterContext {
+        entries: entries,
+        unregistered: unregistered,
+        flash: flash.map(|f| f.message().to_string()),
+        parent: templates::LAYOUT,
     };
-    Template::render("admin/users", &ctx)
+    Ok(Template::render(templates::ADMIN_ROSTER, &ctx))
 }
 
+/// Replaces the roster with an uploaded CSV file.
+#[post("/", data = "<data>")]
+pub(crate) async fn upload_roster(
+    adm: Admin,
+    data: Form<RosterUploadForm>,
+    backend: &State<AsyncBackend>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let entries = roster::parse(&data.csv).map_err(|e| {
+        Flash::error(
+            Redirect::to("/admin/roster"),
+            format!("The roster was not uploaded: {}.", e),
+        )
+    })?;
+    let n = entries.len();
+    backend
+        .acting_as(&adm.user)
+        .with_transaction(move |be| roster::replace(be, entries))
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to("/admin/roster")))?;
+
+    Ok(Flash::success(
+        Redirect::to("/admin/roster"),
+        format!("Uploaded a roster of {} people.", n),
+    ))
+}
@@ -1,26 +1,46 @@
-use crate::backend::MySqlBackend;
+use crate::admin::User;
+use crate::backend::{self, encode_policy, AsyncBackend, Backend, BackendError, Value};
 use crate::config::Config;
 use crate::email;
+use crate::ratelimit::{Action, Rejection, Target, Throttle};
+use crate::roster::{self, Registration};
+use crate::session::{self, SESSION_COOKIE};
+use crate::templates;
+use chrono::naive::NaiveDateTime;
+use chrono::{Duration, Local};
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
-use mysql::from_value;
+use crypto::util::fixed_time_eq;
+use rand::Rng;
 use rocket::form::Form;
 use rocket::http::Status;
 use rocket::http::{Cookie, CookieJar};
-use rocket::outcome::IntoOutcome;
-use rocket::request::{self, FromRequest, Request};
-use rocket::response::Redirect;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FlashMessage, FromRequest, Request};
+use rocket::response::{Flash, Redirect};
 use rocket::State;
 use rocket_dyn_templates::Template;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
 
-use beaver::generic_policied::AsPolicied;
+/// Random bytes in an API key.
+const KEY_BYTES: usize = 32;
 
-/// (username, apikey)
+/// Random bytes in the salt each key is hashed with.
+const SALT_BYTES: usize = 16;
+
+/// Leading characters of a key that are stored in the clear, to look the
+/// key up by and to tell a user's keys apart.
+pub(crate) const KEY_PREFIX_LEN: usize = 12;
+
+/// Attempts to issue a key whose prefix is not taken yet.
+const ISSUE_ATTEMPTS: usize = 5;
+
+/// The logged-in user, with the prefix of the API key they logged in with
+/// (`None` if they used a login link) and the id of their session
 pub(crate) struct ApiKey {
     pub user: String,
-    pub key: String,
+    pub prefix: Option<String>,
+    pub session: String,
 }
 
 #[derive(Debug, FromForm)]
@@ -33,10 +53,70 @@ pub(crate) struct ApiKeySubmit {
     key: String,
 }
 
+#[derive(Debug, FromForm)]
+pub(crate) struct RevokeKeyForm {
+    prefix: String,
+}
+
+/// A key listed on the keys page.
+pub(crate) struct KeyRow {
+    pub prefix: String,
+    pub created_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+crate::from_row!(KeyRow {
+    prefix: "prefix",
+    created_at: "created_at",
+    revoked_at: "revoked_at",
+    expires_at: "expires_at",
+});
+
+/// An active key with the prefix of the one being checked.
+pub(crate) struct KeyCandidate {
+    pub salt: String,
+    pub hash: String,
+    pub email: String,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+crate::from_row!(KeyCandidate {
+    salt: "salt",
+    hash: "hash",
+    email: "email",
+    expires_at: "expires_at",
+});
+
+impl KeyCandidate {
+    pub fn is_expired(&self, now: NaiveDateTime) -> bool {
+        self.expires_at.map(|t| t <= now).unwrap_or(false)
+    }
+}
+
+#[derive(Serialize)]
+struct KeyEntry {
+    prefix: String,
+    created: Option<String>,
+    revoked: Option<String>,
+    expires: Option<String>,
+    current: bool,
+}
+
+#[derive(Serialize)]
+struct KeysContext {
+    email: String,
+    keys: Vec<KeyEntry>,
+    new_key: Option<String>,
+    flash: Option<String>,
+    parent: &'static str,
+}
+
 #[derive(Debug)]
 pub(crate) enum ApiKeyError {
     Ambiguous,
     Missing,
+    Expired,
     BackendFailure,
 }
 
@@ -68,117 +148,492 @@ impl<'r> FromRequest<'r> for ApiKey {
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
         let be = request
-            .guard::<&State<Arc<Mutex<MySqlBackend>>>>()
+            .guard::<&State<AsyncBackend>>()
             .await
             .unwrap();
-        request
+        let cfg = request.guard::<&State<Config>>().await.unwrap();
+        let token: Option<String> = request
             .cookies()
-            .get("apikey")
-            .and_then(|cookie| cookie.value().parse().ok())
-            .and_then(|key: String| match check_api_key(&be, &key) {
-                Ok(user) => Some(ApiKey { user, key }),
-                Err(_) => None,
-            })
-            .into_outcome((Status::Unauthorized, ApiKeyError::Missing))
+            .get(SESSION_COOKIE)
+            .map(|cookie| cookie.value().to_string());
+        match token {
+            Some(token) => match session::check(&be, &cfg, &token).await {
+                Ok(s) => Outcome::Success(ApiKey {
+                    user: s.email,
+                    prefix: s.key_prefix,
+                    session: s.id,
+                }),
+                Err(ApiKeyError::BackendFailure) => {
+                    Outcome::Failure((Status::ServiceUnavailable, ApiKeyError::BackendFailure))
+                }
+                Err(e) => Outcome::Failure((Status::Unauthorized, e)),
+            },
+            None => Outcome::Failure((Status::Unauthorized, ApiKeyError::Missing)),
+        }
     }
 }
 
+pub(crate) fn random_hex<R: Rng>(rng: &mut R, len: usize) -> String {
+    (0..len).map(|_| format!("{:02x}", rng.gen::<u8>())).collect()
+}
+
+/// The part of `key` stored in the clear.
+pub(crate) fn key_prefix(key: &str) -> &str {
+    match key.char_indices().nth(KEY_PREFIX_LEN) {
+        Some((i, _)) => &key[..i],
+        None => key,
+    }
+}
+
+/// The hash `key` is stored as.
+pub(crate) fn hash_key(salt: &str, key: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(salt);
+    hasher.input_str(key);
+    hasher.result_str()
+}
+
+/// Hashes `key` with a new random salt, and returns the hash and the salt.
+pub(crate) fn hash_new_key(key: &str) -> (String, String) {
+    let salt = random_hex(&mut rand::thread_rng(), SALT_BYTES);
+    (hash_key(&salt, key), salt)
+}
+
+/// Creates a new random API key for `email`, valid until it is revoked or
+/// `lifetime_days` have passed (if not 0). Only its prefix and hash are
+/// stored; the key itself is returned to be given to the user.
+pub(crate) fn issue_key(
+    be: &dyn Backend,
+    lifetime_days: u64,
+    email: &str,
+) -> backend::Result<String> {
+    let mut attempts = 0;
+    loop {
+        let key = random_hex(&mut rand::thread_rng(), KEY_BYTES);
+        let (hash, salt) = hash_new_key(&key);
+        let now = Local::now().naive_local();
+        let expires_at: Value = match lifetime_days {
+            0 => Value::NULL,
+            days => (now + Duration::days(days as i64)).into(),
+        };
+        let row = vec![
+            key_prefix(&key).into(),
+            hash.into(),
+            salt.into(),
+            email.into(),
+            now.into(),
+            Value::NULL,
+            expires_at,
+        ];
+        match be.insert("apikeys", row) {
+            Ok(()) => return Ok(key),
+            // another key has the same prefix
+            Err(BackendError::ConstraintViolation(_)) if attempts + 1 < ISSUE_ATTEMPTS => {
+                attempts += 1
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub(crate) fn revoke_key(be: &dyn Backend, prefix: &str) -> backend::Result<()> {
+    be.update(
+        "apikeys",
+        vec![prefix.into()],
+        vec![(5, Local::now().naive_local().into())],
+    )
+}
+
 #[post("/", data = "<data>")]
-pub(crate) fn generate(
+pub(crate) async fn generate(
     data: Form<ApiKeyRequest>,
-    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    throttle: Throttle<'_>,
+    backend: &State<AsyncBackend>,
     config: &State<Config>,
-) -> Template {
-    // generate an API key from email address
-    let mut hasher = Sha256::new();
-    hasher.input_str(&data.email);
-    // add a secret to make API keys unforgeable without access to the server
-    hasher.input_str(&config.secret);
-    let hash = hasher.result_str();
-
-    let is_admin = if config.admins.contains(&data.email) {
+) -> Result<Template, Rejection> {
+    let email = roster::normalize(&data.email);
+    throttle.check(Action::Generate, Some(Target::Address(&email)))?;
+    let is_admin: Value = if config.admins.iter().any(|a| roster::normalize(a) == email) {
         1.into()
     } else {
         0.into()
     };
+    let policy = encode_policy(&UserInfoPolicy {
+        user_id: email.clone(),
+    })
+    .map_err(|e| e.flash(backend.log(), Redirect::to("/login")))?;
 
-    // insert into MySql if not exists
-    let mut bg = backend.lock().unwrap();
-    bg.insert_policied(
-        "users",
-        vec![data.email.as_str().into(), hash.as_str().into(), is_admin].policied_with(
-            Box::new(UserInfoPolicy { user_id: hash.clone() })
-        )
-    );
+    // every request gets a new key; earlier ones stay valid until revoked
+    let addr = email.clone();
+    let lifetime = config.apikey_lifetime_days;
+    let registration = Registration::new(config);
+    let key = backend
+        .acting_as(&email)
+        .with_transaction(move |be| {
+            if be
+                .query_as::<User>("user_by_email", vec![addr.as_str().into()])?
+                .is_empty()
+            {
+                if !registration.allows(be, &addr)? {
+                    return Ok(None);
+                }
+                let rec = be.with_policy(vec![addr.as_str().into(), is_admin], policy)?;
+                be.insert("users", rec)?;
+            }
+            issue_key(be, lifetime, &addr).map(Some)
+        })
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to("/login")))?;
+    let key = match key {
+        Some(key) => key,
+        None => {
+            info!(backend.log(), "refused to register {}", email);
+            let mut ctx = HashMap::new();
+            ctx.insert("CLASS_ID", config.class.clone());
+            ctx.insert("apikey_email", email.clone());
+            ctx.insert("parent", templates::LAYOUT.into());
+            return Err(Rejection::Forbidden(Template::render(templates::APIKEY_REJECTED, &ctx)));
+        }
+    };
 
     if config.send_emails {
-        email::send(
-            bg.log.clone(),
+        let res = email::send(
+            backend.log().clone(),
+            config.mail_dir.as_deref(),
             "no-reply@csci2390-submit.cs.brown.edu".into(),
-            vec![data.email.clone()],
+            vec![email.clone()],
             format!("{} API key", config.class),
             format!(
                 "Your {} API key is: {}\n",
                 config.class,
-                hash.as_str(),
+                key.as_str(),
             ),
-        )
-        .expect("failed to send API key email");
+        );
+        if let Err(e) = res {
+            error!(backend.log(), "failed to send API key to {}: {}", email, e);
+            return Err(Flash::error(
+                Redirect::to("/login"),
+                "Your API key could not be sent. Please try again later.",
+            )
+            .into());
+        }
     }
-    drop(bg);
 
     // return to user
     let mut ctx = HashMap::new();
-    ctx.insert("apikey_email", data.email.clone());
-    ctx.insert("parent", "layout".into());
-    Template::render("apikey/generate", &ctx)
+    ctx.insert("apikey_email", email.clone());
+    ctx.insert("parent", templates::LAYOUT.into());
+    Ok(Template::render(templates::APIKEY_GENERATE, &ctx))
 }
 
-pub(crate) fn check_api_key(
-    backend: &Arc<Mutex<MySqlBackend>>,
+pub(crate) async fn check_api_key(
+    backend: &AsyncBackend,
     key: &str,
 ) -> Result<String, ApiKeyError> {
-    let mut bg = backend.lock().unwrap();
-    let rs = bg.query_exec("users_by_apikey", vec![key.into()]);
-    drop(bg);
+    let prefix = key_prefix(key);
+    let candidates = match backend
+        .query_as::<KeyCandidate>("apikeys_by_prefix", vec![prefix.into()])
+        .await
+    {
+        Ok(rs) => rs,
+        Err(e) => {
+            error!(backend.log(), "failed to look up API key: {}", e);
+            return Err(ApiKeyError::BackendFailure);
+        }
+    };
+    // compare every candidate, in time independent of where they differ
+    let mut rs: Vec<_> = candidates
+        .into_iter()
+        .filter(|c| fixed_time_eq(hash_key(&c.salt, key).as_bytes(), c.hash.as_bytes()))
+        .collect();
     if rs.len() < 1 {
         Err(ApiKeyError::Missing)
+    } else if rs[0].is_expired(Local::now().naive_local()) {
+        Err(ApiKeyError::Expired)
     } else if rs.len() > 1 {
         Err(ApiKeyError::Ambiguous)
-    } else if rs.len() >= 1 {
-        // user email
-        Ok(from_value::<String>(rs[0][0].clone()))
     } else {
-        Err(ApiKeyError::BackendFailure)
+        Ok(rs.remove(0).email)
     }
 }
 
 #[post("/", data = "<data>")]
-pub(crate) fn check(
+pub(crate) async fn check(
     data: Form<ApiKeySubmit>,
+    throttle: Throttle<'_>,
     cookies: &CookieJar<'_>,
-    backend: &State<Arc<Mutex<MySqlBackend>>>,
-) -> Redirect {
-    // check that the API key exists and set cookie
-    let res = check_api_key(&*backend, &data.key);
-    match res {
+    backend: &State<AsyncBackend>,
+    config: &State<Config>,
+) -> Result<Redirect, Rejection> {
+    // keys are not tied to an address until they are found, so guesses are
+    // limited per client and per key prefix
+    throttle.check(Action::Check, Some(Target::KeyPrefix(&key_prefix(&data.key))))?;
+
+    // check that the API key is valid and start a session
+    let log = backend.log();
+    let res = check_api_key(&*backend, &data.key).await;
+    let msg = match res {
+        // already logged by check_api_key
         Err(ApiKeyError::BackendFailure) => {
-            eprintln!("Problem communicating with MySql backend");
+            "The database is currently unavailable. Please try again later."
         }
         Err(ApiKeyError::Missing) => {
-            eprintln!("No such API key: {}", data.key);
+            info!(log, "login with unknown API key {}...", key_prefix(&data.key));
+            "No such API key."
         }
         Err(ApiKeyError::Ambiguous) => {
-            eprintln!("Ambiguous API key: {}", data.key);
+            warn!(log, "login with ambiguous API key {}...", key_prefix(&data.key));
+            "No such API key."
         }
-        Ok(_) => (),
-    }
+        Err(ApiKeyError::Expired) => {
+            info!(log, "login with expired API key {}...", key_prefix(&data.key));
+            "This API key has expired. Please request a new one."
+        }
+        Ok(_) => "",
+    };
+
+    let email = match res {
+        Ok(email) => email,
+        Err(_) => return Err(Flash::error(Redirect::to("/login"), msg).into()),
+    };
+    let prefix = key_prefix(&data.key).to_string();
+    let token = backend
+        .acting_as(&email)
+        .run(move |be| session::create(be, &email, Some(&prefix)))
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to("/login")))?;
+    // the key itself was kept in a cookie before there were sessions
+    cookies.remove(Cookie::named("apikey"));
+    cookies.add(session::cookie(config, token));
+    Ok(Redirect::to("/leclist"))
+}
+
+/// Renders the keys page for the user logged in with the key with prefix
+/// `current`, if any.
+async fn keys_page(
+    backend: &AsyncBackend,
+    email: &str,
+    current: Option<&str>,
+    new_key: Option<String>,
+    flash: Option<String>,
+) -> Result<Template, BackendError> {
+    let fmt = |t: NaiveDateTime| t.format("%Y-%m-%d %H:%M").to_string();
+    let keys = backend
+        .query_as::<KeyRow>("apikeys_by_email", vec![email.into()])
+        .await?
+        .into_iter()
+        .map(|k| KeyEntry {
+            current: Some(k.prefix.as_str()) == current,
+            prefix: k.prefix,
+            created: k.created_at.map(fmt),
+            revoked: k.revoked_at.map(fmt),
+            expires: k.expires_at.map(fmt),
+        })
+        .collect();
+
+    let ctx = KeysContext {
+        email: email.to_string(),
+        keys: keys,
+        new_key: new_key,
+        flash: flash,
+        parent: templates::LAYOUT,
+    };
+    Ok(Template::render(templates::APIKEY_KEYS, &ctx))
+}
+
+#[get("/")]
+pub(crate) async fn keys(
+    apikey: ApiKey,
+    flash: Option<FlashMessage<'_>>,
+    backend: &State<AsyncBackend>,
+) -> Result<Template, BackendError> {
+    let flash = flash.map(|f| f.message().to_string());
+    keys_page(backend, &apikey.user, apikey.prefix.as_deref(), None, flash).await
+}
+
+/// Replaces the key the user is logged in with by a new one. Users logged in
+/// with a login link are given a new key and logged in with it.
+#[post("/rotate")]
+pub(crate) async fn rotate(
+    apikey: ApiKey,
+    backend: &State<AsyncBackend>,
+    config: &State<Config>,
+) -> Result<Template, Flash<Redirect>> {
+    let email = apikey.user.clone();
+    let old = apikey.prefix.clone();
+    let session_id = apikey.session.clone();
+    let lifetime = config.apikey_lifetime_days;
+    let key = backend
+        .acting_as(&apikey.user)
+        .with_transaction(move |be| {
+            let key = issue_key(be, lifetime, &email)?;
+            if let Some(ref old) = old {
+                revoke_key(be, &old)?;
+            }
+            // the session stays logged in, now with the new key
+            session::set_key(be, &session_id, key_prefix(&key))?;
+            Ok(key)
+        })
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to("/apikey")))?;
 
-    if res.is_err() {
-        Redirect::to("/")
+    keys_page(backend, &apikey.user, Some(key_prefix(&key)), Some(key.clone()), None)
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to("/apikey")))
+}
+
+#[post("/revoke", data = "<data>")]
+pub(crate) async fn revoke(
+    apikey: ApiKey,
+    data: Form<RevokeKeyForm>,
+    cookies: &CookieJar<'_>,
+    backend: &State<AsyncBackend>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let email = apikey.user.clone();
+    let prefix = data.prefix.clone();
+    let current = apikey.prefix.as_ref() == Some(&data.prefix);
+    let session_id = apikey.session.clone();
+    let revoked = backend
+        .acting_as(&apikey.user)
+        .with_transaction(move |be| {
+            // users may only revoke their own keys
+            let owned = be
+                .query_as::<KeyRow>("apikeys_by_email", vec![email.into()])?
+                .into_iter()
+                .any(|k| k.prefix == prefix && k.revoked_at.is_none());
+            if owned {
+                revoke_key(be, &prefix)?;
+                if current {
+                    session::end(be, &session_id)?;
+                }
+            }
+            Ok(owned)
+        })
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to("/apikey")))?;
+
+    if !revoked {
+        Err(Flash::error(Redirect::to("/apikey"), "No such API key."))
+    } else if current {
+        cookies.remove(Cookie::named(SESSION_COOKIE));
+        Ok(Flash::success(
+            Redirect::to("/login"),
+            "The API key you were logged in with has been revoked.",
+        ))
     } else {
-        let cookie = Cookie::build("apikey", data.key.clone()).path("/").finish();
-        cookies.add(cookie);
-        Redirect::to("/leclist")
+        Ok(Flash::success(Redirect::to("/apikey"), "API key revoked."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SqliteBackend;
+    use std::sync::Arc;
+
+    fn backend() -> SqliteBackend {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        be
+    }
+
+    fn expiry(be: &dyn Backend, key: &str) -> Option<NaiveDateTime> {
+        be.query_as::<KeyRow>("apikeys_by_email", vec!["a@example.com".into()])
+            .unwrap()
+            .into_iter()
+            .find(|k| k.prefix == key_prefix(key))
+            .unwrap()
+            .expires_at
+    }
+
+    #[test]
+    fn issues_distinct_random_keys() {
+        let be = backend();
+        let first = issue_key(&be, 30, "a@example.com").unwrap();
+        let second = issue_key(&be, 0, "a@example.com").unwrap();
+        assert_eq!(first.len(), 2 * KEY_BYTES);
+        assert_ne!(first, second);
+        assert_ne!(key_prefix(&first), key_prefix(&second));
+
+        let db: &dyn Backend = &be;
+        let expires = expiry(db, &first).unwrap();
+        let lifetime = expires - Local::now().naive_local();
+        assert!(lifetime > Duration::days(29) && lifetime <= Duration::days(30));
+        assert_eq!(expiry(db, &second), None);
+    }
+
+    #[rocket::async_test]
+    async fn revoked_keys_are_refused() {
+        let be = backend();
+        let key = issue_key(&be, 0, "a@example.com").unwrap();
+        let other = issue_key(&be, 0, "a@example.com").unwrap();
+        let backend = AsyncBackend::new(Arc::new(be));
+        assert_eq!(check_api_key(&backend, &key).await.unwrap(), "a@example.com");
+
+        let prefix = key_prefix(&key).to_string();
+        backend.run(move |be| revoke_key(be, &prefix)).await.unwrap();
+        assert!(matches!(check_api_key(&backend, &key).await, Err(ApiKeyError::Missing)));
+        assert!(check_api_key(&backend, &other).await.is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn expired_keys_are_refused() {
+        let be = backend();
+        let key = random_hex(&mut rand::thread_rng(), KEY_BYTES);
+        let (hash, salt) = hash_new_key(&key);
+        let issued = Local::now().naive_local() - Duration::days(2);
+        let row = vec![
+            key_prefix(&key).into(),
+            hash.into(),
+            salt.into(),
+            "a@example.com".into(),
+            issued.into(),
+            Value::NULL,
+            (issued + Duration::days(1)).into(),
+        ];
+        be.insert("apikeys", row).unwrap();
+        let backend = AsyncBackend::new(Arc::new(be));
+        assert!(matches!(check_api_key(&backend, &key).await, Err(ApiKeyError::Expired)));
+    }
+
+    #[test]
+    fn prefix_is_the_leading_characters() {
+        assert_eq!(key_prefix("0123456789abcdef"), "0123456789ab");
+        assert_eq!(key_prefix("0123"), "0123");
+        assert_eq!(key_prefix("ééééééééééééé"), "éééééééééééé");
+    }
+
+    #[test]
+    fn keys_are_stored_hashed_with_their_own_salt() {
+        let be = backend();
+        let key = issue_key(&be, 0, "a@example.com").unwrap();
+        let rows = be
+            .query_raw("SELECT prefix, hash, salt FROM apikeys;", vec![])
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        let row: Vec<String> = rows[0].iter().map(|v| mysql::from_value(v.clone())).collect();
+        assert_eq!(row[0], key_prefix(&key));
+        assert_eq!(row[1], hash_key(&row[2], &key));
+        assert!(row.iter().all(|v| !v.contains(&key)));
+
+        let (hash, salt) = hash_new_key(&key);
+        assert_ne!(salt, row[2]);
+        assert_ne!(hash, row[1]);
+    }
+
+    #[rocket::async_test]
+    async fn keys_sharing_a_prefix_are_told_apart() {
+        let be = backend();
+        let key = issue_key(&be, 0, "a@example.com").unwrap();
+        let backend = AsyncBackend::new(Arc::new(be));
+        let mut wrong = key.clone();
+        wrong.replace_range(KEY_PREFIX_LEN.., &"0".repeat(key.len() - KEY_PREFIX_LEN));
+        assert_ne!(wrong, key);
+        assert!(matches!(check_api_key(&backend, &wrong).await, Err(ApiKeyError::Missing)));
+        assert!(matches!(
+            check_api_key(&backend, key_prefix(&key)).await,
+            Err(ApiKeyError::Missing)
+        ));
+        assert_eq!(check_api_key(&backend, &key).await.unwrap(), "a@example.com");
     }
 }
@@ -0,0 +1,460 @@
+use crate::backend::{policy_id, Backend, BackendError, EncodedPolicy, Result, Value};
+use crate::migrations;
+use chrono::naive::NaiveDateTime;
+use mysql::from_value;
+use mysql::prelude::FromValue;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Version of the archive format. Bump it when the layout of the records
+/// below changes; the database schema version is recorded separately.
+const ARCHIVE_VERSION: u32 = 1;
+
+/// Tables in an archive, in the order they are written and restored. The
+/// policies come first, so that the rows referencing them can be checked
+/// as they are read.
+const TABLES: &[&str] = &[
+    "policies",
+    "lectures",
+    "questions",
+    "roster",
+    "users",
+    "apikeys",
+    "answers",
+];
+
+const DATETIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+/// One line of an archive. The first line is the header; each following
+/// line is a row of one of `TABLES`, with its values keyed by column name.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Record {
+    Header {
+        archive_version: u32,
+        schema_version: u32,
+        exported_at: String,
+    },
+    Row {
+        table: String,
+        values: serde_json::Map<String, serde_json::Value>,
+    },
+}
+
+fn archive_err<E: std::fmt::Display>(context: &str) -> impl FnOnce(E) -> BackendError + '_ {
+    move |e| BackendError::Database(format!("{}: {}", context, e))
+}
+
+fn to_json(v: Value) -> Result<serde_json::Value> {
+    Ok(match v {
+        Value::NULL => serde_json::Value::Null,
+        Value::Bytes(b) => String::from_utf8(b)
+            .map_err(archive_err("cannot export non-UTF-8 value"))?
+            .into(),
+        Value::Int(i) => i.into(),
+        Value::UInt(u) => u.into(),
+        Value::Float(f) => (f as f64).into(),
+        Value::Double(d) => d.into(),
+        // tagged, so that import can tell them apart from strings
+        v @ Value::Date(..) => {
+            let dt: NaiveDateTime = from_value(v);
+            serde_json::json!({ "datetime": dt.format(DATETIME_FORMAT).to_string() })
+        }
+        v => {
+            return Err(BackendError::Database(format!(
+                "cannot export value {:?}",
+                v
+            )))
+        }
+    })
+}
+
+/// Turns a value read from line `line` of an archive back into a value.
+fn from_json(line: usize, v: &serde_json::Value) -> Result<Value> {
+    Ok(match v {
+        serde_json::Value::Null => Value::NULL,
+        serde_json::Value::String(s) => s.as_str().into(),
+        serde_json::Value::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+            (Some(i), _, _) => i.into(),
+            (None, Some(u), _) => u.into(),
+            (None, None, Some(f)) => f.into(),
+            _ => {
+                return Err(BackendError::Database(format!(
+                    "archive line {}: unsupported number {}",
+                    line, n
+                )))
+            }
+        },
+        serde_json::Value::Object(o) if o.len() == 1 && o.contains_key("datetime") => {
+            let s = o["datetime"].as_str().unwrap_or_default();
+            NaiveDateTime::parse_from_str(s, DATETIME_FORMAT)
+                .map_err(|e| {
+                    BackendError::Database(format!("archive line {}: bad datetime: {}", line, e))
+                })?
+                .into()
+        }
+        v => {
+            return Err(BackendError::Database(format!(
+                "archive line {}: unexpected value {}",
+                line, v
+            )))
+        }
+    })
+}
+
+fn string_value(table: &str, col: &str, v: &Value) -> Result<String> {
+    String::from_value_opt(v.clone()).map_err(|_| {
+        BackendError::Database(format!("archived {} row has a bad {}: {:?}", table, col, v))
+    })
+}
+
+fn write_record<W: Write>(out: &mut W, rec: &Record) -> Result<()> {
+    serde_json::to_writer(&mut *out, rec).map_err(archive_err("failed to write archive"))?;
+    writeln!(out).map_err(archive_err("failed to write archive"))
+}
+
+/// Writes every row of `TABLES` to `path` as JSON lines, and returns the
+/// number of rows written. Policies are written as stored, so that they
+/// survive the round trip unchanged.
+pub fn export(backend: &dyn Backend, path: &Path) -> Result<usize> {
+    let file = File::create(path).map_err(archive_err("cannot create archive"))?;
+    let mut out = BufWriter::new(file);
+    write_record(
+        &mut out,
+        &Record::Header {
+            archive_version: ARCHIVE_VERSION,
+            schema_version: migrations::current_version(backend)?,
+            exported_at: chrono::Local::now()
+                .naive_local()
+                .format(DATETIME_FORMAT)
+                .to_string(),
+        },
+    )?;
+
+    let mut count = 0;
+    backend.with_transaction(|be| {
+        for table in TABLES {
+            let (_, cols) = be.table(table)?;
+            for row in be.query_raw(&format!("SELECT * FROM {};", table), vec![])? {
+                let values = cols
+                    .iter()
+                    .cloned()
+                    .zip(row.into_iter().map(to_json))
+                    .map(|(c, v)| v.map(|v| (c, v)))
+                    .collect::<Result<_>>()?;
+                write_record(
+                    &mut out,
+                    &Record::Row {
+                        table: table.to_string(),
+                        values: values,
+                    },
+                )?;
+                count += 1;
+            }
+        }
+        Ok(())
+    })?;
+    out.flush()
+        .map_err(archive_err("failed to write archive"))?;
+    Ok(count)
+}
+
+/// Checks that none of `TABLES` has any rows.
+fn check_empty(backend: &dyn Backend) -> Result<()> {
+    for table in TABLES {
+        let rows = backend.query_raw(&format!("SELECT COUNT(*) FROM {};", table), vec![])?;
+        let n: u64 = rows.first().map(|r| from_value(r[0].clone())).unwrap_or(0);
+        if n > 0 {
+            return Err(BackendError::Database(format!(
+                "cannot import into a database that has data: table {} has {} row(s)",
+                table, n
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Turns the values of an archived row, read from line `line`, into a row
+/// of `table`.
+fn row_values(
+    backend: &dyn Backend,
+    line: usize,
+    table: &str,
+    mut values: serde_json::Map<String, serde_json::Value>,
+) -> Result<Vec<Value>> {
+    let (_, cols) = backend.table(table)?;
+    let row = cols
+        .iter()
+        .map(|c| match values.remove(c) {
+            Some(v) => from_json(line, &v),
+            None => Err(BackendError::Database(format!(
+                "archive line {}: {} row has no column {}",
+                line, table, c
+            ))),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(c) = values.keys().next() {
+        return Err(BackendError::Database(format!(
+            "archive line {}: {} row has unknown column {}",
+            line, table, c
+        )));
+    }
+    Ok(row)
+}
+
+/// Checks that a `policies` row is intact and that its policy decodes, and
+/// returns its id.
+fn check_policy(row: &[Value]) -> Result<String> {
+    let id = string_value("policies", "id", &row[0])?;
+    let json = string_value("policies", "policy", &row[1])?;
+    if policy_id(&json) != id {
+        return Err(BackendError::PolicyDeserialization(format!(
+            "archived policy {} does not match its id",
+            id
+        )));
+    }
+    EncodedPolicy::from_stored(json).decode().map_err(|e| {
+        BackendError::PolicyDeserialization(format!("archived policy {}: {}", id, e))
+    })?;
+    Ok(id)
+}
+
+/// Restores an archive written by `export` into an empty database at the
+/// same schema version, and returns the number of rows restored. Every
+/// policy must decode, and every policied row must reference a policy in
+/// the archive; otherwise nothing is restored.
+pub fn import(backend: &dyn Backend, path: &Path) -> Result<usize> {
+    let file = File::open(path).map_err(archive_err("cannot open archive"))?;
+    let mut lines = BufReader::new(file).lines();
+    let header = lines
+        .next()
+        .ok_or_else(|| BackendError::Database("archive is empty".to_string()))?
+        .map_err(archive_err("failed to read archive"))?;
+    match serde_json::from_str(&header).map_err(archive_err("bad archive header"))? {
+        Record::Header {
+            archive_version,
+            schema_version,
+            ..
+        } => {
+            if archive_version != ARCHIVE_VERSION {
+                return Err(BackendError::Database(format!(
+                    "archive format version {} is not supported (expected {})",
+                    archive_version, ARCHIVE_VERSION
+                )));
+            }
+            let current = migrations::current_version(backend)?;
+            if schema_version != current {
+                return Err(BackendError::Database(format!(
+                    "archive is from schema version {}, but the database is at version {}",
+                    schema_version, current
+                )));
+            }
+        }
+        Record::Row { .. } => {
+            return Err(BackendError::Database(
+                "archive does not start with a header".to_string(),
+            ))
+        }
+    }
+
+    backend.with_transaction(|be| {
+        check_empty(be)?;
+        let mut policies = HashSet::new();
+        let mut count = 0;
+        for (i, line) in lines.enumerate() {
+            let line = line.map_err(archive_err("failed to read archive"))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (table, values) = match serde_json::from_str(&line)
+                .map_err(|e| BackendError::Database(format!("archive line {}: {}", i + 2, e)))?
+            {
+                Record::Row { table, values } => (table, values),
+                Record::Header { .. } => {
+                    return Err(BackendError::Database(format!(
+                        "archive line {}: unexpected header",
+                        i + 2
+                    )))
+                }
+            };
+            if !TABLES.contains(&table.as_str()) {
+                return Err(BackendError::Database(format!(
+                    "archive line {}: unknown table {}",
+                    i + 2,
+                    table
+                )));
+            }
+            let row = row_values(be, i + 2, &table, values)?;
+            if table == "policies" {
+                policies.insert(check_policy(&row)?);
+            } else if let Some(p) = be.table(&table)?.1.iter().position(|c| c == "policy") {
+                let id = string_value(&table, "policy", &row[p])?;
+                if !policies.contains(&id) {
+                    return Err(BackendError::PolicyDeserialization(format!(
+                        "archive line {}: {} row references missing policy {}",
+                        i + 2,
+                        table,
+                        id
+                    )));
+                }
+            }
+            be.insert(&table, row)?;
+            count += 1;
+        }
+        Ok(count)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{encode_policy, PolicyUpdate, SqliteBackend};
+    use beaver::filter::Context;
+    use beaver::policy::{MergePolicy, Policy, PolicyError};
+    use std::path::PathBuf;
+
+    #[derive(Clone, Serialize, Deserialize)]
+    struct ArchivedPolicy {
+        owner: String,
+    }
+
+    #[typetag::serde]
+    impl Policy for ArchivedPolicy {
+        fn check(&self, _: &Context) -> std::result::Result<(), PolicyError> {
+            Ok(())
+        }
+        fn merge(
+            &self,
+            other: &Box<dyn Policy>,
+        ) -> std::result::Result<Box<dyn Policy>, PolicyError> {
+            Ok(Box::new(MergePolicy::make(Box::new(self.clone()), other.clone())))
+        }
+    }
+
+    fn owned_by(owner: &str) -> EncodedPolicy {
+        encode_policy(&ArchivedPolicy { owner: owner.to_string() }).unwrap()
+    }
+
+    fn backend() -> SqliteBackend {
+        let be = SqliteBackend::for_tests();
+        migrations::up(&be, None).unwrap();
+        be
+    }
+
+    fn archive_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("websubmit-{}-{}.jsonl", name, std::process::id()))
+    }
+
+    fn rows(be: &dyn Backend, table: &str) -> Vec<Vec<Value>> {
+        be.query_raw(&format!("SELECT * FROM {} ORDER BY 1;", table), vec![])
+            .unwrap()
+    }
+
+    fn header(be: &dyn Backend) -> String {
+        serde_json::to_string(&Record::Header {
+            archive_version: ARCHIVE_VERSION,
+            schema_version: migrations::current_version(be).unwrap(),
+            exported_at: "2021-01-01T00:00:00".to_string(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn round_trip_keeps_every_row() {
+        let src = backend();
+        let db: &dyn Backend = &src;
+        let submitted =
+            NaiveDateTime::parse_from_str("2021-02-03 04:05:06", "%Y-%m-%d %H:%M:%S").unwrap();
+        let expires =
+            NaiveDateTime::parse_from_str("2021-03-04 05:06:07", "%Y-%m-%d %H:%M:%S").unwrap();
+        db.insert("lectures", vec![1.into(), "Intro".into()]).unwrap();
+        db.insert("questions", vec![1.into(), 1.into(), "Why?".into()])
+            .unwrap();
+        let mode = PolicyUpdate::Replace;
+        for user in &["a@example.com", "b@example.com"] {
+            let rec = vec![(*user).into(), 0.into()];
+            db.insert_or_update_policied("users", rec, vec![], owned_by(user), mode)
+                .unwrap();
+            let rec = vec![
+                (*user).into(),
+                1.into(),
+                1.into(),
+                "Because.".into(),
+                submitted.into(),
+            ];
+            db.insert_or_update_policied("answers", rec, vec![], owned_by(user), mode)
+                .unwrap();
+        }
+        // a key from before keys were random: no creation time, salt or expiry
+        let key = vec![
+            "abcdefghijkl".into(),
+            "0123".into(),
+            Value::NULL,
+            "a@example.com".into(),
+            Value::NULL,
+            Value::NULL,
+            expires.into(),
+        ];
+        db.insert("apikeys", key).unwrap();
+
+        let path = archive_path("round-trip");
+        let exported = export(db, &path).unwrap();
+        let dst = backend();
+        let imported = import(&dst, &path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(imported.unwrap(), exported);
+        // two users and their answers share two policies
+        assert_eq!(exported, 9);
+        for table in TABLES {
+            assert_eq!(rows(&dst, table), rows(db, table), "{} differs", table);
+        }
+    }
+
+    #[test]
+    fn import_refuses_a_database_with_data() {
+        let src = backend();
+        src.insert("lectures", vec![1.into(), "Intro".into()]).unwrap();
+        let path = archive_path("not-empty");
+        export(&src, &path).unwrap();
+        let res = import(&src, &path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(res.is_err());
+        assert_eq!(rows(&src, "lectures").len(), 1);
+    }
+
+    #[test]
+    fn import_reports_the_bad_line() {
+        let be = backend();
+        let lines = [
+            header(&be),
+            r#"{"kind":"row","table":"lectures","values":{"id":1,"label":"Intro"}}"#.to_string(),
+            r#"{"kind":"row","table":"lectures","values":{"id":{"when":1},"label":"A"}}"#
+                .to_string(),
+        ];
+        let path = archive_path("bad-line");
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        let res = import(&be, &path);
+        std::fs::remove_file(&path).unwrap();
+        let err = res.unwrap_err().to_string();
+        assert!(err.contains("archive line 3"), "{}", err);
+        // nothing is restored
+        assert!(rows(&be, "lectures").is_empty());
+    }
+
+    #[test]
+    fn import_requires_archived_policies() {
+        let be = backend();
+        let user = r#"{"kind":"row","table":"users","values":{"email":"a@example.com","#;
+        let lines = [header(&be), format!(r#"{}"is_admin":0,"policy":"missing"}}}}"#, user)];
+        let path = archive_path("no-policy");
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        let res = import(&be, &path);
+        std::fs::remove_file(&path).unwrap();
+        match res {
+            Err(BackendError::PolicyDeserialization(e)) => assert!(e.contains("line 2"), "{}", e),
+            r => panic!("expected a policy error, got {:?}", r.map(|_| ())),
+        }
+    }
+}
@@ -1,16 +1,56 @@
 use crate::config;
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
+use std::path::PathBuf;
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
 const WEBSUBMIT_USAGE: &'static str = "\
 EXAMPLES:
   websubmit -i csci2390
-  websubmit -i csci2390 -c csci2390-f19.toml";
+  websubmit -i csci2390 -c csci2390-f19.toml
+  websubmit -i csci2390 migrate status
+  websubmit -i csci2390 check
+  websubmit -i csci2390 export csci2390-f19.jsonl";
+
+#[derive(Clone, Debug)]
+pub enum MigrateCommand {
+    /// Apply migrations up to the given version (or all)
+    Up(Option<u32>),
+    /// Revert migrations down to the given version (or the previous one)
+    Down(Option<u32>),
+    Status,
+}
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// Run the web application
+    Serve,
+    Migrate(MigrateCommand),
+    /// Check queries and templates without starting the server
+    Check,
+    /// Write the database's data to an archive file
+    Export(PathBuf),
+    /// Restore an archive file into an empty database
+    Import(PathBuf),
+}
 
 #[derive(Clone, Debug)]
 pub struct Args {
     pub class: String,
     pub config: config::Config,
+    pub command: Command,
+}
+
+/// Validates a migration version given on the command line, so that clap
+/// prints a usage error for anything else.
+fn is_version(v: String) -> Result<(), String> {
+    v.parse::<u32>()
+        .map(|_| ())
+        .map_err(|_| format!("migration version must be a number, not \"{}\"", v))
+}
+
+fn parse_version(v: Option<&str>) -> Option<u32> {
+    // validated by `is_version`
+    v.and_then(|v| v.parse().ok())
 }
 
 pub fn parse_args() -> Args {
@@ -35,12 +75,96 @@ pub fn parse_args() -> Args {
                 .required(true)
                 .help("Short textual identifier for the class hosted (used as Noria deployment name)."),
         )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Manages the database schema version.")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("up")
+                        .about("Applies pending migrations.")
+                        .arg(
+                            Arg::with_name("to")
+                                .long("to")
+                                .takes_value(true)
+                                .value_name("VERSION")
+                                .validator(is_version)
+                                .help("Version to migrate to (default: latest)."),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("down")
+                        .about("Reverts applied migrations.")
+                        .arg(
+                            Arg::with_name("to")
+                                .long("to")
+                                .takes_value(true)
+                                .value_name("VERSION")
+                                .validator(is_version)
+                                .help("Version to revert to (default: the previous version)."),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("status")
+                        .about("Shows applied and pending migrations."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Checks schema.sql queries and templates, then exits."),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Exports the course's data and policies to an archive.")
+                .arg(
+                    Arg::with_name("file")
+                        .required(true)
+                        .value_name("FILE")
+                        .help("Archive to write (JSON lines)."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Imports an archive written by `export` into an empty database.")
+                .arg(
+                    Arg::with_name("file")
+                        .required(true)
+                        .value_name("FILE")
+                        .help("Archive to read."),
+                ),
+        )
         .after_help(WEBSUBMIT_USAGE)
         .get_matches();
 
+    let command = match args.subcommand() {
+        ("migrate", Some(m)) => Command::Migrate(match m.subcommand() {
+            ("up", Some(up)) => MigrateCommand::Up(parse_version(up.value_of("to"))),
+            ("down", Some(down)) => MigrateCommand::Down(parse_version(down.value_of("to"))),
+            _ => MigrateCommand::Status,
+        }),
+        ("check", Some(_)) => Command::Check,
+        ("export", Some(e)) => Command::Export(PathBuf::from(e.value_of("file").unwrap())),
+        ("import", Some(i)) => Command::Import(PathBuf::from(i.value_of("file").unwrap())),
+        _ => Command::Serve,
+    };
+
     Args {
         class: String::from(args.value_of("class").unwrap()),
         config: config::parse(args.value_of("config").expect("Failed to parse config!"))
             .expect("failed to parse config"),
+        command: command,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_must_be_numbers() {
+        assert!(is_version("3".to_string()).is_ok());
+        assert!(is_version("abc".to_string()).is_err());
+        assert!(is_version("-1".to_string()).is_err());
+        assert_eq!(parse_version(Some("3")), Some(3));
+        assert_eq!(parse_version(None), None);
     }
 }
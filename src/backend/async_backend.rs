@@ -0,0 +1,342 @@
+use rocket::futures::stream::Stream;
+use rocket::tokio::sync::mpsc;
+use rocket::tokio::task;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use beaver::generic_policied::GPolicied;
+use beaver::policy::Policy;
+
+use super::audit;
+use super::{
+    decode_policied_as, encode_policy, query_policied_rows, Backend, BackendError, FromRow, Page,
+    PolicyUpdate, QueryStats, Result, Row, Value,
+};
+
+/// How many rows a stream reads ahead of its consumer.
+const STREAM_BUFFER: usize = 64;
+
+/// Rows of a query, read on the blocking thread pool as the stream is
+/// consumed. Dropping the stream stops the query.
+pub struct RowStream {
+    rx: mpsc::Receiver<Result<Row>>,
+}
+
+impl Stream for RowStream {
+    type Item = Result<Row>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Row>>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Async front end to a `Backend`, for use from route handlers and request
+/// guards. Each operation runs the blocking backend call on Rocket's
+/// blocking thread pool, so the async worker threads are never blocked on
+/// the database.
+///
+/// Policies are (de)serialized on the calling task, so neither policies nor
+/// `GPolicied` values have to cross threads.
+#[derive(Clone)]
+pub struct AsyncBackend {
+    inner: Arc<dyn Backend>,
+    // the user recorded in the audit log as making this handle's writes
+    actor: Option<String>,
+}
+
+impl AsyncBackend {
+    pub fn new(inner: Arc<dyn Backend>) -> Self {
+        AsyncBackend {
+            inner: inner,
+            actor: None,
+        }
+    }
+
+    /// A handle to the same backend whose writes are attributed to `user` in
+    /// the audit log. Writes through other handles are attributed to
+    /// "system".
+    pub fn acting_as(&self, user: &str) -> AsyncBackend {
+        AsyncBackend {
+            inner: self.inner.clone(),
+            actor: Some(user.to_string()),
+        }
+    }
+
+    pub fn log(&self) -> &slog::Logger {
+        self.inner.log()
+    }
+
+    pub fn stats(&self) -> &QueryStats {
+        self.inner.stats()
+    }
+
+    /// Runs `f` against the underlying backend on the blocking thread pool.
+    pub async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&dyn Backend) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let backend = self.inner.clone();
+        let actor = self.actor.clone();
+        task::spawn_blocking(move || audit::with_actor(actor, || f(&*backend)))
+            .await
+            .unwrap_or_else(|e| Err(BackendError::Database(format!("backend task failed: {}", e))))
+    }
+
+    /// Runs `f` in a transaction on the blocking thread pool; see
+    /// `Backend::with_transaction`. Policies must be encoded with
+    /// `encode_policy` before they are moved into `f`.
+    pub async fn with_transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&dyn Backend) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run(move |be| be.with_transaction(f)).await
+    }
+
+    pub async fn query_exec(&self, qname: &str, keys: Vec<Value>) -> Result<Vec<Vec<Value>>> {
+        let qname = qname.to_string();
+        self.run(move |be| be.query_exec(&qname, keys)).await
+    }
+
+    /// Runs a named query and maps each row to `T` by column name.
+    pub async fn query_as<T: FromRow + Send + 'static>(
+        &self,
+        qname: &str,
+        keys: Vec<Value>,
+    ) -> Result<Vec<T>> {
+        let qname = qname.to_string();
+        self.run(move |be| be.query_as(&qname, keys)).await
+    }
+
+    /// Like `query_as`, for queries over policied tables.
+    pub async fn query_as_policied<T: FromRow>(
+        &self,
+        qname: &str,
+        keys: Vec<Value>,
+    ) -> Result<Vec<GPolicied<T>>> {
+        let qname = qname.to_string();
+        self.run(move |be| query_policied_rows(be, &qname, keys))
+            .await?
+            .into_rows()
+            .map(|row| decode_policied_as(self.inner.policies(), row))
+            .collect()
+    }
+
+    /// Like `query_as`, for the rows in `page`.
+    pub async fn query_page_as<T: FromRow + Send + 'static>(
+        &self,
+        qname: &str,
+        keys: Vec<Value>,
+        page: Page,
+    ) -> Result<Vec<T>> {
+        let qname = qname.to_string();
+        self.run(move |be| be.query_page_as(&qname, keys, page)).await
+    }
+
+    /// Like `query_as_policied`, for the rows in `page`.
+    pub async fn query_page_as_policied<T: FromRow>(
+        &self,
+        qname: &str,
+        keys: Vec<Value>,
+        page: Page,
+    ) -> Result<Vec<GPolicied<T>>> {
+        let qname = qname.to_string();
+        self.run(move |be| {
+            let rows = be.query_page(&qname, keys, page)?;
+            be.policies().load(be, &rows)?;
+            Ok(rows)
+        })
+        .await?
+        .into_rows()
+        .map(|row| decode_policied_as(self.inner.policies(), row))
+        .collect()
+    }
+
+    /// Like `query_page_as_policied`, for queries whose rows may have a NULL
+    /// policy (e.g., audit log entries for unpolicied tables). Each row comes
+    /// with its policy, if it has one; check it before showing the row.
+    pub async fn query_page_as_maybe_policied<T: FromRow>(
+        &self,
+        qname: &str,
+        keys: Vec<Value>,
+        page: Page,
+    ) -> Result<Vec<(T, Option<Box<dyn Policy>>)>> {
+        let qname = qname.to_string();
+        self.run(move |be| {
+            let rows = be.query_page(&qname, keys, page)?;
+            be.policies().load(be, &rows)?;
+            Ok(rows)
+        })
+        .await?
+        .into_rows()
+        .map(|mut row| {
+            let policy = match row.take_policy()? {
+                Value::NULL => None,
+                id => Some(self.inner.policies().decode(id)?),
+            };
+            Ok((T::from_row(&row)?, policy))
+        })
+        .collect()
+    }
+
+    /// Runs `f` on the blocking thread pool, streaming the rows it passes
+    /// to its callback.
+    fn stream<F>(&self, f: F) -> RowStream
+    where
+        F: FnOnce(&dyn Backend, &mut dyn FnMut(Row) -> Result<()>) -> Result<()> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let backend = self.inner.clone();
+        task::spawn_blocking(move || {
+            let res = f(&*backend, &mut |row| {
+                tx.blocking_send(Ok(row))
+                    .map_err(|_| BackendError::Database("row stream was dropped".to_string()))
+            });
+            if let Err(e) = res {
+                let _ = tx.blocking_send(Err(e));
+            }
+        });
+        RowStream { rx: rx }
+    }
+
+    /// Runs a named query, streaming its rows instead of collecting them.
+    pub fn query_stream(&self, qname: &str, keys: Vec<Value>) -> RowStream {
+        let qname = qname.to_string();
+        self.stream(move |be, f| be.query_each(&qname, keys, f))
+    }
+
+    pub async fn insert(&self, table: &str, vals: Vec<Value>) -> Result<()> {
+        let table = table.to_string();
+        self.run(move |be| be.insert(&table, vals)).await
+    }
+
+    pub async fn update(
+        &self,
+        table: &str,
+        keys: Vec<Value>,
+        vals: Vec<(usize, Value)>,
+    ) -> Result<()> {
+        let table = table.to_string();
+        self.run(move |be| be.update(&table, keys, vals)).await
+    }
+
+    pub async fn insert_or_update(
+        &self,
+        table: &str,
+        rec: Vec<Value>,
+        update_vals: Vec<(u64, Value)>,
+    ) -> Result<()> {
+        let table = table.to_string();
+        self.run(move |be| be.insert_or_update(&table, rec, update_vals))
+            .await
+    }
+
+    pub async fn delete(&self, table: &str, keys: Vec<Value>) -> Result<()> {
+        let table = table.to_string();
+        self.run(move |be| be.delete(&table, keys)).await
+    }
+
+    /// See `Backend::insert_or_update_policied`.
+    pub fn insert_or_update_policied(
+        &self,
+        table: &str,
+        rec: Vec<Value>,
+        update_vals: Vec<(u64, Value)>,
+        policy: &dyn beaver::policy::Policy,
+        mode: PolicyUpdate,
+    ) -> impl Future<Output = Result<()>> + '_ {
+        let policy = encode_policy(policy);
+        let table = table.to_string();
+        self.run(move |be| be.insert_or_update_policied(&table, rec, update_vals, policy?, mode))
+    }
+
+    /// See `Backend::update_policied`.
+    pub fn update_policied(
+        &self,
+        table: &str,
+        keys: Vec<Value>,
+        vals: Vec<(usize, Value)>,
+        policy: &dyn beaver::policy::Policy,
+        mode: PolicyUpdate,
+    ) -> impl Future<Output = Result<()>> + '_ {
+        let policy = encode_policy(policy);
+        let table = table.to_string();
+        self.run(move |be| be.update_policied(&table, keys, vals, policy?, mode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SqliteBackend;
+    use rocket::futures::StreamExt;
+
+    fn backend() -> AsyncBackend {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        AsyncBackend::new(Arc::new(be))
+    }
+
+    async fn actors(backend: &AsyncBackend) -> Vec<String> {
+        backend
+            .run(|be| be.query_raw("SELECT actor FROM audit_log ORDER BY actor;", vec![]))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| mysql::from_value(r[0].clone()))
+            .collect()
+    }
+
+    #[rocket::async_test]
+    async fn writes_are_attributed_to_the_acting_user() {
+        let backend = backend();
+        backend
+            .acting_as("admin@example.com")
+            .insert("lectures", vec![1.into(), "Intro".into()])
+            .await
+            .unwrap();
+        backend
+            .insert("lectures", vec![2.into(), "Basics".into()])
+            .await
+            .unwrap();
+        assert_eq!(actors(&backend).await, vec!["admin@example.com", "system"]);
+    }
+
+    #[rocket::async_test]
+    async fn failed_transactions_are_rolled_back() {
+        let backend = backend();
+        let res = backend
+            .with_transaction(|be| {
+                be.insert("lectures", vec![1.into(), "Intro".into()])?;
+                be.insert("lectures", vec![1.into(), "Again".into()])
+            })
+            .await;
+        assert!(matches!(res, Err(BackendError::ConstraintViolation(_))));
+        assert!(backend.query_exec("leclist", vec![]).await.unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn panics_become_errors() {
+        let backend = backend();
+        let res: Result<()> = backend.run(|_| panic!("query failed")).await;
+        assert!(matches!(res, Err(BackendError::Database(_))));
+        assert!(backend.query_exec("leclist", vec![]).await.is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn streams_every_row() {
+        let backend = backend();
+        for q in 1..=100 {
+            let question = vec![1.into(), (q as u32).into(), "Why?".into()];
+            backend.insert("questions", question).await.unwrap();
+        }
+        let rows: Vec<_> = backend.query_stream("qs_by_lec", vec![1.into()]).collect().await;
+        assert_eq!(rows.len(), 100);
+        assert!(rows.iter().all(|r| r.is_ok()));
+        let none: Vec<_> = backend.query_stream("no_such_query", vec![]).collect().await;
+        assert!(matches!(none.as_slice(), [Err(BackendError::UnknownQuery(_))]));
+    }
+}
@@ -0,0 +1,360 @@
+use chrono::Local;
+use rand::Rng;
+use std::cell::RefCell;
+
+use super::{check_keys, Backend, Result, Value};
+
+/// Tables whose writes are not recorded: the audit log itself, the policies
+/// table, whose rows never change and are referenced by the audited rows,
+/// and the sessions and login links tables, which are written on logins and
+/// most requests.
+const UNAUDITED: &[&str] = &["audit_log", "policies", "sessions", "login_links"];
+
+/// Columns holding credentials, by table. Their values are never written to
+/// the audit log; entries show `REDACTED` in their place.
+const SECRET_COLUMNS: &[(&str, &[&str])] = &[("apikeys", &["hash", "salt"])];
+
+const REDACTED: &str = "[redacted]";
+
+/// Recorded as the actor of writes made outside of any request.
+const SYSTEM_ACTOR: &str = "system";
+
+thread_local! {
+    // the user writes on this thread are attributed to
+    static ACTOR: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Restores the previous actor, even if the guarded code panics.
+struct ActorGuard(Option<String>);
+
+impl Drop for ActorGuard {
+    fn drop(&mut self) {
+        let prev = self.0.take();
+        ACTOR.with(|a| *a.borrow_mut() = prev);
+    }
+}
+
+/// Runs `f` with the writes it makes attributed to `actor`.
+pub fn with_actor<T, F: FnOnce() -> T>(actor: Option<String>, f: F) -> T {
+    let _guard = ActorGuard(ACTOR.with(|a| a.replace(actor)));
+    f()
+}
+
+fn current_actor() -> String {
+    ACTOR.with(|a| {
+        a.borrow()
+            .clone()
+            .unwrap_or_else(|| SYSTEM_ACTOR.to_string())
+    })
+}
+
+/// Whether writes to `table` are recorded in the audit log.
+pub fn is_audited(table: &str) -> bool {
+    !UNAUDITED.contains(&table)
+}
+
+fn value_json(v: &Value) -> serde_json::Value {
+    match v {
+        Value::NULL => serde_json::Value::Null,
+        Value::Bytes(b) => String::from_utf8_lossy(b).into_owned().into(),
+        Value::Int(i) => (*i).into(),
+        Value::UInt(u) => (*u).into(),
+        Value::Float(f) => (*f as f64).into(),
+        Value::Double(d) => (*d).into(),
+        Value::Date(..) | Value::Time(..) => v.as_sql(true).trim_matches('\'').into(),
+    }
+}
+
+fn is_secret(table: &str, col: &str) -> bool {
+    SECRET_COLUMNS
+        .iter()
+        .any(|(t, cols)| *t == table && cols.contains(&col))
+}
+
+/// A row (or part of one) of `table` as a JSON object keyed by column name,
+/// with its secret columns redacted.
+fn row_json<'a, I: Iterator<Item = (&'a String, &'a Value)>>(table: &str, cols: I) -> String {
+    let obj: serde_json::Map<_, _> = cols
+        .map(|(c, v)| {
+            let v = if is_secret(table, c) { REDACTED.into() } else { value_json(v) };
+            (c.clone(), v)
+        })
+        .collect();
+    serde_json::Value::Object(obj).to_string()
+}
+
+/// Redacts `cols` in a row recorded by `row_json`, for entries written before
+/// the columns were secret (or while they held plaintext credentials).
+pub fn redact_recorded(json: &str, cols: &[&str]) -> String {
+    match serde_json::from_str::<serde_json::Value>(json) {
+        Ok(serde_json::Value::Object(mut obj)) => {
+            for c in cols {
+                if let Some(v) = obj.get_mut(*c) {
+                    *v = REDACTED.into();
+                }
+            }
+            serde_json::Value::Object(obj).to_string()
+        }
+        _ => json.to_string(),
+    }
+}
+
+/// Whether a write to `table` has to be wrapped in a transaction first, so
+/// that it and its audit log entry are committed together.
+pub fn needs_transaction(be: &dyn Backend, table: &str) -> bool {
+    is_audited(table) && !be.in_transaction()
+}
+
+/// Runs a write in a transaction; see `needs_transaction`.
+pub fn transaction<F: FnOnce(&dyn Backend) -> Result<()>>(be: &dyn Backend, f: F) -> Result<()> {
+    be.with_transaction(f)
+}
+
+/// The conditions matching the row of `table` with primary key `keys`.
+pub fn key_conds(be: &dyn Backend, table: &str, keys: &[Value]) -> Result<Vec<(usize, Value)>> {
+    let (key_cols, cols) = be.table(table)?;
+    check_keys(table, key_cols, keys)?;
+    Ok(key_cols
+        .iter()
+        .zip(keys)
+        .filter_map(|(k, v)| cols.iter().position(|c| c == k).map(|i| (i, v.clone())))
+        .collect())
+}
+
+/// The conditions matching the row of `table` with the same primary key as
+/// the full record `rec`.
+pub fn rec_key_conds(be: &dyn Backend, table: &str, rec: &[Value]) -> Result<Vec<(usize, Value)>> {
+    let (key_cols, cols) = be.table(table)?;
+    Ok(cols
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| key_cols.contains(c))
+        .filter_map(|(i, _)| rec.get(i).map(|v| (i, v.clone())))
+        .collect())
+}
+
+/// The rows of `table` whose columns (by index) match `conds`, read before
+/// they are changed so that their old values can be recorded.
+pub fn rows_before(
+    be: &dyn Backend,
+    table: &str,
+    conds: &[(usize, Value)],
+) -> Result<Vec<Vec<Value>>> {
+    if !is_audited(table) || conds.is_empty() {
+        return Ok(vec![]);
+    }
+    let (_, cols) = be.table(table)?;
+    let sql_conds: Vec<_> = conds
+        .iter()
+        .map(|(i, _)| format!("{} = ?", cols[*i]))
+        .collect();
+    let sql = format!("SELECT * FROM {} WHERE {};", table, sql_conds.join(" AND "));
+    be.query_raw(&sql, conds.iter().map(|(_, v)| v.clone()).collect())
+}
+
+fn apply(mut row: Vec<Value>, changes: &[(usize, Value)]) -> Vec<Value> {
+    for (i, v) in changes {
+        if let Some(c) = row.get_mut(*i) {
+            *c = v.clone();
+        }
+    }
+    row
+}
+
+pub fn record_insert(be: &dyn Backend, table: &str, new: &[Value]) -> Result<()> {
+    if !is_audited(table) {
+        return Ok(());
+    }
+    record(be, table, None, Some(new))
+}
+
+pub fn record_update(
+    be: &dyn Backend,
+    table: &str,
+    old: Vec<Vec<Value>>,
+    changes: &[(usize, Value)],
+) -> Result<()> {
+    for row in old {
+        let new = apply(row.clone(), changes);
+        record(be, table, Some(&row), Some(&new))?;
+    }
+    Ok(())
+}
+
+/// Records an `insert_or_update` of `rec`, which updated the row in `old`
+/// if there was one.
+pub fn record_upsert(
+    be: &dyn Backend,
+    table: &str,
+    old: Vec<Vec<Value>>,
+    rec: &[Value],
+    changes: &[(u64, Value)],
+) -> Result<()> {
+    if old.is_empty() {
+        return record_insert(be, table, rec);
+    }
+    let changes: Vec<_> = changes
+        .iter()
+        .map(|(i, v)| (*i as usize, v.clone()))
+        .collect();
+    record_update(be, table, old, &changes)
+}
+
+pub fn record_delete(be: &dyn Backend, table: &str, old: Vec<Vec<Value>>) -> Result<()> {
+    for row in old {
+        record(be, table, Some(&row), None)?;
+    }
+    Ok(())
+}
+
+/// Appends an entry for a write to `table` to the audit log. `old` and
+/// `new` are the row before and after the write; `old` is `None` for an
+/// insert, and `new` is `None` for a delete. The entry carries the row's
+/// policy, so it is only shown to those the row itself may be shown to.
+fn record(
+    be: &dyn Backend,
+    table: &str,
+    old: Option<&[Value]>,
+    new: Option<&[Value]>,
+) -> Result<()> {
+    let (key_cols, cols) = be.table(table)?;
+    let (action, row) = match (old, new) {
+        (None, Some(new)) => ("insert", new),
+        (Some(_), Some(new)) => ("update", new),
+        (Some(old), None) => ("delete", old),
+        (None, None) => return Ok(()),
+    };
+    let key = row_json(table, cols.iter().zip(row).filter(|(c, _)| key_cols.contains(c)));
+    let policy = match cols.last() {
+        Some(c) if c == "policy" => row.last().cloned().unwrap_or(Value::NULL),
+        _ => Value::NULL,
+    };
+    let id: u128 = rand::thread_rng().gen();
+    be.insert(
+        "audit_log",
+        vec![
+            format!("{:032x}", id).into(),
+            Local::now().naive_local().into(),
+            current_actor().into(),
+            action.into(),
+            table.into(),
+            key.into(),
+            old.map(|r| row_json(table, cols.iter().zip(r))).into(),
+            new.map(|r| row_json(table, cols.iter().zip(r))).into(),
+            policy,
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SqliteBackend;
+
+    fn backend() -> SqliteBackend {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        be
+    }
+
+    /// The actor, key, old and new values of the entries for `action`s on
+    /// `table`.
+    fn entries(be: &dyn Backend, table: &str, action: &str) -> Vec<Vec<Option<String>>> {
+        be.query_raw(
+            "SELECT actor, row_key, old_values, new_values FROM audit_log \
+             WHERE tbl = ? AND action = ?;",
+            vec![table.into(), action.into()],
+        )
+        .unwrap()
+        .into_iter()
+        .map(|r| r.into_iter().map(mysql::from_value).collect())
+        .collect()
+    }
+
+    fn entry(actor: &str, key: &str, old: Option<&str>, new: Option<&str>) -> Vec<Option<String>> {
+        vec![Some(actor), Some(key), old, new]
+            .into_iter()
+            .map(|v| v.map(String::from))
+            .collect()
+    }
+
+    #[test]
+    fn writes_are_recorded_with_their_actor() {
+        let be = backend();
+        with_actor(Some("admin@example.com".to_string()), || {
+            be.insert("lectures", vec![1.into(), "Intro".into()])
+        })
+        .unwrap();
+        be.update("lectures", vec![1.into()], vec![(1, "Basics".into())])
+            .unwrap();
+        be.delete("lectures", vec![1.into()]).unwrap();
+
+        let key = r#"{"id":1}"#;
+        let intro = r#"{"id":1,"label":"Intro"}"#;
+        let basics = r#"{"id":1,"label":"Basics"}"#;
+        assert_eq!(
+            entries(&be, "lectures", "insert"),
+            vec![entry("admin@example.com", key, None, Some(intro))]
+        );
+        assert_eq!(
+            entries(&be, "lectures", "update"),
+            vec![entry(SYSTEM_ACTOR, key, Some(intro), Some(basics))]
+        );
+        assert_eq!(
+            entries(&be, "lectures", "delete"),
+            vec![entry(SYSTEM_ACTOR, key, Some(basics), None)]
+        );
+    }
+
+    #[test]
+    fn failed_and_unaudited_writes_are_not_recorded() {
+        let be = backend();
+        be.insert("lectures", vec![1.into(), "Intro".into()]).unwrap();
+        assert!(be.insert("lectures", vec![1.into(), "Again".into()]).is_err());
+        assert_eq!(entries(&be, "lectures", "insert").len(), 1);
+
+        let session = vec![
+            "id".into(),
+            "a@example.com".into(),
+            Value::NULL,
+            "2021-01-01 00:00:00".into(),
+            "2021-01-01 00:00:00".into(),
+            Value::NULL,
+        ];
+        be.insert("sessions", session).unwrap();
+        assert!(entries(&be, "sessions", "insert").is_empty());
+    }
+
+    #[test]
+    fn secret_columns_are_redacted() {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        let key = vec![
+            "abcdefghijkl".into(),
+            "secret-hash".into(),
+            "secret-salt".into(),
+            "a@example.com".into(),
+            "2021-01-01 00:00:00".into(),
+            Value::NULL,
+            Value::NULL,
+        ];
+        be.insert("apikeys", key).unwrap();
+        be.update("apikeys", vec!["abcdefghijkl".into()], vec![(2, "new-salt".into())])
+            .unwrap();
+
+        let rows = be
+            .query_raw(
+                "SELECT row_key, old_values, new_values FROM audit_log WHERE tbl = ?;",
+                vec!["apikeys".into()],
+            )
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        for row in rows {
+            for v in row {
+                let json: Option<String> = mysql::from_value(v);
+                let json = json.unwrap_or_default();
+                assert!(!json.contains("secret") && !json.contains("new-salt"), "{}", json);
+            }
+        }
+    }
+}
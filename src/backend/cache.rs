@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::{Rows, Schema, Value};
+
+/// Most results kept per cached query; further results are not cached
+/// until a write to one of the query's tables empties its entries.
+const MAX_ENTRIES: usize = 10_000;
+
+struct CachedQuery {
+    // bumped by every write to one of the query's tables, so that results
+    // read before the write are not cached after it
+    generation: u64,
+    // query arguments (as text) --> result
+    results: HashMap<String, Rows>,
+}
+
+/// Keeps the results of selected named queries in memory, in place of the
+/// incrementally maintained views Noria provided. A write to a table empties
+/// the results of all cached queries that read it (directly or through a
+/// view), as determined from the schema.
+pub struct QueryCache {
+    // table name --> cached queries that read it
+    readers: HashMap<String, Vec<String>>,
+    queries: Mutex<HashMap<String, CachedQuery>>,
+}
+
+fn cache_key(keys: &[Value]) -> String {
+    format!("{:?}", keys)
+}
+
+impl QueryCache {
+    /// A cache for the named queries `qnames`, all of which must be defined
+    /// in `schema`.
+    pub fn new(schema: &Schema, qnames: &[String]) -> Result<Self, String> {
+        let mut readers: HashMap<String, Vec<String>> = HashMap::new();
+        let mut queries = HashMap::new();
+        for qname in qnames {
+            let tables = schema
+                .query_tables(qname)
+                .ok_or_else(|| format!("cannot cache unknown query \"{}\"", qname))?;
+            for table in tables {
+                readers.entry(table).or_default().push(qname.clone());
+            }
+            queries.insert(
+                qname.clone(),
+                CachedQuery {
+                    generation: 0,
+                    results: HashMap::new(),
+                },
+            );
+        }
+        Ok(QueryCache {
+            readers: readers,
+            queries: Mutex::new(queries),
+        })
+    }
+
+    /// Runs a named query with `run`, unless its result is cached. Results
+    /// read inside a transaction are neither served from nor added to the
+    /// cache, as the transaction may see its own uncommitted writes.
+    pub fn query<F>(
+        &self,
+        qname: &str,
+        keys: Vec<Value>,
+        in_transaction: bool,
+        run: F,
+    ) -> super::Result<Rows>
+    where
+        F: FnOnce(Vec<Value>) -> super::Result<Rows>,
+    {
+        if in_transaction {
+            return run(keys);
+        }
+        let key = cache_key(&keys);
+        let generation = {
+            let queries = self.queries.lock().unwrap_or_else(|e| e.into_inner());
+            match queries.get(qname) {
+                Some(q) => match q.results.get(&key) {
+                    Some(rows) => return Ok(rows.clone()),
+                    None => q.generation,
+                },
+                None => return run(keys),
+            }
+        };
+        let rows = run(keys)?;
+        let mut queries = self.queries.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(q) = queries.get_mut(qname) {
+            // skip results read before a write to one of the query's tables
+            if q.generation == generation && q.results.len() < MAX_ENTRIES {
+                q.results.insert(key, rows.clone());
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Drops the results of all cached queries that read `table`.
+    pub fn invalidate(&self, table: &str) {
+        let readers = match self.readers.get(table) {
+            Some(r) => r,
+            None => return,
+        };
+        let mut queries = self.queries.lock().unwrap_or_else(|e| e.into_inner());
+        for qname in readers {
+            if let Some(q) = queries.get_mut(qname) {
+                q.generation += 1;
+                q.results.clear();
+            }
+        }
+    }
+
+    /// Drops all cached results, e.g. after a raw SQL statement that may
+    /// have written to any table.
+    pub fn clear(&self) {
+        let mut queries = self.queries.lock().unwrap_or_else(|e| e.into_inner());
+        for q in queries.values_mut() {
+            q.generation += 1;
+            q.results.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn cache() -> QueryCache {
+        let schema = Schema::load(None).unwrap();
+        QueryCache::new(&schema, &["leclist".to_string(), "qs_by_lec".to_string()]).unwrap()
+    }
+
+    fn rows(v: u64) -> Rows {
+        Rows::new(vec!["v".to_string()], vec![vec![v.into()]])
+    }
+
+    /// Runs `qname` through `cache`, returning the result and whether the
+    /// query actually ran.
+    fn query(cache: &QueryCache, qname: &str, key: u64, in_tx: bool, v: u64) -> (Value, bool) {
+        let ran = Cell::new(false);
+        let res = cache
+            .query(qname, vec![key.into()], in_tx, |_| {
+                ran.set(true);
+                Ok(rows(v))
+            })
+            .unwrap();
+        (res.values[0][0].clone(), ran.get())
+    }
+
+    #[test]
+    fn serves_cached_results_per_key() {
+        let c = cache();
+        assert_eq!(query(&c, "qs_by_lec", 1, false, 1), (1u64.into(), true));
+        assert_eq!(query(&c, "qs_by_lec", 1, false, 2), (1u64.into(), false));
+        assert_eq!(query(&c, "qs_by_lec", 2, false, 3), (3u64.into(), true));
+        // queries that are not cached always run
+        assert_eq!(query(&c, "answers_by_lec", 1, false, 4), (4u64.into(), true));
+        assert_eq!(query(&c, "answers_by_lec", 1, false, 5), (5u64.into(), true));
+    }
+
+    #[test]
+    fn writes_invalidate_readers_only() {
+        let c = cache();
+        query(&c, "leclist", 0, false, 1);
+        query(&c, "qs_by_lec", 1, false, 1);
+        c.invalidate("answers");
+        assert!(!query(&c, "leclist", 0, false, 2).1);
+        assert!(!query(&c, "qs_by_lec", 1, false, 2).1);
+        // leclist reads questions through the lec_qcount view
+        c.invalidate("questions");
+        assert_eq!(query(&c, "leclist", 0, false, 3), (3u64.into(), true));
+        assert_eq!(query(&c, "qs_by_lec", 1, false, 3), (3u64.into(), true));
+        c.invalidate("lectures");
+        assert!(query(&c, "leclist", 0, false, 4).1);
+        assert!(!query(&c, "qs_by_lec", 1, false, 4).1);
+        c.clear();
+        assert!(query(&c, "qs_by_lec", 1, false, 5).1);
+    }
+
+    #[test]
+    fn transactions_bypass_the_cache() {
+        let c = cache();
+        query(&c, "qs_by_lec", 1, false, 1);
+        assert_eq!(query(&c, "qs_by_lec", 1, true, 2), (2u64.into(), true));
+        assert_eq!(query(&c, "qs_by_lec", 2, true, 3), (3u64.into(), true));
+        // nor are results read in a transaction kept
+        assert_eq!(query(&c, "qs_by_lec", 2, false, 4), (4u64.into(), true));
+        assert_eq!(query(&c, "qs_by_lec", 1, false, 5), (1u64.into(), false));
+    }
+
+    #[test]
+    fn results_read_before_a_write_are_not_kept() {
+        let c = cache();
+        let res = c
+            .query("qs_by_lec", vec![1u64.into()], false, |_| {
+                // a write that commits while the query runs
+                c.invalidate("questions");
+                Ok(rows(1))
+            })
+            .unwrap();
+        assert_eq!(res.values[0][0], 1u64.into());
+        assert_eq!(query(&c, "qs_by_lec", 1, false, 2), (2u64.into(), true));
+        assert_eq!(query(&c, "qs_by_lec", 1, false, 3), (2u64.into(), false));
+    }
+}
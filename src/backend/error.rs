@@ -0,0 +1,166 @@
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Flash, Redirect, Responder};
+use std::fmt;
+
+use super::AsyncBackend;
+
+/// Errors returned by the backend API.
+#[derive(Debug)]
+pub enum BackendError {
+    /// No query with this name is defined in `schema.sql`
+    UnknownQuery(String),
+    /// No table with this name is defined in `schema.sql`
+    UnknownTable(String),
+    /// A write violated a primary key or other constraint
+    ConstraintViolation(String),
+    /// The database could not be reached
+    ConnectionLost(String),
+    /// A row's stored policy could not be deserialized
+    PolicyDeserialization(String),
+    /// A row's stored policy could not be merged with a new one
+    PolicyMerge(String),
+    /// A row does not match the type it is read into
+    RowMapping(String),
+    /// Any other database error
+    Database(String),
+}
+
+pub type Result<T> = std::result::Result<T, BackendError>;
+
+impl BackendError {
+    /// The HTTP status a request failing with this error should return.
+    pub fn status(&self) -> Status {
+        match self {
+            BackendError::ConstraintViolation(_) => Status::Conflict,
+            BackendError::ConnectionLost(_) => Status::ServiceUnavailable,
+            _ => Status::InternalServerError,
+        }
+    }
+
+    /// A short description of the error that is safe to show to users.
+    pub fn user_message(&self) -> &'static str {
+        match self {
+            BackendError::ConstraintViolation(_) => {
+                "This entry conflicts with an existing one (e.g., a duplicate ID)."
+            }
+            BackendError::ConnectionLost(_) => {
+                "The database is currently unavailable. Please try again later."
+            }
+            _ => "Something went wrong while accessing the database.",
+        }
+    }
+
+    /// Logs the error and turns it into an error flash message on `redirect`,
+    /// for handlers that return to a form when a write fails.
+    pub fn flash(self, log: &slog::Logger, redirect: Redirect) -> Flash<Redirect> {
+        error!(log, "{}", self);
+        Flash::error(redirect, self.user_message())
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::UnknownQuery(q) => write!(f, "unknown query \"{}\"", q),
+            BackendError::UnknownTable(t) => write!(f, "unknown table \"{}\"", t),
+            BackendError::ConstraintViolation(e) => write!(f, "constraint violation: {}", e),
+            BackendError::ConnectionLost(e) => write!(f, "database connection lost: {}", e),
+            BackendError::PolicyDeserialization(e) => {
+                write!(f, "failed to deserialize policy: {}", e)
+            }
+            BackendError::PolicyMerge(e) => write!(f, "failed to merge policies: {}", e),
+            BackendError::RowMapping(e) => write!(f, "row mapping failed: {}", e),
+            BackendError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+impl From<mysql::Error> for BackendError {
+    fn from(e: mysql::Error) -> Self {
+        use mysql::{DriverError, Error};
+        match e {
+            // ER_DUP_ENTRY, ER_BAD_NULL_ERROR, foreign key failures
+            Error::MySqlError(ref me) if [1062, 1048, 1451, 1452].contains(&me.code) => {
+                BackendError::ConstraintViolation(me.message.clone())
+            }
+            Error::IoError(_)
+            | Error::DriverError(DriverError::CouldNotConnect(_))
+            | Error::DriverError(DriverError::ConnectionClosed) => {
+                BackendError::ConnectionLost(e.to_string())
+            }
+            _ => BackendError::Database(e.to_string()),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for BackendError {
+    fn from(e: rusqlite::Error) -> Self {
+        use rusqlite::ErrorCode;
+        match e {
+            rusqlite::Error::SqliteFailure(ref fe, _) => match fe.code {
+                ErrorCode::ConstraintViolation => BackendError::ConstraintViolation(e.to_string()),
+                ErrorCode::CannotOpen => BackendError::ConnectionLost(e.to_string()),
+                _ => BackendError::Database(e.to_string()),
+            },
+            _ => BackendError::Database(e.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for BackendError {
+    fn from(e: serde_json::Error) -> Self {
+        BackendError::PolicyDeserialization(e.to_string())
+    }
+}
+
+/// Handlers can return backend errors directly; they are logged and turned
+/// into an error page with the matching status code.
+impl<'r> Responder<'r, 'static> for BackendError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        if let Some(backend) = request.rocket().state::<AsyncBackend>() {
+            error!(backend.log(), "{} {}: {}", request.method(), request.uri(), self);
+        }
+        (self.status(), self.user_message()).respond_to(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Backend, SqliteBackend};
+
+    #[test]
+    fn duplicate_keys_are_conflicts() {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        be.insert("lectures", vec![1.into(), "Intro".into()]).unwrap();
+        let err = be.insert("lectures", vec![1.into(), "Again".into()]).unwrap_err();
+        assert!(matches!(err, BackendError::ConstraintViolation(_)));
+        assert_eq!(err.status(), Status::Conflict);
+        assert!(err.user_message().contains("conflicts"));
+    }
+
+    #[test]
+    fn only_lost_connections_are_unavailable() {
+        let lost = BackendError::ConnectionLost("gone".to_string());
+        assert_eq!(lost.status(), Status::ServiceUnavailable);
+        for err in &[
+            BackendError::UnknownQuery("q".to_string()),
+            BackendError::RowMapping("bad row".to_string()),
+            BackendError::Database("failed".to_string()),
+        ] {
+            assert_eq!(err.status(), Status::InternalServerError);
+            assert!(!err.user_message().contains("bad row"));
+        }
+    }
+
+    #[test]
+    fn sqlite_errors_are_classified() {
+        let be = SqliteBackend::for_tests();
+        let err = be.exec_raw("SELECT * FROM no_such_table;", vec![]).unwrap_err();
+        assert!(matches!(err, BackendError::Database(_)));
+    }
+}
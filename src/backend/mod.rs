@@ -0,0 +1,506 @@
+pub use mysql::Value;
+
+use beaver::generic_policied::{GPolicied, AsPolicied};
+use std::time::Duration;
+
+mod async_backend;
+mod audit;
+mod cache;
+mod error;
+mod mysql_backend;
+mod policies;
+mod rows;
+mod schema;
+mod sqlite_backend;
+mod stats;
+
+pub use self::async_backend::{AsyncBackend, RowStream};
+pub use self::audit::redact_recorded;
+pub use self::cache::QueryCache;
+pub use self::error::{BackendError, Result};
+pub use self::mysql_backend::MySqlBackend;
+pub use self::policies::{
+    encode_policy, policy_id, upgrade_policy, EncodedPolicy, PolicyStore, PolicyUpdate,
+};
+pub use self::rows::{check_columns, FromRow, Page, Row, Rows};
+pub use self::schema::{split_statements, Schema, SchemaError, SchemaStmt};
+pub use self::sqlite_backend::SqliteBackend;
+pub use self::stats::{QueryStat, QueryStats};
+
+/// Storage interface used by the route handlers.
+///
+/// Queries are referred to by the names given to them in `schema.sql`, and
+/// tables are written to using the column order of their `CREATE TABLE`
+/// statement. Policied tables store the id of their policy (see
+/// `PolicyStore`) in the last column.
+///
+/// Implementations handle their own connection management, so a backend can
+/// be shared between concurrently running requests.
+pub trait Backend: Send + Sync {
+    fn log(&self) -> &slog::Logger;
+
+    /// The policies this backend has read or written.
+    fn policies(&self) -> &PolicyStore;
+
+    /// Timings of the named queries and writes this backend has run.
+    fn stats(&self) -> &QueryStats;
+
+    /// Whether this is a view of a backend within a transaction.
+    fn in_transaction(&self) -> bool;
+
+    /// The key columns and all columns of `table`, in `CREATE TABLE` order.
+    fn table(&self, table: &str) -> Result<&(Vec<String>, Vec<String>)>;
+
+    /// Runs a named query, returning its rows along with their column names.
+    fn query_rows(&self, qname: &str, keys: Vec<Value>) -> Result<Rows>;
+
+    fn query_exec(&self, qname: &str, keys: Vec<Value>) -> Result<Vec<Vec<Value>>> {
+        Ok(self.query_rows(qname, keys)?.values)
+    }
+
+    /// Runs a named query and returns the rows in `page`.
+    fn query_page(&self, qname: &str, keys: Vec<Value>, page: Page) -> Result<Rows>;
+
+    /// Runs a named query and passes its rows to `f` as they are read,
+    /// without collecting the result set; stops at the first error `f`
+    /// returns. The connection stays busy while `f` runs, so `f` must not
+    /// use the backend.
+    fn query_each(
+        &self,
+        qname: &str,
+        keys: Vec<Value>,
+        f: &mut dyn FnMut(Row) -> Result<()>,
+    ) -> Result<()>;
+
+    /// Returns the names of the columns a named query returns, without
+    /// running it.
+    fn query_columns(&self, qname: &str) -> Result<Vec<String>>;
+
+    /// Runs an SQL statement that is not one of the named queries, such as
+    /// the DDL in a migration.
+    fn exec_raw(&self, sql: &str, args: Vec<Value>) -> Result<()>;
+
+    /// Like `exec_raw`, returning the number of rows the statement changed,
+    /// e.g. to tell whether a conditional update won a race.
+    fn exec_affected(&self, sql: &str, args: Vec<Value>) -> Result<u64>;
+
+    /// Runs an SQL query that is not one of the named queries.
+    fn query_raw(&self, sql: &str, args: Vec<Value>) -> Result<Vec<Vec<Value>>>;
+
+    fn insert(&self, table: &str, vals: Vec<Value>) -> Result<()>;
+
+    fn update(&self, table: &str, keys: Vec<Value>, vals: Vec<(usize, Value)>) -> Result<()>;
+
+    fn insert_or_update(
+        &self,
+        table: &str,
+        rec: Vec<Value>,
+        update_vals: Vec<(u64, Value)>,
+    ) -> Result<()>;
+
+    /// Deletes the row with primary key `keys`, which must give a value for
+    /// every key column.
+    fn delete(&self, table: &str, keys: Vec<Value>) -> Result<()>;
+
+    /// Deletes all rows whose columns (by index) match `conds`, e.g. all
+    /// answers to one lecture. At least one condition is required.
+    fn delete_where(&self, table: &str, conds: Vec<(usize, Value)>) -> Result<()>;
+
+    /// Runs `f` in a database transaction, which is committed if `f`
+    /// succeeds and rolled back if it fails. A transaction started inside
+    /// `f` joins the enclosing one. Use `with_transaction` instead.
+    fn transaction(&self, f: &mut dyn FnMut(&dyn Backend) -> Result<()>) -> Result<()>;
+}
+
+/// Builds a `DELETE` statement for `table` matching each of `cols`.
+fn delete_sql(table: &str, cols: &[&String]) -> Result<String> {
+    if cols.is_empty() {
+        return Err(BackendError::Database(format!(
+            "refusing to delete from {} without conditions",
+            table
+        )));
+    }
+    let conds: Vec<_> = cols.iter().map(|c| format!("{} = ?", c)).collect();
+    Ok(format!(r"DELETE FROM {} WHERE {};", table, conds.join(" AND ")))
+}
+
+/// Checks that `keys` gives a value for each of a table's key columns.
+fn check_keys(table: &str, key_cols: &[String], keys: &[Value]) -> Result<()> {
+    if key_cols.len() != keys.len() {
+        Err(BackendError::Database(format!(
+            "{} has {} key column(s), but {} key value(s) were given",
+            table,
+            key_cols.len(),
+            keys.len()
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks that each of `indices` is a column of a table with columns `cols`.
+fn check_indices<I: IntoIterator<Item = usize>>(
+    table: &str,
+    cols: &[String],
+    indices: I,
+) -> Result<()> {
+    match indices.into_iter().find(|i| *i >= cols.len()) {
+        Some(i) => Err(BackendError::Database(format!(
+            "{} has {} column(s), but column {} was given",
+            table,
+            cols.len(),
+            i
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Checks that an update of `table` sets at least one column, and only
+/// columns the table has.
+fn check_assignments(table: &str, cols: &[String], vals: &[(usize, Value)]) -> Result<()> {
+    if vals.is_empty() {
+        return Err(BackendError::Database(format!(
+            "refusing to update {} without values",
+            table
+        )));
+    }
+    check_indices(table, cols, vals.iter().map(|(i, _)| *i))
+}
+
+/// Maps a row of a policied table to `T`, attaching the row's policy.
+fn decode_policied_as<T: FromRow>(policies: &PolicyStore, mut row: Row) -> Result<GPolicied<T>> {
+    let p = policies.decode(row.take_policy()?)?;
+    Ok(T::from_row(&row)?.policied_with(p))
+}
+
+/// Runs a query over a policied table, loading the policies its rows use.
+fn query_policied_rows(be: &dyn Backend, qname: &str, keys: Vec<Value>) -> Result<Rows> {
+    let rows = be.query_rows(qname, keys)?;
+    be.policies().load(be, &rows)?;
+    Ok(rows)
+}
+
+impl dyn Backend {
+    /// Runs `f` in a transaction: all of its writes take effect if it
+    /// returns `Ok`, and none of them do if it returns an error.
+    pub fn with_transaction<T, F: FnOnce(&dyn Backend) -> Result<T>>(&self, f: F) -> Result<T> {
+        let mut f = Some(f);
+        let mut res = None;
+        self.transaction(&mut |be| {
+            let f = f.take().expect("transaction body run twice");
+            res = Some(f(be)?);
+            Ok(())
+        })?;
+        Ok(res.expect("transaction body not run"))
+    }
+
+    /// Runs a named query and maps each row to `T` by column name.
+    pub fn query_as<T: FromRow>(&self, qname: &str, keys: Vec<Value>) -> Result<Vec<T>> {
+        self.query_rows(qname, keys)?
+            .into_rows()
+            .map(|row| T::from_row(&row))
+            .collect()
+    }
+
+    /// Like `query_as`, for queries over policied tables.
+    pub fn query_as_policied<T: FromRow>(
+        &self,
+        qname: &str,
+        keys: Vec<Value>,
+    ) -> Result<Vec<GPolicied<T>>> {
+        query_policied_rows(self, qname, keys)?
+            .into_rows()
+            .map(|row| decode_policied_as(self.policies(), row))
+            .collect()
+    }
+
+    /// Like `query_as`, for the rows in `page`.
+    pub fn query_page_as<T: FromRow>(
+        &self,
+        qname: &str,
+        keys: Vec<Value>,
+        page: Page,
+    ) -> Result<Vec<T>> {
+        self.query_page(qname, keys, page)?
+            .into_rows()
+            .map(|row| T::from_row(&row))
+            .collect()
+    }
+
+    /// Like `query_as_policied`, for the rows in `page`.
+    pub fn query_page_as_policied<T: FromRow>(
+        &self,
+        qname: &str,
+        keys: Vec<Value>,
+        page: Page,
+    ) -> Result<Vec<GPolicied<T>>> {
+        let rows = self.query_page(qname, keys, page)?;
+        self.policies().load(self, &rows)?;
+        rows.into_rows()
+            .map(|row| decode_policied_as(self.policies(), row))
+            .collect()
+    }
+
+    /// Stores `policy` and appends its id to a record for a policied table,
+    /// so that it can be written with the plain `insert` and
+    /// `insert_or_update` methods (e.g., inside a transaction).
+    pub fn with_policy(&self, mut rec: Vec<Value>, policy: EncodedPolicy) -> Result<Vec<Value>> {
+        rec.push(self.policies().store(self, policy)?);
+        Ok(rec)
+    }
+
+    /// The id of the policy stored for the row of `table` with primary key
+    /// `keys`, or `None` if there is no such row.
+    pub fn stored_policy(&self, table: &str, keys: Vec<Value>) -> Result<Option<Value>> {
+        let (key_cols, _) = self.table(table)?;
+        check_keys(table, key_cols, &keys)?;
+        let conds: Vec<_> = key_cols.iter().map(|c| format!("{} = ?", c)).collect();
+        let sql = format!("SELECT policy FROM {} WHERE {};", table, conds.join(" AND "));
+        Ok(self.query_raw(&sql, keys)?.pop().and_then(|mut row| row.pop()))
+    }
+
+    /// The policy to write for the row of `table` with primary key `keys`:
+    /// `policy` itself, or with `PolicyUpdate::Merge`, the row's stored
+    /// policy merged with it (if the row exists). Fails if the stored policy
+    /// cannot be decoded.
+    fn updated_policy(
+        &self,
+        table: &str,
+        keys: Vec<Value>,
+        policy: EncodedPolicy,
+        mode: PolicyUpdate,
+    ) -> Result<EncodedPolicy> {
+        if mode == PolicyUpdate::Replace {
+            return Ok(policy);
+        }
+        let old = match self.stored_policy(table, keys)? {
+            Some(id) => {
+                self.policies().load_id(self, id.clone())?;
+                self.policies().decode_strict(id)?
+            }
+            None => return Ok(policy),
+        };
+        let merged = old
+            .merge(&policy.decode()?)
+            .map_err(|e| BackendError::PolicyMerge(e.message))?;
+        encode_policy(merged.as_ref())
+    }
+
+    /// Inserts `rec` into a policied table with `policy`. If a row with the
+    /// same primary key exists, it is updated with `update_vals`, and its
+    /// policy is replaced or merged with `policy` as `mode` says.
+    pub fn insert_or_update_policied(
+        &self,
+        table: &str,
+        rec: Vec<Value>,
+        mut update_vals: Vec<(u64, Value)>,
+        policy: EncodedPolicy,
+        mode: PolicyUpdate,
+    ) -> Result<()> {
+        self.with_transaction(move |be| {
+            let (key_cols, cols) = be.table(table)?;
+            let keys = key_cols
+                .iter()
+                .map(|k| cols.iter().position(|c| c == k).and_then(|i| rec.get(i).cloned()))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| {
+                    BackendError::Database(format!("record for {} lacks key columns", table))
+                })?;
+            let rec = be.with_policy(rec, be.updated_policy(table, keys, policy, mode)?)?;
+            let i = rec.len() - 1;
+            update_vals.push((i as u64, rec[i].clone()));
+            be.insert_or_update(table, rec, update_vals)
+        })
+    }
+
+    /// Updates the row of a policied table with primary key `keys`, and
+    /// replaces or merges its policy with `policy` as `mode` says.
+    pub fn update_policied(
+        &self,
+        table: &str,
+        keys: Vec<Value>,
+        mut vals: Vec<(usize, Value)>,
+        policy: EncodedPolicy,
+        mode: PolicyUpdate,
+    ) -> Result<()> {
+        self.with_transaction(move |be| {
+            let policy = be.updated_policy(table, keys.clone(), policy, mode)?;
+            let (_, cols) = be.table(table)?;
+            vals.push((cols.len() - 1, be.policies().store(be, policy)?));
+            be.update(table, keys, vals)
+        })
+    }
+}
+
+/// Connects to the storage backend selected in the configuration, using the
+/// configured schema (or the built-in one). A fresh in-memory SQLite
+/// database is migrated to the current schema version.
+pub fn open(
+    config: &crate::config::Config,
+    dbname: &str,
+    log: Option<slog::Logger>,
+) -> std::result::Result<Box<dyn Backend>, String> {
+    let schema = Schema::load(config.schema_path.as_deref())?;
+    let stats = QueryStats::new(Duration::from_millis(config.slow_query_ms));
+    let cache = QueryCache::new(&schema, &config.cached_queries)?;
+    match config.backend.as_str() {
+        "mysql" => MySqlBackend::new(&config.database, dbname, schema, stats, cache, log)
+            .map(|b| Box::new(b) as Box<dyn Backend>)
+            .map_err(|e| e.to_string()),
+        "sqlite" => {
+            let be: Box<dyn Backend> = Box::new(
+                SqliteBackend::new(config.sqlite_path.as_deref(), schema, stats, cache, log)
+                    .map_err(|e| e.to_string())?,
+            );
+            if config.sqlite_path.is_none() {
+                crate::migrations::up(&*be, None).map_err(|e| e.to_string())?;
+            }
+            Ok(be)
+        }
+        other => Err(format!("unknown backend \"{}\"", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use beaver::filter::Context;
+    use beaver::policy::{MergePolicy, Policy, PolicyError};
+
+    #[derive(Clone, Serialize, Deserialize)]
+    struct AllowUser {
+        user: String,
+    }
+
+    #[typetag::serde]
+    impl Policy for AllowUser {
+        fn check(&self, ctxt: &Context) -> std::result::Result<(), PolicyError> {
+            match ctxt {
+                Context::KVContext(m) if m.get("user") == Some(&self.user) => Ok(()),
+                _ => Err(PolicyError { message: "not allowed".to_string() }),
+            }
+        }
+        fn merge(
+            &self,
+            other: &Box<dyn Policy>,
+        ) -> std::result::Result<Box<dyn Policy>, PolicyError> {
+            Ok(Box::new(MergePolicy::make(Box::new(self.clone()), other.clone())))
+        }
+    }
+
+    fn allow(user: &str) -> EncodedPolicy {
+        encode_policy(&AllowUser { user: user.to_string() }).unwrap()
+    }
+
+    /// The id `policy` is stored under.
+    fn id_of(be: &dyn Backend, policy: EncodedPolicy) -> Value {
+        be.policies().store(be, policy).unwrap()
+    }
+
+    fn merged(be: &dyn Backend, old: &str, new: &str) -> Value {
+        let old = allow(old).decode().unwrap();
+        let merged = old.merge(&allow(new).decode().unwrap()).unwrap();
+        id_of(be, encode_policy(merged.as_ref()).unwrap())
+    }
+
+    fn keys() -> Vec<Value> {
+        vec!["a@example.com".into(), 1.into(), 1.into()]
+    }
+
+    fn answer(text: &str) -> Vec<Value> {
+        vec![
+            "a@example.com".into(),
+            1.into(),
+            1.into(),
+            text.into(),
+            "2021-01-01 00:00:00".into(),
+        ]
+    }
+
+    fn stored(be: &dyn Backend) -> Value {
+        be.stored_policy("answers", keys()).unwrap().unwrap()
+    }
+
+    /// A migrated database with an answer that only user "a" may see.
+    fn backend() -> SqliteBackend {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        let db: &dyn Backend = &be;
+        let mode = PolicyUpdate::Merge;
+        db.insert_or_update_policied("answers", answer("yes"), vec![], allow("a"), mode)
+            .unwrap();
+        be
+    }
+
+    #[test]
+    fn insert_or_update_stores_policy_of_new_row() {
+        let be = backend();
+        assert_eq!(stored(&be), id_of(&be, allow("a")));
+    }
+
+    #[test]
+    fn insert_or_update_merges_policy() {
+        let be = backend();
+        let db: &dyn Backend = &be;
+        let update = vec![(3, "no".into())];
+        let mode = PolicyUpdate::Merge;
+        db.insert_or_update_policied("answers", answer("no"), update, allow("b"), mode)
+            .unwrap();
+        assert_eq!(stored(db), merged(db, "a", "b"));
+    }
+
+    #[test]
+    fn insert_or_update_replaces_policy() {
+        let be = backend();
+        let db: &dyn Backend = &be;
+        let update = vec![(3, "no".into())];
+        let mode = PolicyUpdate::Replace;
+        db.insert_or_update_policied("answers", answer("no"), update, allow("b"), mode)
+            .unwrap();
+        assert_eq!(stored(db), id_of(db, allow("b")));
+    }
+
+    #[test]
+    fn update_merges_policy() {
+        let be = backend();
+        let db: &dyn Backend = &be;
+        let mode = PolicyUpdate::Merge;
+        db.update_policied("answers", keys(), vec![(3, "no".into())], allow("b"), mode)
+            .unwrap();
+        assert_eq!(stored(db), merged(db, "a", "b"));
+    }
+
+    #[test]
+    fn update_replaces_policy() {
+        let be = backend();
+        let db: &dyn Backend = &be;
+        let mode = PolicyUpdate::Replace;
+        db.update_policied("answers", keys(), vec![(3, "no".into())], allow("b"), mode)
+            .unwrap();
+        assert_eq!(stored(db), id_of(db, allow("b")));
+    }
+
+    #[test]
+    fn merge_refuses_undecodable_policy() {
+        let be = backend();
+        let db: &dyn Backend = &be;
+        let json = r#"{"version":1,"policy":{"RenamedPolicy":{}}}"#;
+        be.exec_raw("INSERT INTO policies VALUES (?, ?);", vec!["bad".into(), json.into()])
+            .unwrap();
+        be.exec_raw("UPDATE answers SET policy = ?;", vec!["bad".into()])
+            .unwrap();
+
+        let merge = PolicyUpdate::Merge;
+        assert!(db
+            .update_policied("answers", keys(), vec![], allow("b"), merge)
+            .is_err());
+        assert!(db
+            .insert_or_update_policied("answers", answer("no"), vec![], allow("b"), merge)
+            .is_err());
+        assert_eq!(stored(db), Value::from("bad"));
+
+        db.update_policied("answers", keys(), vec![], allow("b"), PolicyUpdate::Replace)
+            .unwrap();
+        assert_eq!(stored(db), id_of(db, allow("b")));
+    }
+}
@@ -0,0 +1,470 @@
+use mysql::prelude::*;
+use mysql::*;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use super::audit;
+use super::rows::paged_sql;
+use super::{
+    check_assignments, check_indices, check_keys, delete_sql, Backend, BackendError, Page,
+    PolicyStore, QueryCache, QueryStats, Rows, Schema, SchemaStmt,
+};
+use crate::config::DatabaseConfig;
+
+pub struct MySqlBackend {
+    pub pool: mysql::Pool,
+    pub log: slog::Logger,
+    _schema: Arc<str>,
+
+    // table name --> (keys, columns)
+    tables: Arc<HashMap<String, (Vec<String>, Vec<String>)>>,
+    // query name --> SQL; each pooled connection caches the prepared statements
+    queries: Arc<HashMap<String, String>>,
+    policies: Arc<PolicyStore>,
+    stats: Arc<QueryStats>,
+    cache: Arc<QueryCache>,
+    // within a transaction, the connection it runs on
+    tx_conn: Option<Mutex<PooledConn>>,
+    // within a transaction, the tables it wrote to
+    tx_written: Mutex<Vec<String>>,
+}
+
+/// A connection from the pool, or the connection of the current transaction.
+enum ConnGuard<'a> {
+    Pooled(PooledConn),
+    Transaction(MutexGuard<'a, PooledConn>),
+}
+
+impl Deref for ConnGuard<'_> {
+    type Target = PooledConn;
+
+    fn deref(&self) -> &PooledConn {
+        match self {
+            ConnGuard::Pooled(c) => c,
+            ConnGuard::Transaction(c) => &**c,
+        }
+    }
+}
+
+impl DerefMut for ConnGuard<'_> {
+    fn deref_mut(&mut self) -> &mut PooledConn {
+        match self {
+            ConnGuard::Pooled(c) => c,
+            ConnGuard::Transaction(c) => &mut **c,
+        }
+    }
+}
+
+/// Rolls back the transaction open on a transaction view's connection if
+/// the transaction body panics, so that the connection does not go back to
+/// the pool with the transaction still open.
+struct TxRollback<'a>(&'a MySqlBackend);
+
+impl Drop for TxRollback<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            if let Ok(mut conn) = self.0.conn() {
+                let _ = conn.query_drop("ROLLBACK");
+            }
+        }
+    }
+}
+
+impl MySqlBackend {
+    pub fn new(
+        dbconfig: &DatabaseConfig,
+        dbname: &str,
+        schema: Schema,
+        stats: QueryStats,
+        cache: QueryCache,
+        log: Option<slog::Logger>,
+    ) -> Result<Self> {
+        let log = match log {
+            None => slog::Logger::root(slog::Discard, o!()),
+            Some(l) => l,
+        };
+
+        // connect to everything
+        debug!(log, "Connecting to MySql DB {}...", dbname);
+        let pool = mysql::Pool::new_manual(
+            dbconfig.pool_min,
+            dbconfig.pool_max,
+            connection_opts(dbconfig, dbname)?,
+        )?;
+        if !pool.get_conn()?.ping() {
+            return Err(Error::DriverError(DriverError::CouldNotConnect(None)));
+        }
+
+        // tables are created by the migrations; queries are prepared on
+        // first use, since they may refer to tables that do not exist yet
+        let mut queries = HashMap::new();
+        for stmt in &schema.stmts {
+            if let SchemaStmt::Query(name, sql) = stmt {
+                queries.insert(name.to_string(), sql.to_string());
+            }
+        }
+        let policies = PolicyStore::new(log.clone());
+        Ok(MySqlBackend {
+            pool: pool,
+            log: log,
+            _schema: schema.text.into(),
+
+            tables: Arc::new(schema.tables),
+            queries: Arc::new(queries),
+            policies: Arc::new(policies),
+            stats: Arc::new(stats),
+            cache: Arc::new(cache),
+            tx_conn: None,
+            tx_written: Mutex::new(vec![]),
+        })
+    }
+}
+
+/// Builds MySQL connection options from the deployment's database config.
+fn connection_opts(dbconfig: &DatabaseConfig, dbname: &str) -> Result<Opts> {
+    let mut builder = OptsBuilder::new()
+        .user(Some(&dbconfig.user))
+        .pass(dbconfig.password()?)
+        .db_name(Some(dbconfig.name.as_deref().unwrap_or(dbname)))
+        .stmt_cache_size(Some(dbconfig.stmt_cache_size));
+    builder = match dbconfig.socket {
+        Some(ref socket) => builder.socket(Some(socket)),
+        None => builder
+            .ip_or_hostname(Some(&dbconfig.host))
+            .tcp_port(dbconfig.port),
+    };
+    if dbconfig.tls {
+        let ssl_opts = SslOpts::default()
+            .with_root_cert_path(dbconfig.tls_ca.clone().map(std::path::PathBuf::from))
+            .with_danger_accept_invalid_certs(dbconfig.tls_accept_invalid_certs);
+        builder = builder.ssl_opts(Some(ssl_opts));
+    }
+    Ok(builder.into())
+}
+
+impl MySqlBackend {
+    fn conn(&self) -> super::Result<ConnGuard<'_>> {
+        Ok(match self.tx_conn {
+            Some(ref c) => ConnGuard::Transaction(c.lock().unwrap_or_else(|e| e.into_inner())),
+            None => ConnGuard::Pooled(self.pool.get_conn()?),
+        })
+    }
+
+    fn query(&self, qname: &str) -> super::Result<&String> {
+        self.queries
+            .get(qname)
+            .ok_or_else(|| BackendError::UnknownQuery(qname.to_string()))
+    }
+
+    fn query_sql(&self, sql: &str, keys: Vec<Value>) -> super::Result<Rows> {
+        let mut conn = self.conn()?;
+        // served from the connection's statement cache after the first use
+        let stmt = conn.prep(sql)?;
+        let columns = stmt
+            .columns()
+            .iter()
+            .map(|c| c.name_str().to_string())
+            .collect();
+        let values = conn.exec_map(&stmt, keys, |row: Row| row.unwrap())?;
+        Ok(Rows::new(columns, values))
+    }
+
+    /// Drops cached results that read `table`, now and, in a transaction,
+    /// again when it ends.
+    fn invalidate(&self, table: &str) {
+        self.cache.invalidate(table);
+        if self.tx_conn.is_some() {
+            self.tx_written
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(table.to_string());
+        }
+    }
+
+    /// Runs a write to `table`, timed as `<kind>:<table>`.
+    fn exec_write(
+        &self,
+        kind: &str,
+        table: &str,
+        q: String,
+        args: Vec<Value>,
+    ) -> super::Result<()> {
+        let res = self.stats.time(
+            &self.log,
+            &format!("{}:{}", kind, table),
+            || {
+                let mut conn = self.conn()?;
+                conn.exec_drop(q, args)?;
+                Ok(conn.affected_rows())
+            },
+            |n| *n as usize,
+        );
+        // only after the write, so that no result read before it is cached
+        self.invalidate(table);
+        res.map(|_| ())
+    }
+}
+
+impl Backend for MySqlBackend {
+    fn log(&self) -> &slog::Logger {
+        &self.log
+    }
+
+    fn policies(&self) -> &PolicyStore {
+        &self.policies
+    }
+
+    fn stats(&self) -> &QueryStats {
+        &self.stats
+    }
+
+    fn in_transaction(&self) -> bool {
+        self.tx_conn.is_some()
+    }
+
+    fn table(&self, table: &str) -> super::Result<&(Vec<String>, Vec<String>)> {
+        self.tables
+            .get(table)
+            .ok_or_else(|| BackendError::UnknownTable(table.to_string()))
+    }
+
+    fn transaction(
+        &self,
+        f: &mut dyn FnMut(&dyn Backend) -> super::Result<()>,
+    ) -> super::Result<()> {
+        if self.tx_conn.is_some() {
+            // nested: part of the enclosing transaction
+            return f(self);
+        }
+        let mut conn = self.pool.get_conn()?;
+        conn.query_drop("START TRANSACTION")?;
+        // a view of this backend that runs everything on the transaction's
+        // connection
+        let tx = MySqlBackend {
+            pool: self.pool.clone(),
+            log: self.log.clone(),
+            _schema: self._schema.clone(),
+            tables: self.tables.clone(),
+            queries: self.queries.clone(),
+            policies: self.policies.clone(),
+            stats: self.stats.clone(),
+            cache: self.cache.clone(),
+            tx_conn: Some(Mutex::new(conn)),
+            tx_written: Mutex::new(vec![]),
+        };
+        let res = {
+            let _rollback = TxRollback(&tx);
+            f(&tx)
+        };
+        let mut conn = tx
+            .tx_conn
+            .unwrap()
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner());
+        let res = match res {
+            Ok(()) => conn.query_drop("COMMIT").map_err(BackendError::from),
+            Err(e) => {
+                if let Err(re) = conn.query_drop("ROLLBACK") {
+                    error!(self.log, "failed to roll back transaction: {}", re);
+                }
+                Err(e)
+            }
+        };
+        // results other requests read while the transaction was open are
+        // outdated now that it committed
+        for table in tx.tx_written.into_inner().unwrap_or_else(|e| e.into_inner()) {
+            self.cache.invalidate(&table);
+        }
+        res
+    }
+
+    fn query_rows(&self, qname: &str, keys: Vec<Value>) -> super::Result<Rows> {
+        let sql = self.query(qname)?;
+        self.cache.query(qname, keys, self.in_transaction(), |keys| {
+            self.stats.time(
+                &self.log,
+                qname,
+                || self.query_sql(sql, keys),
+                |rows| rows.values.len(),
+            )
+        })
+    }
+
+    fn query_page(&self, qname: &str, mut keys: Vec<Value>, page: Page) -> super::Result<Rows> {
+        let sql = paged_sql(qname, self.query(qname)?, &mut keys, page)?;
+        self.stats.time(
+            &self.log,
+            qname,
+            || self.query_sql(&sql, keys),
+            |rows| rows.values.len(),
+        )
+    }
+
+    fn query_each(
+        &self,
+        qname: &str,
+        keys: Vec<Value>,
+        f: &mut dyn FnMut(super::Row) -> super::Result<()>,
+    ) -> super::Result<()> {
+        let sql = self.query(qname)?;
+        let count = || {
+            let mut conn = self.conn()?;
+            let stmt = conn.prep(sql)?;
+            let columns: Arc<[String]> = stmt
+                .columns()
+                .iter()
+                .map(|c| c.name_str().to_string())
+                .collect::<Vec<_>>()
+                .into();
+            let mut n = 0;
+            for row in conn.exec_iter(&stmt, keys)? {
+                f(super::Row::new(columns.clone(), row?.unwrap()))?;
+                n += 1;
+            }
+            Ok(n)
+        };
+        self.stats.time(&self.log, qname, count, |n| *n).map(|_| ())
+    }
+
+    fn query_columns(&self, qname: &str) -> super::Result<Vec<String>> {
+        let stmt = self.conn()?.prep(self.query(qname)?)?;
+        Ok(stmt
+            .columns()
+            .iter()
+            .map(|c| c.name_str().to_string())
+            .collect())
+    }
+
+    fn exec_raw(&self, sql: &str, args: Vec<Value>) -> super::Result<()> {
+        let mut conn = self.conn()?;
+        let res = if args.is_empty() {
+            // DDL is not always preparable, so use the text protocol
+            conn.query_drop(sql)
+        } else {
+            conn.exec_drop(sql, args)
+        };
+        drop(conn);
+        // may have written to any table
+        for table in self.tables.keys() {
+            self.invalidate(table);
+        }
+        Ok(res?)
+    }
+
+    fn exec_affected(&self, sql: &str, args: Vec<Value>) -> super::Result<u64> {
+        let mut conn = self.conn()?;
+        let res = conn.exec_drop(sql, args).map(|_| conn.affected_rows());
+        drop(conn);
+        for table in self.tables.keys() {
+            self.invalidate(table);
+        }
+        Ok(res?)
+    }
+
+    fn query_raw(&self, sql: &str, args: Vec<Value>) -> super::Result<Vec<Vec<Value>>> {
+        Ok(self.conn()?.exec_map(sql, args, |row: Row| row.unwrap())?)
+    }
+
+    fn insert(&self, table: &str, vals: Vec<Value>) -> super::Result<()> {
+        if audit::needs_transaction(self, table) {
+            return audit::transaction(self, |be| be.insert(table, vals));
+        }
+        let valstrs: Vec<&str> = vals.iter().map(|_| "?").collect();
+        let q = format!(r"INSERT INTO {} VALUES ({});", table, valstrs.join(","));
+        self.exec_write("insert", table, q, vals.clone())?;
+        audit::record_insert(self, table, &vals)
+    }
+
+    fn update(&self, table: &str, keys: Vec<Value>, vals: Vec<(usize, Value)>) -> super::Result<()> {
+        if audit::needs_transaction(self, table) {
+            return audit::transaction(self, |be| be.update(table, keys, vals));
+        }
+        let (key_cols, cols) = self.table(table)?;
+        check_keys(table, key_cols, &keys)?;
+        check_assignments(table, cols, &vals)?;
+        let old = audit::rows_before(self, table, &audit::key_conds(self, table, &keys)?)?;
+        let mut assignments = vec![];
+        let mut args = vec![];
+        for (index, value) in &vals {
+            assignments.push(format!("{} = ?", cols[*index],));
+            args.push(value.clone());
+        }
+        let mut conds = vec![];
+        for (i, value) in keys.iter().enumerate() {
+            conds.push(format!("{} = ?", key_cols[i],));
+            args.push(value.clone());
+        }
+        let q = format!(
+            r"UPDATE {} SET {} WHERE {};",
+            table,
+            assignments.join(","),
+            conds.join(" AND ")
+        );
+        self.exec_write("update", table, q, args)?;
+        audit::record_update(self, table, old, &vals)
+    }
+
+    fn insert_or_update(
+        &self,
+        table: &str,
+        rec: Vec<Value>,
+        update_vals: Vec<(u64, Value)>,
+    ) -> super::Result<()> {
+        if audit::needs_transaction(self, table) {
+            return audit::transaction(self, |be| be.insert_or_update(table, rec, update_vals));
+        }
+        let (_, cols) = self.table(table)?;
+        check_indices(table, cols, update_vals.iter().map(|(i, _)| *i as usize))?;
+        let old = audit::rows_before(self, table, &audit::rec_key_conds(self, table, &rec)?)?;
+        let mut args = vec![];
+        let recstrs: Vec<&str> = rec
+            .iter()
+            .map(|v| {
+                args.push(v.clone());
+                "?"
+            })
+            .collect();
+        let mut assignments = vec![];
+        for (index, value) in &update_vals {
+            assignments.push(format!("{} = ?", cols[*index as usize],));
+            args.push(value.clone());
+        }
+
+        let q = format!(
+            r"INSERT INTO {} VALUES ({}) ON DUPLICATE KEY UPDATE {};",
+            table,
+            recstrs.join(","),
+            assignments.join(","),
+        );
+        self.exec_write("insert_or_update", table, q, args)?;
+        audit::record_upsert(self, table, old, &rec, &update_vals)
+    }
+
+    fn delete(&self, table: &str, keys: Vec<Value>) -> super::Result<()> {
+        if audit::needs_transaction(self, table) {
+            return audit::transaction(self, |be| be.delete(table, keys));
+        }
+        let (key_cols, _) = self.table(table)?;
+        check_keys(table, key_cols, &keys)?;
+        let old = audit::rows_before(self, table, &audit::key_conds(self, table, &keys)?)?;
+        let q = delete_sql(table, &key_cols.iter().collect::<Vec<_>>())?;
+        self.exec_write("delete", table, q, keys)?;
+        audit::record_delete(self, table, old)
+    }
+
+    fn delete_where(&self, table: &str, conds: Vec<(usize, Value)>) -> super::Result<()> {
+        if audit::needs_transaction(self, table) {
+            return audit::transaction(self, |be| be.delete_where(table, conds));
+        }
+        let (_, cols) = self.table(table)?;
+        check_indices(table, cols, conds.iter().map(|(i, _)| *i))?;
+        let old = audit::rows_before(self, table, &conds)?;
+        let (cond_cols, args): (Vec<_>, Vec<_>) =
+            conds.into_iter().map(|(i, v)| (&cols[i], v)).unzip();
+        let q = delete_sql(table, &cond_cols)?;
+        self.exec_write("delete", table, q, args)?;
+        audit::record_delete(self, table, old)
+    }
+}
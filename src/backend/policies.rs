@@ -0,0 +1,447 @@
+use beaver::filter::Context;
+use beaver::policy::{MergePolicy, Policy, PolicyError};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use mysql::prelude::FromValue;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use super::{Backend, BackendError, Result, Rows, Value};
+
+/// A policy serialized for storage. Unlike the policy itself, it can be
+/// moved into a blocking task or a transaction.
+pub struct EncodedPolicy {
+    json: String,
+}
+
+impl EncodedPolicy {
+    /// A policy as stored in the `policies` table, e.g. read from an export.
+    pub fn from_stored(json: String) -> Self {
+        EncodedPolicy { json: json }
+    }
+
+    /// Deserializes the policy again.
+    pub fn decode(&self) -> Result<Box<dyn Policy>> {
+        decode_versioned(&self.json).map_err(BackendError::PolicyDeserialization)
+    }
+}
+
+/// Version of the format policies are stored in. When a policy type is
+/// renamed or its fields change, bump this and add an upgrade from the
+/// previous version to `UPGRADES`, so that existing rows still decode.
+pub const POLICY_VERSION: u32 = 1;
+
+/// Rewrites a stored policy (the typetag JSON of a `Box<dyn Policy>`) from
+/// version `from` to version `from + 1`.
+struct PolicyUpgrade {
+    from: u32,
+    upgrade: fn(serde_json::Value) -> std::result::Result<serde_json::Value, String>,
+}
+
+const UPGRADES: &[PolicyUpgrade] = &[PolicyUpgrade {
+    from: 0,
+    upgrade: unversioned,
+}];
+
+/// Policies stored before versioning are bare typetag JSON, which is
+/// already in the version 1 format.
+fn unversioned(policy: serde_json::Value) -> std::result::Result<serde_json::Value, String> {
+    Ok(policy)
+}
+
+#[derive(Serialize, Deserialize)]
+struct VersionedPolicy {
+    version: u32,
+    policy: serde_json::Value,
+}
+
+/// Upgrades a stored policy to `POLICY_VERSION`, returning its typetag JSON.
+fn upgrade(json: &str) -> std::result::Result<serde_json::Value, String> {
+    let value: serde_json::Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let (mut version, mut policy) = match serde_json::from_value::<VersionedPolicy>(value.clone()) {
+        Ok(v) => (v.version, v.policy),
+        Err(_) => (0, value),
+    };
+    if version > POLICY_VERSION {
+        return Err(format!(
+            "policy version {} is newer than this build supports ({})",
+            version, POLICY_VERSION
+        ));
+    }
+    while version < POLICY_VERSION {
+        let upgrade = UPGRADES
+            .iter()
+            .find(|u| u.from == version)
+            .ok_or_else(|| format!("no upgrade from policy version {}", version))?;
+        policy = (upgrade.upgrade)(policy)
+            .map_err(|e| format!("upgrade from policy version {}: {}", version, e))?;
+        version += 1;
+    }
+    Ok(policy)
+}
+
+/// Deserializes a stored policy, upgrading it to `POLICY_VERSION` first.
+fn decode_versioned(json: &str) -> std::result::Result<Box<dyn Policy>, String> {
+    serde_json::from_value(upgrade(json)?).map_err(|e| e.to_string())
+}
+
+/// Rewrites a stored policy in the format `encode_policy` produces, without
+/// decoding it, so that it gets the same id as the policy encoded afresh.
+pub fn upgrade_policy(json: &str) -> Result<String> {
+    upgrade(json)
+        .and_then(|policy| {
+            serde_json::to_string(&VersionedPolicy {
+                version: POLICY_VERSION,
+                policy: policy,
+            })
+            .map_err(|e| e.to_string())
+        })
+        .map_err(|e| BackendError::Database(format!("failed to upgrade policy: {}", e)))
+}
+
+/// Stands in for a stored policy that could not be decoded (e.g., because
+/// its type was renamed without an upgrade). It fails closed: only admins
+/// may see the data.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UndecodablePolicy {
+    pub reason: String,
+}
+
+#[typetag::serde]
+impl Policy for UndecodablePolicy {
+    fn check(&self, ctxt: &Context) -> std::result::Result<(), PolicyError> {
+        match ctxt {
+            Context::CustomContext(any) if any.is::<crate::admin::Admin>() => Ok(()),
+            _ => Err(PolicyError {
+                message: format!("stored policy could not be decoded: {}", self.reason),
+            }),
+        }
+    }
+    fn merge(&self, other: &Box<dyn Policy>) -> std::result::Result<Box<dyn Policy>, PolicyError> {
+        Ok(Box::new(MergePolicy::make(
+            Box::new(self.clone()),
+            other.clone(),
+        )))
+    }
+}
+
+/// How a policied write treats the policy of a row that already exists.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PolicyUpdate {
+    /// Combine the stored policy with the new one using `Policy::merge`
+    Merge,
+    /// Overwrite the stored policy with the new one
+    Replace,
+}
+
+/// Serializes `policy` for storage.
+pub fn encode_policy(policy: &dyn Policy) -> Result<EncodedPolicy> {
+    serde_json::to_value(policy)
+        .and_then(|policy| {
+            serde_json::to_string(&VersionedPolicy {
+                version: POLICY_VERSION,
+                policy: policy,
+            })
+        })
+        .map(|json| EncodedPolicy { json: json })
+        .map_err(|e| BackendError::Database(format!("failed to serialize policy: {}", e)))
+}
+
+/// The id a policy is stored under: a hash of its JSON serialization, so
+/// that rows with equal policies share a single `policies` row.
+pub fn policy_id(json: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(json);
+    hasher.result_str()
+}
+
+thread_local! {
+    // policy id --> deserialized policy. Kept per thread, so that policies
+    // never have to be shared between threads; ids are content hashes, so
+    // entries never go stale.
+    static DECODED: RefCell<HashMap<String, Box<dyn Policy>>> = RefCell::new(HashMap::new());
+}
+
+/// Policies are stored once in the `policies` table, and policied rows hold
+/// the id of theirs in their `policy` column. This caches the serialized
+/// policies a backend has read or written.
+pub struct PolicyStore {
+    log: slog::Logger,
+    // policy id --> JSON
+    json: Mutex<HashMap<String, String>>,
+    // ids known to be in the `policies` table. Policies written or read
+    // inside a transaction are not added, as it may still be rolled back.
+    stored: Mutex<HashSet<String>>,
+}
+
+impl PolicyStore {
+    pub fn new(log: slog::Logger) -> Self {
+        PolicyStore {
+            log: log,
+            json: Mutex::new(HashMap::new()),
+            stored: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn cached(&self, id: &str) -> Option<String> {
+        self.json
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(id)
+            .cloned()
+    }
+
+    fn is_stored(&self, id: &str) -> bool {
+        self.stored
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(id)
+    }
+
+    fn cache(&self, backend: &dyn Backend, id: String, json: String) {
+        if !backend.in_transaction() {
+            self.stored
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(id.clone());
+        }
+        self.json
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, json);
+    }
+
+    /// Stores `policy` in the `policies` table unless it is already there,
+    /// and returns its id for the row's policy column.
+    pub fn store(&self, backend: &dyn Backend, policy: EncodedPolicy) -> Result<Value> {
+        let id = policy_id(&policy.json);
+        if !self.is_stored(&id) {
+            match backend.insert(
+                "policies",
+                vec![id.as_str().into(), policy.json.as_str().into()],
+            ) {
+                // another row already uses this policy
+                Ok(()) | Err(BackendError::ConstraintViolation(_)) => (),
+                Err(e) => return Err(e),
+            }
+            self.cache(backend, id.clone(), policy.json);
+        }
+        Ok(id.into())
+    }
+
+    /// Makes sure the policies referenced by `rows` can be decoded, loading
+    /// any that are not cached yet. Rows with a NULL policy are skipped.
+    pub fn load(&self, backend: &dyn Backend, rows: &Rows) -> Result<()> {
+        let i = match rows.columns.iter().position(|c| c == "policy") {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        for row in &rows.values {
+            if row[i] != Value::NULL {
+                self.load_id(backend, row[i].clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `load`, for a single policy id.
+    pub fn load_id(&self, backend: &dyn Backend, id: Value) -> Result<()> {
+        let id = String::from_value_opt(id)
+            .map_err(|e| BackendError::PolicyDeserialization(e.to_string()))?;
+        if self.cached(&id).is_some() {
+            return Ok(());
+        }
+        let json = match backend
+            .query_rows("policy_by_id", vec![id.as_str().into()])?
+            .into_rows()
+            .next()
+        {
+            Some(row) => row.get::<String>("policy")?,
+            None => {
+                return Err(BackendError::PolicyDeserialization(format!(
+                    "no policy with id {}",
+                    id
+                )))
+            }
+        };
+        self.cache(backend, id, json);
+        Ok(())
+    }
+
+    /// Returns the policy stored under the id in a row's policy column. The
+    /// policy must have been loaded with `load`. A policy that cannot be
+    /// decoded is logged and replaced by an `UndecodablePolicy`.
+    pub fn decode(&self, v: Value) -> Result<Box<dyn Policy>> {
+        let id = String::from_value_opt(v)
+            .map_err(|e| BackendError::PolicyDeserialization(e.to_string()))?;
+        if let Some(p) = DECODED.with(|d| d.borrow().get(&id).cloned()) {
+            return Ok(p);
+        }
+        let json = self.cached(&id).ok_or_else(|| {
+            BackendError::PolicyDeserialization(format!("policy {} was not loaded", id))
+        })?;
+        let p: Box<dyn Policy> = match decode_versioned(&json) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!(
+                    self.log,
+                    "cannot decode policy {}, only admins may read its rows: {}", id, e
+                );
+                Box::new(UndecodablePolicy { reason: e })
+            }
+        };
+        DECODED.with(|d| d.borrow_mut().insert(id, p.clone()));
+        Ok(p)
+    }
+
+    /// Like `decode`, but fails if the policy cannot be decoded, for callers
+    /// that write the policy back: storing an `UndecodablePolicy` in its
+    /// place would lose the original for good.
+    pub fn decode_strict(&self, v: Value) -> Result<Box<dyn Policy>> {
+        let id = String::from_value_opt(v)
+            .map_err(|e| BackendError::PolicyDeserialization(e.to_string()))?;
+        let json = self.cached(&id).ok_or_else(|| {
+            BackendError::PolicyDeserialization(format!("policy {} was not loaded", id))
+        })?;
+        decode_versioned(&json).map_err(|e| {
+            BackendError::PolicyDeserialization(format!("cannot decode policy {}: {}", id, e))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::Admin;
+    use crate::backend::SqliteBackend;
+
+    #[derive(Clone, Serialize, Deserialize)]
+    struct AllowAll {
+        note: String,
+    }
+
+    #[typetag::serde]
+    impl Policy for AllowAll {
+        fn check(&self, _: &Context) -> std::result::Result<(), PolicyError> {
+            Ok(())
+        }
+        fn merge(
+            &self,
+            other: &Box<dyn Policy>,
+        ) -> std::result::Result<Box<dyn Policy>, PolicyError> {
+            Ok(Box::new(MergePolicy::make(Box::new(self.clone()), other.clone())))
+        }
+    }
+
+    fn student() -> Context {
+        kv_ctx!("user" => "a@example.com", "method" => "website")
+    }
+
+    fn admin() -> Context {
+        Context::CustomContext(Box::new(Admin {
+            user: "admin@example.com".to_string(),
+        }))
+    }
+
+    #[test]
+    fn decodes_unversioned_policy() {
+        let p = decode_versioned(r#"{"AllowAll":{"note":"v0"}}"#).unwrap();
+        assert!(p.check(&student()).is_ok());
+    }
+
+    #[test]
+    fn decodes_versioned_policy() {
+        let p = decode_versioned(r#"{"version":1,"policy":{"AllowAll":{"note":"v1"}}}"#).unwrap();
+        assert!(p.check(&student()).is_ok());
+        let encoded = encode_policy(&AllowAll { note: "v1".to_string() }).unwrap();
+        assert!(encoded.decode().unwrap().check(&student()).is_ok());
+    }
+
+    #[test]
+    fn refuses_newer_version() {
+        let json = format!(
+            r#"{{"version":{},"policy":{{"AllowAll":{{"note":"v2"}}}}}}"#,
+            POLICY_VERSION + 1
+        );
+        assert!(decode_versioned(&json).is_err());
+    }
+
+    #[test]
+    fn upgraded_policy_matches_encoded_policy() {
+        let encoded = encode_policy(&AllowAll { note: "v0".to_string() }).unwrap();
+        let upgraded = upgrade_policy(r#"{"AllowAll":{"note":"v0"}}"#).unwrap();
+        assert_eq!(upgraded, encoded.json);
+        assert_eq!(upgrade_policy(&encoded.json).unwrap(), encoded.json);
+    }
+
+    #[test]
+    fn undecodable_policy_is_for_admins_only() {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        let json = r#"{"version":1,"policy":{"RenamedPolicy":{}}}"#;
+        be.exec_raw(
+            "INSERT INTO policies VALUES (?, ?);",
+            vec![policy_id(json).into(), json.into()],
+        )
+        .unwrap();
+
+        let store = be.policies();
+        let id: Value = policy_id(json).into();
+        store.load_id(&be, id.clone()).unwrap();
+        let p = store.decode(id.clone()).unwrap();
+        assert!(p.check(&student()).is_err());
+        assert!(p.check(&admin()).is_ok());
+        assert!(store.decode_strict(id).is_err());
+    }
+
+    fn note(note: &str) -> EncodedPolicy {
+        encode_policy(&AllowAll { note: note.to_string() }).unwrap()
+    }
+
+    fn stored_policies(be: &dyn Backend) -> usize {
+        be.query_raw("SELECT * FROM policies;", vec![]).unwrap().len()
+    }
+
+    #[test]
+    fn identical_policies_are_stored_once() {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        let store = be.policies();
+        let id = store.store(&be, note("a")).unwrap();
+        assert_eq!(store.store(&be, note("a")).unwrap(), id);
+        // another backend sharing the database finds the row already there
+        let other = PolicyStore::new(slog::Logger::root(slog::Discard, o!()));
+        assert_eq!(other.store(&be, note("a")).unwrap(), id);
+        assert_eq!(stored_policies(&be), 1);
+        assert_ne!(store.store(&be, note("b")).unwrap(), id);
+        assert_eq!(stored_policies(&be), 2);
+    }
+
+    #[test]
+    fn policies_of_rolled_back_transactions_are_stored_again() {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        let db: &dyn Backend = &be;
+        let res: Result<()> = db.with_transaction(|be| {
+            be.policies().store(be, note("a"))?;
+            Err(BackendError::Database("give up".to_string()))
+        });
+        assert!(res.is_err());
+        assert_eq!(stored_policies(&be), 0);
+        be.policies().store(&be, note("a")).unwrap();
+        assert_eq!(stored_policies(&be), 1);
+    }
+
+    #[test]
+    fn stored_policies_are_loaded_by_id() {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        let id = be.policies().store(&be, note("a")).unwrap();
+        let other = PolicyStore::new(slog::Logger::root(slog::Discard, o!()));
+        assert!(other.decode_strict(id.clone()).is_err());
+        other.load_id(&be, id.clone()).unwrap();
+        assert!(other.decode_strict(id).unwrap().check(&student()).is_ok());
+        assert!(other.load_id(&be, "missing".into()).is_err());
+    }
+}
@@ -0,0 +1,307 @@
+use mysql::prelude::FromValue;
+use std::sync::Arc;
+
+use super::{Backend, BackendError, Result, Value};
+
+/// Rows returned by a named query, together with the names of their columns.
+#[derive(Clone)]
+pub struct Rows {
+    pub columns: Arc<[String]>,
+    pub values: Vec<Vec<Value>>,
+}
+
+impl Rows {
+    pub fn new(columns: Vec<String>, values: Vec<Vec<Value>>) -> Self {
+        Rows {
+            columns: columns.into(),
+            values: values,
+        }
+    }
+
+    pub fn into_rows(self) -> impl Iterator<Item = Row> {
+        let columns = self.columns;
+        self.values.into_iter().map(move |values| Row {
+            columns: columns.clone(),
+            values: values,
+        })
+    }
+}
+
+/// A window of a named query's results, read with `LIMIT` and `OFFSET`.
+/// The query should have an `ORDER BY`, so that pages do not overlap.
+#[derive(Clone, Copy, Debug)]
+pub struct Page {
+    pub limit: u64,
+    pub offset: u64,
+}
+
+impl Page {
+    /// Page `number` (counting from 1) of `size` rows each. One extra row
+    /// is fetched to tell whether there is a next page; see `truncate`.
+    pub fn number(number: u64, size: u64) -> Page {
+        Page {
+            limit: size + 1,
+            offset: (number.max(1) - 1) * size,
+        }
+    }
+
+    /// Drops the extra row fetched for a page from `number`, and returns
+    /// whether there was one (i.e., whether there is a next page).
+    pub fn truncate<T>(&self, rows: &mut Vec<T>) -> bool {
+        let size = (self.limit - 1) as usize;
+        if rows.len() > size {
+            rows.truncate(size);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Appends `LIMIT` and `OFFSET` placeholders for `page` to a named query,
+/// and their values to its arguments.
+pub(super) fn paged_sql(
+    qname: &str,
+    sql: &str,
+    keys: &mut Vec<Value>,
+    page: Page,
+) -> Result<String> {
+    let sql = sql.trim_end().trim_end_matches(';');
+    if sql
+        .split_whitespace()
+        .any(|w| w.eq_ignore_ascii_case("LIMIT"))
+    {
+        return Err(BackendError::Database(format!(
+            "query \"{}\" already has a LIMIT and cannot be paged",
+            qname
+        )));
+    }
+    keys.push(page.limit.into());
+    keys.push(page.offset.into());
+    Ok(format!("{} LIMIT ? OFFSET ?;", sql))
+}
+
+/// A single result row whose values can be looked up by column name.
+pub struct Row {
+    columns: Arc<[String]>,
+    values: Vec<Value>,
+}
+
+impl Row {
+    pub(super) fn new(columns: Arc<[String]>, values: Vec<Value>) -> Row {
+        Row {
+            columns: columns,
+            values: values,
+        }
+    }
+
+    pub(super) fn into_values(self) -> Vec<Value> {
+        self.values
+    }
+
+    fn index(&self, column: &str) -> Result<usize> {
+        self.columns
+            .iter()
+            .position(|c| c == column)
+            .ok_or_else(|| BackendError::RowMapping(format!("no column \"{}\" in row", column)))
+    }
+
+    /// Returns the value of `column`. Nullable columns should be read as an
+    /// `Option`.
+    pub fn get<T: FromValue>(&self, column: &str) -> Result<T> {
+        let v = self.values[self.index(column)?].clone();
+        T::from_value_opt(v.clone()).map_err(|_| {
+            BackendError::RowMapping(format!(
+                "cannot convert column \"{}\" ({:?}) to {}",
+                column,
+                v,
+                std::any::type_name::<T>()
+            ))
+        })
+    }
+
+    /// Removes the `policy` column from the row and returns its policy id.
+    pub fn take_policy(&mut self) -> Result<Value> {
+        let i = self.index("policy")?;
+        let mut columns = self.columns.to_vec();
+        columns.remove(i);
+        self.columns = columns.into();
+        Ok(self.values.remove(i))
+    }
+}
+
+/// Types that can be built from a query result row by column name.
+/// Implementations are generated with the `from_row!` macro.
+pub trait FromRow: Sized {
+    /// The columns read by `from_row`.
+    const COLUMNS: &'static [&'static str];
+
+    fn from_row(row: &Row) -> Result<Self>;
+}
+
+/// Implements `FromRow` for a struct, mapping each field to a named column:
+///
+/// ```ignore
+/// from_row!(LectureAnswer {
+///     id: "q",
+///     user: "email",
+/// });
+/// ```
+#[macro_export]
+macro_rules! from_row {
+    ($ty:ident { $($field:ident : $col:expr),* $(,)? }) => {
+        impl $crate::backend::FromRow for $ty {
+            const COLUMNS: &'static [&'static str] = &[$($col),*];
+
+            fn from_row(row: &$crate::backend::Row) -> $crate::backend::Result<Self> {
+                Ok($ty {
+                    $($field: row.get($col)?),*
+                })
+            }
+        }
+    };
+}
+
+/// Checks that the named query returns every column `T` reads.
+pub fn check_columns<T: FromRow>(backend: &dyn Backend, qname: &str) -> Result<()> {
+    let columns = backend.query_columns(qname)?;
+    let missing: Vec<_> = T::COLUMNS
+        .iter()
+        .filter(|c| !columns.iter().any(|qc| qc == *c))
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(BackendError::RowMapping(format!(
+            "query \"{}\" does not return column(s) {:?} needed by {}",
+            qname,
+            missing,
+            std::any::type_name::<T>()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SqliteBackend;
+
+    struct Answer {
+        user: String,
+        lec: u64,
+        answer: Option<String>,
+    }
+
+    crate::from_row!(Answer {
+        user: "email",
+        lec: "lec",
+        answer: "answer",
+    });
+
+    fn row(values: Vec<Value>) -> Row {
+        let columns: Vec<String> = vec!["lec".into(), "email".into(), "answer".into()];
+        Row::new(columns.into(), values)
+    }
+
+    #[test]
+    fn maps_columns_by_name() {
+        let a = Answer::from_row(&row(vec![3.into(), "a@example.com".into(), "yes".into()]))
+            .unwrap();
+        assert_eq!(a.user, "a@example.com");
+        assert_eq!(a.lec, 3);
+        assert_eq!(a.answer.as_deref(), Some("yes"));
+        let a = Answer::from_row(&row(vec![3.into(), "a@example.com".into(), Value::NULL]))
+            .unwrap();
+        assert_eq!(a.answer, None);
+    }
+
+    #[test]
+    fn mapping_fails_on_wrong_or_missing_columns() {
+        let r = row(vec!["three".into(), "a@example.com".into(), Value::NULL]);
+        assert!(matches!(Answer::from_row(&r), Err(BackendError::RowMapping(_))));
+        let r = row(vec![3.into(), Value::NULL, Value::NULL]);
+        assert!(matches!(Answer::from_row(&r), Err(BackendError::RowMapping(_))));
+        let r = row(vec![3.into(), "a@example.com".into(), Value::NULL]);
+        assert!(matches!(r.get::<u64>("q"), Err(BackendError::RowMapping(_))));
+    }
+
+    #[test]
+    fn take_policy_removes_the_column() {
+        let columns: Vec<String> = vec!["email".into(), "policy".into(), "lec".into()];
+        let mut r = Row::new(columns.into(), vec!["a@example.com".into(), "p".into(), 1.into()]);
+        assert_eq!(r.take_policy().unwrap(), Value::from("p"));
+        assert_eq!(r.get::<u64>("lec").unwrap(), 1);
+        assert!(r.get::<String>("policy").is_err());
+        assert!(r.take_policy().is_err());
+    }
+
+    #[test]
+    fn checks_columns_of_named_queries() {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        assert!(check_columns::<Answer>(&be, "answers_by_lec").is_ok());
+        assert!(matches!(
+            check_columns::<Answer>(&be, "leclist"),
+            Err(BackendError::RowMapping(_))
+        ));
+        assert!(check_columns::<Answer>(&be, "no_such_query").is_err());
+    }
+
+    #[test]
+    fn pages_fetch_one_extra_row() {
+        let page = Page::number(3, 10);
+        assert_eq!((page.limit, page.offset), (11, 20));
+        let first = Page::number(0, 10);
+        assert_eq!((first.limit, first.offset), (11, 0));
+
+        let mut rows: Vec<u32> = (0..11).collect();
+        assert!(page.truncate(&mut rows));
+        assert_eq!(rows.len(), 10);
+        assert!(!page.truncate(&mut rows));
+        assert_eq!(rows.len(), 10);
+    }
+
+    #[test]
+    fn paged_sql_appends_limit_and_offset() {
+        let mut keys = vec![1.into()];
+        let sql = "SELECT * FROM answers WHERE lec = ? ORDER BY q; ";
+        assert_eq!(
+            paged_sql("q", sql, &mut keys, Page::number(2, 5)).unwrap(),
+            "SELECT * FROM answers WHERE lec = ? ORDER BY q LIMIT ? OFFSET ?;"
+        );
+        assert_eq!(keys, vec![1.into(), 6u64.into(), 5u64.into()]);
+        let sql = paged_sql("q", "SELECT * FROM answers limit 5;", &mut vec![], Page::number(1, 5));
+        assert!(sql.is_err());
+    }
+
+    #[test]
+    fn pages_do_not_overlap() {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        for q in 1..=5 {
+            let email = format!("{}@example.com", q);
+            let answer = vec![
+                email.into(),
+                1.into(),
+                q.into(),
+                "yes".into(),
+                Value::NULL,
+                "p".into(),
+            ];
+            be.insert("answers", answer).unwrap();
+        }
+        let db: &dyn Backend = &be;
+        let page = |n| {
+            let mut rows = db
+                .query_page_as::<Answer>("answers_by_lec", vec![1.into()], Page::number(n, 2))
+                .unwrap();
+            let more = Page::number(n, 2).truncate(&mut rows);
+            let users: Vec<_> = rows.into_iter().map(|a| a.user).collect();
+            (users.join(" "), more)
+        };
+        assert_eq!(page(1), ("1@example.com 2@example.com".to_string(), true));
+        assert_eq!(page(2), ("3@example.com 4@example.com".to_string(), true));
+        assert_eq!(page(3), ("5@example.com".to_string(), false));
+        assert_eq!(page(4), ("".to_string(), false));
+    }
+}
@@ -0,0 +1,401 @@
+use sqlparser::ast::*;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// The schema compiled into the binary, used unless the configuration
+/// names a `schema_path`.
+pub const DEFAULT_SCHEMA: &str = include_str!("../schema.sql");
+
+/// A single statement from `schema.sql`.
+pub enum SchemaStmt {
+    /// `CREATE TABLE`, with its name and the original statement text
+    Table(String, String),
+    /// `CREATE VIEW`
+    View(String),
+    /// `QUERY name: SELECT ...`, with its name and the SELECT text
+    Query(String, String),
+}
+
+/// Parsed contents of `schema.sql`, shared by all backends.
+pub struct Schema {
+    pub text: String,
+    pub stmts: Vec<SchemaStmt>,
+    // table name --> (keys, columns)
+    pub tables: HashMap<String, (Vec<String>, Vec<String>)>,
+}
+
+/// An error in an SQL file, with the line it was found on.
+#[derive(Debug)]
+pub struct SchemaError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+fn error<T>(line: usize, message: String) -> Result<T, SchemaError> {
+    Err(SchemaError {
+        line: line,
+        message: message,
+    })
+}
+
+/// A statement in an SQL file, without comments, and the line it starts on.
+pub struct SqlStatement {
+    pub line: usize,
+    pub text: String,
+}
+
+/// Splits an SQL file into `;`-terminated statements. Skips `--` and
+/// `/* */` comments, and ignores semicolons inside quoted strings and
+/// identifiers; statements may span several lines.
+pub fn split_statements(sql: &str) -> Result<Vec<SqlStatement>, SchemaError> {
+    let mut stmts = vec![];
+    let mut stmt = String::new();
+    let mut stmt_line = 1;
+    let mut line = 1;
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        if stmt.trim().is_empty() {
+            stmt_line = line;
+        }
+        match c {
+            '\n' => {
+                line += 1;
+                stmt.push(c);
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                while chars.peek().map_or(false, |&n| n != '\n') {
+                    chars.next();
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                let start = line;
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('*') if chars.peek() == Some(&'/') => {
+                            chars.next();
+                            break;
+                        }
+                        Some('\n') => line += 1,
+                        Some(_) => (),
+                        None => return error(start, "unterminated /* comment".to_string()),
+                    }
+                }
+                stmt.push(' ');
+            }
+            '\'' | '"' | '`' => {
+                let start = line;
+                stmt.push(c);
+                loop {
+                    match chars.next() {
+                        // backslash escapes (not in `identifiers`)
+                        Some('\\') if c != '`' => {
+                            stmt.push('\\');
+                            if let Some(n) = chars.next() {
+                                if n == '\n' {
+                                    line += 1;
+                                }
+                                stmt.push(n);
+                            }
+                        }
+                        // a doubled quote is an escaped quote
+                        Some(n) if n == c && chars.peek() == Some(&c) => {
+                            stmt.push(n);
+                            stmt.push(chars.next().unwrap());
+                        }
+                        Some(n) if n == c => {
+                            stmt.push(n);
+                            break;
+                        }
+                        Some(n) => {
+                            if n == '\n' {
+                                line += 1;
+                            }
+                            stmt.push(n);
+                        }
+                        None => return error(start, format!("unterminated {} quote", c)),
+                    }
+                }
+            }
+            ';' => {
+                stmt.push(c);
+                stmts.push(SqlStatement {
+                    line: stmt_line,
+                    text: stmt.trim().to_string(),
+                });
+                stmt.clear();
+            }
+            _ => stmt.push(c),
+        }
+    }
+    if !stmt.trim().is_empty() {
+        return error(stmt_line, "statement does not end with `;`".to_string());
+    }
+    Ok(stmts)
+}
+
+/// Returns the first `n` words of `text`, upper-cased.
+fn leading_keywords(text: &str, n: usize) -> Vec<String> {
+    text.split_whitespace()
+        .take(n)
+        .map(|w| w.to_ascii_uppercase())
+        .collect()
+}
+
+/// Splits the rest of a `QUERY name: SELECT ...;` statement into name and
+/// query. The name ends at the first colon followed by whitespace, so names
+/// may themselves contain colons.
+fn split_query(line: usize, rest: &str) -> Result<(String, String), SchemaError> {
+    let sep = rest
+        .char_indices()
+        .find(|&(i, c)| c == ':' && rest[i + 1..].starts_with(char::is_whitespace));
+    let (name, query) = match sep {
+        Some((i, _)) => (rest[..i].trim(), rest[i + 1..].trim()),
+        None => return error(line, "expected `QUERY <name>: <SELECT ...>;`".to_string()),
+    };
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return error(line, format!("invalid query name \"{}\"", name));
+    }
+    if query == ";" {
+        return error(line, format!("query \"{}\" is empty", name));
+    }
+    Ok((name.to_string(), query.to_string()))
+}
+
+impl Schema {
+    /// Loads the schema from the file at `path`, or the built-in schema if
+    /// no path is given.
+    pub fn load(path: Option<&str>) -> Result<Schema, String> {
+        match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+                Schema::parse(&text).map_err(|e| format!("{}: {}", path, e))
+            }
+            None => Schema::parse(DEFAULT_SCHEMA).map_err(|e| format!("built-in schema: {}", e)),
+        }
+    }
+
+    /// The tables a named query reads, including the tables behind the
+    /// views it uses, or `None` if there is no such query. This errs on the
+    /// side of caution: every table or view whose name appears as a word in
+    /// the query counts.
+    pub fn query_tables(&self, qname: &str) -> Option<Vec<String>> {
+        let sql = self.stmts.iter().find_map(|stmt| match stmt {
+            SchemaStmt::Query(name, sql) if name == qname => Some(sql.as_str()),
+            _ => None,
+        })?;
+        // view name --> CREATE VIEW statement
+        let views: HashMap<String, &str> = self
+            .stmts
+            .iter()
+            .filter_map(|stmt| match stmt {
+                SchemaStmt::View(sql) => sql
+                    .split_whitespace()
+                    .nth(2)
+                    .map(|name| (name.to_ascii_lowercase(), sql.as_str())),
+                _ => None,
+            })
+            .collect();
+
+        let mut tables = vec![];
+        let mut seen = HashSet::new();
+        let mut pending = vec![sql];
+        while let Some(sql) = pending.pop() {
+            let words = sql
+                .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .filter(|w| !w.is_empty());
+            for word in words {
+                let word = word.to_ascii_lowercase();
+                if !seen.insert(word.clone()) {
+                    continue;
+                }
+                if let Some(view) = views.get(&word) {
+                    pending.push(view);
+                }
+                tables.extend(
+                    self.tables
+                        .keys()
+                        .filter(|t| t.to_ascii_lowercase() == word)
+                        .cloned(),
+                );
+            }
+        }
+        Some(tables)
+    }
+
+    pub fn parse(schema: &str) -> Result<Schema, SchemaError> {
+        let mut tables = HashMap::new();
+        let mut stmts = vec![];
+        // query name --> line it is defined on
+        let mut query_lines = HashMap::new();
+        for SqlStatement { line, text } in split_statements(schema)? {
+            let keywords = leading_keywords(&text, 2);
+            if keywords[0] == "QUERY" {
+                let (name, query) = split_query(line, text[5..].trim_start())?;
+                if let Some(first) = query_lines.insert(name.clone(), line) {
+                    return error(
+                        line,
+                        format!("query \"{}\" is already defined on line {}", name, first),
+                    );
+                }
+                stmts.push(SchemaStmt::Query(name, query));
+            } else if keywords == ["CREATE", "VIEW"] {
+                stmts.push(SchemaStmt::View(text));
+            } else {
+                let dialect = sqlparser::dialect::MySqlDialect {};
+                let mut asts = match sqlparser::parser::Parser::parse_sql(&dialect, text.clone()) {
+                    Ok(asts) => asts,
+                    Err(e) => return error(line, format!("could not parse statement: {:?}", e)),
+                };
+                if asts.len() != 1 {
+                    return error(
+                        line,
+                        format!("expected one statement, found {}", asts.len()),
+                    );
+                }
+
+                if let sqlparser::ast::Statement::CreateTable {
+                    name,
+                    columns,
+                    constraints,
+                    ..
+                } = asts.remove(0)
+                {
+                    let mut tab_keys = vec![];
+                    let tab_cols = columns.iter().map(|c| c.name.to_string()).collect();
+                    for constraint in constraints {
+                        match constraint {
+                            TableConstraint::Unique {
+                                columns,
+                                is_primary,
+                                ..
+                            } => {
+                                if is_primary {
+                                    columns.iter().for_each(|c| tab_keys.push(c.to_string()));
+                                }
+                            }
+                            _ => (),
+                        }
+                    }
+                    tables.insert(name.to_string(), (tab_keys, tab_cols));
+                    stmts.push(SchemaStmt::Table(name.to_string(), text));
+                } else {
+                    return error(
+                        line,
+                        "expected CREATE TABLE, CREATE VIEW or QUERY".to_string(),
+                    );
+                }
+            }
+        }
+        Ok(Schema {
+            text: schema.to_owned(),
+            stmts: stmts,
+            tables: tables,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(sql: &str) -> Vec<(usize, String)> {
+        split_statements(sql)
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.line, s.text))
+            .collect()
+    }
+
+    fn error_line(sql: &str) -> usize {
+        match Schema::parse(sql) {
+            Err(e) => e.line,
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn skips_line_comments() {
+        let sql = "-- a comment; not a statement\nSELECT 1; -- trailing;\n\n-- last\nSELECT 2;\n";
+        assert_eq!(
+            texts(sql),
+            vec![(2, "SELECT 1;".to_string()), (5, "SELECT 2;".to_string())]
+        );
+    }
+
+    #[test]
+    fn skips_block_comments() {
+        let sql = "/* header;\n   more */\nSELECT /* inline; */ 1;\nSELECT 2 /* a\n; */;\n";
+        let stmts = texts(sql);
+        assert_eq!(stmts.len(), 2);
+        assert_eq!(stmts[0].0, 3);
+        assert!(stmts[0].1.starts_with("SELECT") && stmts[0].1.ends_with("1;"));
+        assert!(!stmts[0].1.contains("inline"));
+        assert_eq!(stmts[1].0, 4);
+    }
+
+    #[test]
+    fn ignores_semicolons_in_quotes() {
+        let sql = "INSERT INTO t VALUES ('a;b', \"c;d\", `e;f`, 'it''s;', 'x\\';');\nSELECT 1;";
+        let stmts = texts(sql);
+        assert_eq!(stmts.len(), 2);
+        assert_eq!(
+            stmts[0].1,
+            "INSERT INTO t VALUES ('a;b', \"c;d\", `e;f`, 'it''s;', 'x\\';');"
+        );
+        assert_eq!(stmts[1], (2, "SELECT 1;".to_string()));
+    }
+
+    #[test]
+    fn reports_lines_of_split_errors() {
+        let quote = split_statements("SELECT 1;\n\nSELECT 'a;\nb;\n").err().unwrap();
+        assert_eq!(quote.line, 3);
+        let comment = split_statements("SELECT 1;\n/* open\n").err().unwrap();
+        assert_eq!(comment.line, 2);
+        let end = split_statements("SELECT 1;\n\nSELECT\n2\n").err().unwrap();
+        assert_eq!(end.line, 3);
+    }
+
+    #[test]
+    fn parses_multi_line_queries() {
+        let sql = "CREATE TABLE t (a int, b int, PRIMARY KEY (a));\n\
+                   QUERY by_a:\n  SELECT *\n  FROM t\n  WHERE a = ?;\n\
+                   QUERY with:colon: SELECT b FROM t;\n";
+        let schema = Schema::parse(sql).unwrap();
+        let queries: Vec<_> = schema
+            .stmts
+            .iter()
+            .filter_map(|s| match s {
+                SchemaStmt::Query(name, query) => Some((name.as_str(), query.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            queries,
+            vec![
+                ("by_a", "SELECT *\n  FROM t\n  WHERE a = ?;"),
+                ("with:colon", "SELECT b FROM t;"),
+            ]
+        );
+        let (keys, cols) = &schema.tables["t"];
+        assert_eq!(keys, &vec!["a".to_string()]);
+        assert_eq!(cols, &vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn reports_lines_of_parse_errors() {
+        assert_eq!(error_line("CREATE TABLE t (a int);\n\nQUERY q SELECT 1;\n"), 3);
+        assert_eq!(error_line("\nQUERY q: ;\n"), 2);
+        assert_eq!(error_line("QUERY q: SELECT 1;\n\nQUERY q:\nSELECT 2;\n"), 3);
+        assert_eq!(error_line("CREATE TABLE t (a int);\nDROP TABLE t;\n"), 2);
+        assert_eq!(error_line("\n\nCREATE TABLE t (a int;\n"), 3);
+    }
+}
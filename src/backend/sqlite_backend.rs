@@ -0,0 +1,678 @@
+use chrono::naive::NaiveDateTime;
+use chrono::{Datelike, Timelike};
+use mysql::Value;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+
+use super::audit;
+use super::rows::paged_sql;
+use super::{
+    check_assignments, check_indices, check_keys, delete_sql, Backend, BackendError, Page,
+    PolicyStore, QueryCache, QueryStats, Row, Rows, Schema, SchemaStmt,
+};
+
+const DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+/// Embedded storage backend, for development and testing without a MySQL
+/// server. Uses the same tables and named queries as the MySQL backend.
+///
+/// SQLite serializes writers anyway, so requests share a single connection.
+/// While a transaction is open, only the thread running it may use the
+/// connection; other threads wait until it commits or rolls back.
+pub struct SqliteBackend {
+    handle: Mutex<Connection>,
+    tx_owner: Mutex<Option<ThreadId>>,
+    tx_done: Condvar,
+    // the tables the open transaction wrote to
+    tx_written: Mutex<Vec<String>>,
+    pub log: slog::Logger,
+    _schema: String,
+
+    // table name --> (keys, columns)
+    tables: HashMap<String, (Vec<String>, Vec<String>)>,
+    queries: HashMap<String, String>,
+    policies: PolicyStore,
+    stats: QueryStats,
+    cache: QueryCache,
+}
+
+impl SqliteBackend {
+    /// Opens the database file at `path`, or a fresh in-memory database if
+    /// no path is given.
+    pub fn new(
+        path: Option<&str>,
+        schema: Schema,
+        stats: QueryStats,
+        cache: QueryCache,
+        log: Option<slog::Logger>,
+    ) -> rusqlite::Result<Self> {
+        let log = match log {
+            None => slog::Logger::root(slog::Discard, o!()),
+            Some(l) => l,
+        };
+
+        debug!(log, "Opening SQLite DB {}...", path.unwrap_or(":memory:"));
+        let db = match path {
+            Some(p) => Connection::open(p)?,
+            None => Connection::open_in_memory()?,
+        };
+
+        // tables are created by the migrations, so queries are only
+        // prepared on first use
+        let mut queries = HashMap::new();
+        for stmt in &schema.stmts {
+            if let SchemaStmt::Query(name, sql) = stmt {
+                queries.insert(name.to_string(), sql.to_string());
+            }
+        }
+        let policies = PolicyStore::new(log.clone());
+        Ok(SqliteBackend {
+            handle: Mutex::new(db),
+            tx_owner: Mutex::new(None),
+            tx_done: Condvar::new(),
+            tx_written: Mutex::new(vec![]),
+            log: log,
+            _schema: schema.text,
+
+            tables: schema.tables,
+            queries: queries,
+            policies: policies,
+            stats: stats,
+            cache: cache,
+        })
+    }
+
+    /// Locks the connection, first waiting for any transaction opened by
+    /// another thread to end. A panic in another request cannot leave the
+    /// connection in a bad state, so a poisoned lock is simply recovered.
+    fn conn(&self) -> MutexGuard<Connection> {
+        let me = thread::current().id();
+        let mut owner = self.tx_owner.lock().unwrap_or_else(|e| e.into_inner());
+        while owner.map_or(false, |t| t != me) {
+            owner = self.tx_done.wait(owner).unwrap_or_else(|e| e.into_inner());
+        }
+        self.handle.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn run_transaction(
+        &self,
+        f: &mut dyn FnMut(&dyn Backend) -> super::Result<()>,
+    ) -> super::Result<()> {
+        self.conn().execute_batch("BEGIN")?;
+        let res = match f(self) {
+            Ok(()) => self.conn().execute_batch("COMMIT").map_err(BackendError::from),
+            Err(e) => {
+                if let Err(re) = self.conn().execute_batch("ROLLBACK") {
+                    error!(self.log, "failed to roll back transaction: {}", re);
+                }
+                Err(e)
+            }
+        };
+        let mut written = self.tx_written.lock().unwrap_or_else(|e| e.into_inner());
+        for table in written.drain(..) {
+            self.cache.invalidate(&table);
+        }
+        res
+    }
+
+    /// Drops cached results that read `table`, now and, in a transaction,
+    /// again when it ends.
+    fn invalidate(&self, table: &str) {
+        self.cache.invalidate(table);
+        if self.in_transaction() {
+            self.tx_written
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(table.to_string());
+        }
+    }
+
+    fn query(&self, qname: &str) -> super::Result<&String> {
+        self.queries
+            .get(qname)
+            .ok_or_else(|| BackendError::UnknownQuery(qname.to_string()))
+    }
+
+    fn exec_drop(&self, q: &str, args: Vec<Value>) -> rusqlite::Result<usize> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(q)?;
+        stmt.execute(args.into_iter().map(to_sql))
+    }
+
+    /// Runs a write to `table`, timed as `<kind>:<table>`.
+    fn exec_write(&self, kind: &str, table: &str, q: &str, args: Vec<Value>) -> super::Result<()> {
+        let name = format!("{}:{}", kind, table);
+        let res = self
+            .stats
+            .time(&self.log, &name, || Ok(self.exec_drop(q, args)?), |n| *n);
+        // only after the write, so that no result read before it is cached
+        self.invalidate(table);
+        res.map(|_| ())
+    }
+
+    fn query_sql(&self, q: &str, keys: Vec<Value>) -> super::Result<Rows> {
+        let mut res = vec![];
+        let columns = self.each_sql(q, keys, &mut |row| {
+            res.push(row.into_values());
+            Ok(())
+        })?;
+        Ok(Rows {
+            columns: columns,
+            values: res,
+        })
+    }
+
+    /// Runs `q` and passes each row to `f` as it is read, returning the
+    /// column names.
+    fn each_sql(
+        &self,
+        q: &str,
+        keys: Vec<Value>,
+        f: &mut dyn FnMut(Row) -> super::Result<()>,
+    ) -> super::Result<Arc<[String]>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare_cached(q)?;
+        let columns: Arc<[String]> = stmt
+            .column_names()
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+            .into();
+        let decl_types: Vec<Option<String>> = stmt
+            .columns()
+            .iter()
+            .map(|c| c.decl_type().map(|t| t.to_string()))
+            .collect();
+        let mut rows = stmt.query(keys.into_iter().map(to_sql))?;
+        while let Some(row) = rows.next()? {
+            let mut vals = vec![];
+            for (i, t) in decl_types.iter().enumerate() {
+                vals.push(from_sql(row.get(i)?, t.as_deref()));
+            }
+            f(Row::new(columns.clone(), vals))?;
+        }
+        Ok(columns)
+    }
+}
+
+/// Converts a MySQL value into the closest SQLite storage class.
+fn to_sql(v: Value) -> SqlValue {
+    match v {
+        Value::NULL => SqlValue::Null,
+        Value::Bytes(b) => match String::from_utf8(b) {
+            Ok(s) => SqlValue::Text(s),
+            Err(e) => SqlValue::Blob(e.into_bytes()),
+        },
+        Value::Int(i) => SqlValue::Integer(i),
+        Value::UInt(u) => SqlValue::Integer(u as i64),
+        Value::Float(f) => SqlValue::Real(f as f64),
+        Value::Double(d) => SqlValue::Real(d),
+        Value::Date(y, m, d, h, mi, s, us) => SqlValue::Text(format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}",
+            y, m, d, h, mi, s, us
+        )),
+        Value::Time(neg, d, h, mi, s, us) => SqlValue::Text(format!(
+            "{}{:02}:{:02}:{:02}.{:06}",
+            if neg { "-" } else { "" },
+            d * 24 + h as u32,
+            mi,
+            s,
+            us
+        )),
+    }
+}
+
+/// Converts an SQLite value back into a MySQL value. `decl_type` is the
+/// declared column type, used to recover `datetime` columns stored as text.
+fn from_sql(v: SqlValue, decl_type: Option<&str>) -> Value {
+    match v {
+        SqlValue::Null => Value::NULL,
+        SqlValue::Integer(i) => Value::Int(i),
+        SqlValue::Real(f) => Value::Double(f),
+        SqlValue::Text(s) => {
+            let is_datetime = decl_type
+                .map(|t| t.eq_ignore_ascii_case("datetime"))
+                .unwrap_or(false);
+            match NaiveDateTime::parse_from_str(&s, DATETIME_FORMAT) {
+                Ok(t) if is_datetime => Value::Date(
+                    t.year() as u16,
+                    t.month() as u8,
+                    t.day() as u8,
+                    t.hour() as u8,
+                    t.minute() as u8,
+                    t.second() as u8,
+                    t.timestamp_subsec_micros(),
+                ),
+                _ => Value::Bytes(s.into_bytes()),
+            }
+        }
+        SqlValue::Blob(b) => Value::Bytes(b),
+    }
+}
+
+/// Ends a transaction's ownership of the connection, even if the
+/// transaction body panicked (in which case it is rolled back).
+struct TxOwner<'a>(&'a SqliteBackend);
+
+impl Drop for TxOwner<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let _ = self.0.conn().execute_batch("ROLLBACK");
+        }
+        *self.0.tx_owner.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        self.0.tx_done.notify_all();
+    }
+}
+
+impl Backend for SqliteBackend {
+    fn log(&self) -> &slog::Logger {
+        &self.log
+    }
+
+    fn policies(&self) -> &PolicyStore {
+        &self.policies
+    }
+
+    fn stats(&self) -> &QueryStats {
+        &self.stats
+    }
+
+    fn in_transaction(&self) -> bool {
+        *self.tx_owner.lock().unwrap_or_else(|e| e.into_inner()) == Some(thread::current().id())
+    }
+
+    fn table(&self, table: &str) -> super::Result<&(Vec<String>, Vec<String>)> {
+        self.tables
+            .get(table)
+            .ok_or_else(|| BackendError::UnknownTable(table.to_string()))
+    }
+
+    fn transaction(
+        &self,
+        f: &mut dyn FnMut(&dyn Backend) -> super::Result<()>,
+    ) -> super::Result<()> {
+        let me = thread::current().id();
+        {
+            let mut owner = self.tx_owner.lock().unwrap_or_else(|e| e.into_inner());
+            if *owner == Some(me) {
+                // nested: part of the enclosing transaction
+                drop(owner);
+                return f(self);
+            }
+            while owner.is_some() {
+                owner = self.tx_done.wait(owner).unwrap_or_else(|e| e.into_inner());
+            }
+            *owner = Some(me);
+        }
+        let _owner = TxOwner(self);
+        self.run_transaction(f)
+    }
+
+    fn query_rows(&self, qname: &str, keys: Vec<Value>) -> super::Result<Rows> {
+        let sql = self.query(qname)?;
+        self.cache.query(qname, keys, self.in_transaction(), |keys| {
+            self.stats.time(
+                &self.log,
+                qname,
+                || self.query_sql(sql, keys),
+                |rows| rows.values.len(),
+            )
+        })
+    }
+
+    fn query_page(&self, qname: &str, mut keys: Vec<Value>, page: Page) -> super::Result<Rows> {
+        let sql = paged_sql(qname, self.query(qname)?, &mut keys, page)?;
+        self.stats.time(
+            &self.log,
+            qname,
+            || self.query_sql(&sql, keys),
+            |rows| rows.values.len(),
+        )
+    }
+
+    fn query_each(
+        &self,
+        qname: &str,
+        keys: Vec<Value>,
+        f: &mut dyn FnMut(Row) -> super::Result<()>,
+    ) -> super::Result<()> {
+        let sql = self.query(qname)?;
+        let count = || {
+            let mut n = 0;
+            self.each_sql(sql, keys, &mut |row| {
+                n += 1;
+                f(row)
+            })?;
+            Ok(n)
+        };
+        self.stats.time(&self.log, qname, count, |n| *n).map(|_| ())
+    }
+
+    fn query_columns(&self, qname: &str) -> super::Result<Vec<String>> {
+        let conn = self.conn();
+        let stmt = conn.prepare_cached(self.query(qname)?)?;
+        let columns = stmt.column_names().iter().map(|c| c.to_string()).collect();
+        Ok(columns)
+    }
+
+    fn exec_raw(&self, sql: &str, args: Vec<Value>) -> super::Result<()> {
+        let res = if args.is_empty() {
+            self.conn().execute_batch(sql)
+        } else {
+            self.exec_drop(sql, args).map(|_| ())
+        };
+        // may have written to any table
+        for table in self.tables.keys() {
+            self.invalidate(table);
+        }
+        Ok(res?)
+    }
+
+    fn exec_affected(&self, sql: &str, args: Vec<Value>) -> super::Result<u64> {
+        let res = self.exec_drop(sql, args);
+        for table in self.tables.keys() {
+            self.invalidate(table);
+        }
+        Ok(res? as u64)
+    }
+
+    fn query_raw(&self, sql: &str, args: Vec<Value>) -> super::Result<Vec<Vec<Value>>> {
+        Ok(self.query_sql(sql, args)?.values)
+    }
+
+    fn insert(&self, table: &str, vals: Vec<Value>) -> super::Result<()> {
+        if audit::needs_transaction(self, table) {
+            return audit::transaction(self, |be| be.insert(table, vals));
+        }
+        let valstrs: Vec<&str> = vals.iter().map(|_| "?").collect();
+        let q = format!(r"INSERT INTO {} VALUES ({});", table, valstrs.join(","));
+        self.exec_write("insert", table, &q, vals.clone())?;
+        audit::record_insert(self, table, &vals)
+    }
+
+    fn update(&self, table: &str, keys: Vec<Value>, vals: Vec<(usize, Value)>) -> super::Result<()> {
+        if audit::needs_transaction(self, table) {
+            return audit::transaction(self, |be| be.update(table, keys, vals));
+        }
+        let (key_cols, cols) = self.table(table)?;
+        check_keys(table, key_cols, &keys)?;
+        check_assignments(table, cols, &vals)?;
+        let old = audit::rows_before(self, table, &audit::key_conds(self, table, &keys)?)?;
+        let mut assignments = vec![];
+        let mut args = vec![];
+        for (index, value) in &vals {
+            assignments.push(format!("{} = ?", cols[*index],));
+            args.push(value.clone());
+        }
+        let mut conds = vec![];
+        for (i, value) in keys.iter().enumerate() {
+            conds.push(format!("{} = ?", key_cols[i],));
+            args.push(value.clone());
+        }
+        let q = format!(
+            r"UPDATE {} SET {} WHERE {};",
+            table,
+            assignments.join(","),
+            conds.join(" AND ")
+        );
+        self.exec_write("update", table, &q, args)?;
+        audit::record_update(self, table, old, &vals)
+    }
+
+    fn insert_or_update(
+        &self,
+        table: &str,
+        rec: Vec<Value>,
+        update_vals: Vec<(u64, Value)>,
+    ) -> super::Result<()> {
+        if audit::needs_transaction(self, table) {
+            return audit::transaction(self, |be| be.insert_or_update(table, rec, update_vals));
+        }
+        let (key_cols, cols) = self.table(table)?;
+        check_indices(table, cols, update_vals.iter().map(|(i, _)| *i as usize))?;
+        let old = audit::rows_before(self, table, &audit::rec_key_conds(self, table, &rec)?)?;
+        let mut args = vec![];
+        let recstrs: Vec<&str> = rec
+            .iter()
+            .map(|v| {
+                args.push(v.clone());
+                "?"
+            })
+            .collect();
+        let mut assignments = vec![];
+        for (index, value) in &update_vals {
+            assignments.push(format!("{} = ?", cols[*index as usize],));
+            args.push(value.clone());
+        }
+
+        // SQLite has no ON DUPLICATE KEY, but an equivalent upsert clause
+        let q = format!(
+            r"INSERT INTO {} VALUES ({}) ON CONFLICT ({}) DO UPDATE SET {};",
+            table,
+            recstrs.join(","),
+            key_cols.join(","),
+            assignments.join(","),
+        );
+        self.exec_write("insert_or_update", table, &q, args)?;
+        audit::record_upsert(self, table, old, &rec, &update_vals)
+    }
+
+    fn delete(&self, table: &str, keys: Vec<Value>) -> super::Result<()> {
+        if audit::needs_transaction(self, table) {
+            return audit::transaction(self, |be| be.delete(table, keys));
+        }
+        let (key_cols, _) = self.table(table)?;
+        check_keys(table, key_cols, &keys)?;
+        let old = audit::rows_before(self, table, &audit::key_conds(self, table, &keys)?)?;
+        let q = delete_sql(table, &key_cols.iter().collect::<Vec<_>>())?;
+        self.exec_write("delete", table, &q, keys)?;
+        audit::record_delete(self, table, old)
+    }
+
+    fn delete_where(&self, table: &str, conds: Vec<(usize, Value)>) -> super::Result<()> {
+        if audit::needs_transaction(self, table) {
+            return audit::transaction(self, |be| be.delete_where(table, conds));
+        }
+        let (_, cols) = self.table(table)?;
+        check_indices(table, cols, conds.iter().map(|(i, _)| *i))?;
+        let old = audit::rows_before(self, table, &conds)?;
+        let (cond_cols, args): (Vec<_>, Vec<_>) =
+            conds.into_iter().map(|(i, v)| (&cols[i], v)).unzip();
+        let q = delete_sql(table, &cond_cols)?;
+        self.exec_write("delete", table, &q, args)?;
+        audit::record_delete(self, table, old)
+    }
+}
+
+#[cfg(test)]
+impl SqliteBackend {
+    /// A fresh in-memory database with the built-in schema, to which no
+    /// migrations have been applied.
+    pub(crate) fn for_tests() -> SqliteBackend {
+        let schema = Schema::load(None).unwrap();
+        let cache = QueryCache::new(&schema, &[]).unwrap();
+        let stats = QueryStats::new(std::time::Duration::from_secs(1));
+        SqliteBackend::new(None, schema, stats, cache, None).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend() -> SqliteBackend {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        be.insert("questions", vec![1.into(), 1.into(), "Why?".into()])
+            .unwrap();
+        be
+    }
+
+    fn questions(be: &SqliteBackend) -> Vec<String> {
+        be.query_raw("SELECT question FROM questions ORDER BY lec, q;", vec![])
+            .unwrap()
+            .into_iter()
+            .map(|r| mysql::from_value(r[0].clone()))
+            .collect()
+    }
+
+    #[test]
+    fn named_queries_return_stored_values() {
+        let be = backend();
+        let at = NaiveDateTime::parse_from_str("2021-02-03 04:05:06.789", DATETIME_FORMAT).unwrap();
+        let answer: Vec<Value> = vec![
+            "a@example.com".into(),
+            1.into(),
+            1.into(),
+            Value::NULL,
+            at.into(),
+            "p".into(),
+        ];
+        be.insert("answers", answer.clone()).unwrap();
+        let rows = be
+            .query_rows("my_answers_for_lec", vec![1.into(), "a@example.com".into()])
+            .unwrap();
+        assert_eq!(&rows.columns[..3], &["email", "lec", "q"]);
+        assert_eq!(rows.values, vec![answer]);
+        assert!(be
+            .query_rows("my_answers_for_lec", vec![2.into(), "a@example.com".into()])
+            .unwrap()
+            .values
+            .is_empty());
+    }
+
+    #[test]
+    fn unknown_queries_and_tables_are_errors() {
+        let be = backend();
+        assert!(matches!(
+            be.query_rows("no_such_query", vec![]),
+            Err(BackendError::UnknownQuery(_))
+        ));
+        assert!(matches!(be.table("no_such_table"), Err(BackendError::UnknownTable(_))));
+    }
+
+    #[test]
+    fn update_rejects_wrong_number_of_keys() {
+        let be = backend();
+        let vals = vec![(2, "How?".into())];
+        assert!(be.update("questions", vec![1.into()], vals.clone()).is_err());
+        assert!(be
+            .update("questions", vec![1.into(), 1.into(), 1.into()], vals)
+            .is_err());
+    }
+
+    #[test]
+    fn update_rejects_bad_column() {
+        let be = backend();
+        assert!(be
+            .update("questions", vec![1.into(), 1.into()], vec![(3, "How?".into())])
+            .is_err());
+        assert!(be.update("questions", vec![1.into(), 1.into()], vec![]).is_err());
+        let rec = vec![1.into(), 1.into(), "How?".into()];
+        assert!(be
+            .insert_or_update("questions", rec, vec![(7, "How?".into())])
+            .is_err());
+        assert!(be.delete_where("questions", vec![(9, 1.into())]).is_err());
+    }
+
+    #[test]
+    fn update_changes_only_the_keyed_row() {
+        let be = backend();
+        be.insert("questions", vec![1.into(), 2.into(), "What?".into()])
+            .unwrap();
+        be.update("questions", vec![1.into(), 2.into()], vec![(2, "How?".into())])
+            .unwrap();
+        assert_eq!(questions(&be), vec!["Why?".to_string(), "How?".to_string()]);
+    }
+
+    #[test]
+    fn exec_affected_counts_changed_rows() {
+        let be = backend();
+        let sql = "UPDATE questions SET question = ? WHERE lec = ? AND question = ?;";
+        let args: Vec<Value> = vec!["How?".into(), 1.into(), "Why?".into()];
+        assert_eq!(be.exec_affected(sql, args.clone()).unwrap(), 1);
+        assert_eq!(be.exec_affected(sql, args).unwrap(), 0);
+    }
+
+    fn add_question(be: &dyn Backend, q: u32, text: &str) -> crate::backend::Result<()> {
+        be.insert("questions", vec![1.into(), q.into(), text.into()])
+    }
+
+    #[test]
+    fn transactions_commit_on_success() {
+        let be = backend();
+        be.transaction(&mut |be| {
+            add_question(be, 2, "How?")?;
+            add_question(be, 3, "When?")
+        })
+        .unwrap();
+        assert_eq!(questions(&be), vec!["Why?", "How?", "When?"]);
+        assert!(!be.in_transaction());
+    }
+
+    #[test]
+    fn transactions_roll_back_on_error() {
+        let be = backend();
+        let res = be.transaction(&mut |be| {
+            add_question(be, 2, "How?")?;
+            // the key is taken
+            add_question(be, 1, "When?")
+        });
+        assert!(res.is_err());
+        assert_eq!(questions(&be), vec!["Why?"]);
+        assert!(!be.in_transaction());
+    }
+
+    #[test]
+    fn nested_transactions_join_the_enclosing_one() {
+        let be = backend();
+        let res = be.transaction(&mut |be| {
+            be.transaction(&mut |be| add_question(be, 2, "How?"))?;
+            Err(BackendError::Database("give up".to_string()))
+        });
+        assert!(res.is_err());
+        assert_eq!(questions(&be), vec!["Why?"]);
+    }
+
+    #[test]
+    fn transactions_roll_back_on_panic() {
+        let be = Arc::new(backend());
+        let tx = be.clone();
+        let res = thread::spawn(move || {
+            tx.transaction(&mut |be| {
+                add_question(be, 2, "How?").unwrap();
+                panic!("request failed");
+            })
+        })
+        .join();
+        assert!(res.is_err());
+        // the connection is free again, and the write is gone
+        add_question(&*be, 3, "When?").unwrap();
+        assert_eq!(questions(&be), vec!["Why?", "When?"]);
+    }
+
+    #[test]
+    fn delete_removes_only_the_keyed_row() {
+        let be = backend();
+        add_question(&be, 2, "How?").unwrap();
+        be.delete("questions", vec![1.into(), 1.into()]).unwrap();
+        assert_eq!(questions(&be), vec!["How?"]);
+        assert!(be.delete("questions", vec![1.into()]).is_err());
+        assert_eq!(questions(&be), vec!["How?"]);
+    }
+
+    #[test]
+    fn delete_where_removes_matching_rows() {
+        let be = backend();
+        add_question(&be, 2, "How?").unwrap();
+        be.insert("questions", vec![2.into(), 1.into(), "When?".into()])
+            .unwrap();
+        be.delete_where("questions", vec![(0, 1.into())]).unwrap();
+        assert_eq!(questions(&be), vec!["When?"]);
+        assert!(be.delete_where("questions", vec![]).is_err());
+        assert_eq!(questions(&be), vec!["When?"]);
+    }
+}
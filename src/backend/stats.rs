@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::Result;
+
+/// Aggregated timings of one named query or table write.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct QueryStat {
+    pub name: String,
+    pub calls: u64,
+    pub errors: u64,
+    pub slow: u64,
+    pub rows: u64,
+    pub total_ms: f64,
+    pub avg_ms: f64,
+    pub max_ms: f64,
+}
+
+/// Times the named queries and writes a backend runs, logging each one and
+/// warning about those that take longer than the slow-query threshold.
+pub struct QueryStats {
+    slow_threshold: Duration,
+    // query name (or "insert:<table>" etc.) --> timings
+    stats: Mutex<HashMap<String, QueryStat>>,
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+impl QueryStats {
+    pub fn new(slow_threshold: Duration) -> Self {
+        QueryStats {
+            slow_threshold: slow_threshold,
+            stats: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `f` and records how long it took under `name`; `rows` gives the
+    /// number of rows it returned or changed.
+    pub fn time<T, F, R>(&self, log: &slog::Logger, name: &str, f: F, rows: R) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+        R: FnOnce(&T) -> usize,
+    {
+        let start = Instant::now();
+        let res = f();
+        let elapsed = start.elapsed();
+        let slow = elapsed >= self.slow_threshold;
+        let nrows = match res {
+            Ok(ref v) => {
+                let n = rows(v);
+                if slow {
+                    warn!(log, "slow query {}: {} row(s) in {:?}", name, n, elapsed);
+                } else {
+                    debug!(log, "query {}: {} row(s) in {:?}", name, n, elapsed);
+                }
+                n
+            }
+            Err(ref e) => {
+                debug!(log, "query {} failed after {:?}: {}", name, elapsed, e);
+                0
+            }
+        };
+
+        let mut stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        let stat = stats.entry(name.to_string()).or_insert_with(|| QueryStat {
+            name: name.to_string(),
+            ..QueryStat::default()
+        });
+        stat.calls += 1;
+        stat.rows += nrows as u64;
+        stat.total_ms += millis(elapsed);
+        stat.avg_ms = stat.total_ms / stat.calls as f64;
+        stat.max_ms = stat.max_ms.max(millis(elapsed));
+        if res.is_err() {
+            stat.errors += 1;
+        }
+        if slow {
+            stat.slow += 1;
+        }
+        res
+    }
+
+    /// The timings recorded so far, sorted by total time spent.
+    pub fn snapshot(&self) -> Vec<QueryStat> {
+        let stats = self.stats.lock().unwrap_or_else(|e| e.into_inner());
+        let mut res: Vec<_> = stats.values().cloned().collect();
+        res.sort_by(|a, b| {
+            b.total_ms
+                .partial_cmp(&a.total_ms)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::BackendError;
+
+    fn log() -> slog::Logger {
+        slog::Logger::root(slog::Discard, o!())
+    }
+
+    fn stat(stats: &QueryStats, name: &str) -> QueryStat {
+        stats.snapshot().into_iter().find(|s| s.name == name).unwrap()
+    }
+
+    #[test]
+    fn counts_calls_rows_and_errors() {
+        let stats = QueryStats::new(Duration::from_secs(60));
+        let log = log();
+        let rows = |v: &Vec<u32>| v.len();
+        assert_eq!(stats.time(&log, "q", || Ok(vec![1, 2, 3]), rows).unwrap().len(), 3);
+        assert_eq!(stats.time(&log, "q", || Ok(vec![4]), rows).unwrap().len(), 1);
+        let failed = || Err(BackendError::Database("failed".to_string()));
+        assert!(stats.time(&log, "q", failed, rows).is_err());
+
+        let q = stat(&stats, "q");
+        assert_eq!((q.calls, q.rows, q.errors, q.slow), (3, 4, 1, 0));
+        assert!(q.max_ms <= q.total_ms);
+        assert_eq!(q.avg_ms, q.total_ms / 3.0);
+    }
+
+    #[test]
+    fn counts_slow_queries() {
+        let stats = QueryStats::new(Duration::from_secs(0));
+        stats.time(&log(), "q", || Ok(()), |_| 0).unwrap();
+        assert_eq!(stat(&stats, "q").slow, 1);
+    }
+
+    #[test]
+    fn snapshot_is_sorted_by_total_time() {
+        let stats = QueryStats::new(Duration::from_secs(60));
+        let log = log();
+        stats.time(&log, "fast", || Ok(()), |_| 0).unwrap();
+        let sleep = || {
+            std::thread::sleep(Duration::from_millis(5));
+            Ok(())
+        };
+        stats.time(&log, "slow", sleep, |_| 0).unwrap();
+        let names: Vec<_> = stats.snapshot().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["slow", "fast"]);
+    }
+}
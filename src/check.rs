@@ -0,0 +1,368 @@
+use crate::backend::{Schema, SchemaStmt};
+use crate::templates;
+use handlebars::Handlebars;
+use sqlparser::ast::*;
+use std::collections::HashMap;
+use std::path::Path;
+
+const TEMPLATE_EXT: &str = ".html.hbs";
+
+/// Results of the startup self-check.
+#[derive(Default)]
+pub struct Report {
+    checks: Vec<(String, Result<(), String>)>,
+}
+
+impl Report {
+    fn add(&mut self, what: String, res: Result<(), String>) {
+        self.checks.push((what, res));
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|(_, res)| res.is_ok())
+    }
+
+    pub fn print(&self) {
+        for (what, res) in &self.checks {
+            match res {
+                Ok(()) => println!("ok    {}", what),
+                Err(e) => println!("FAIL  {}: {}", what, e),
+            }
+        }
+        let failed = self.checks.iter().filter(|(_, res)| res.is_err()).count();
+        println!("{} checks, {} failed", self.checks.len(), failed);
+    }
+}
+
+/// Checks the named queries in the schema (the built-in one, or the file at
+/// `schema_path`) against its tables and views, and that every template used
+/// by a route exists in `template_dir` and compiles.
+pub fn run(schema_path: Option<&str>, template_dir: &str) -> Report {
+    let mut report = Report::default();
+    match Schema::load(schema_path) {
+        Ok(schema) => {
+            report.add("schema".to_string(), Ok(()));
+            check_schema(&schema, &mut report);
+        }
+        Err(e) => report.add("schema".to_string(), Err(e)),
+    }
+    check_templates(Path::new(template_dir), &mut report);
+    report
+}
+
+fn parse(sql: &str) -> Result<Statement, String> {
+    let dialect = sqlparser::dialect::MySqlDialect {};
+    // sqlparser does not know `?` placeholders; any literal type-checks the
+    // same for our purposes. None of our queries contain a `?` in a string.
+    let mut asts = sqlparser::parser::Parser::parse_sql(&dialect, sql.replace('?', "NULL"))
+        .map_err(|e| format!("parse error: {:?}", e))?;
+    if asts.len() != 1 {
+        return Err(format!("expected one statement, found {}", asts.len()));
+    }
+    Ok(asts.remove(0))
+}
+
+fn as_select(query: Query) -> Result<Select, String> {
+    match query.body {
+        SetExpr::Select(select) => Ok(*select),
+        _ => Err("only plain SELECT queries are supported".to_string()),
+    }
+}
+
+fn check_schema(schema: &Schema, report: &mut Report) {
+    // relation name --> columns, for tables and views
+    let mut relations: HashMap<String, Vec<String>> = schema
+        .tables
+        .iter()
+        .map(|(name, (_, cols))| (name.clone(), cols.clone()))
+        .collect();
+
+    for stmt in &schema.stmts {
+        match stmt {
+            SchemaStmt::View(sql) => {
+                let res = parse(sql).and_then(|stmt| match stmt {
+                    Statement::CreateView { name, query, .. } => {
+                        let select = as_select(*query)?;
+                        check_select(&relations, &select)?;
+                        relations.insert(name.to_string(), output_columns(&select));
+                        Ok(())
+                    }
+                    _ => Err("not a CREATE VIEW statement".to_string()),
+                });
+                report.add(format!("view: {}", sql), res);
+            }
+            SchemaStmt::Query(name, sql) => {
+                let res = parse(sql).and_then(|stmt| match stmt {
+                    Statement::Query(query) => check_select(&relations, &as_select(*query)?),
+                    _ => Err("not a SELECT query".to_string()),
+                });
+                report.add(format!("query {}", name), res);
+            }
+            SchemaStmt::Table(..) => (),
+        }
+    }
+}
+
+/// Names of the columns a SELECT returns (for views).
+fn output_columns(select: &Select) -> Vec<String> {
+    select
+        .projection
+        .iter()
+        .filter_map(|item| match item {
+            SelectItem::ExprWithAlias { alias, .. } => Some(alias.to_string()),
+            SelectItem::UnnamedExpr(Expr::Identifier(id)) => Some(id.to_string()),
+            SelectItem::UnnamedExpr(Expr::CompoundIdentifier(ids)) => {
+                ids.last().map(|id| id.to_string())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Collects the column references in `expr`, each as its identifier parts.
+fn column_refs(expr: &Expr, out: &mut Vec<Vec<String>>) {
+    match expr {
+        Expr::Identifier(id) => out.push(vec![id.to_string()]),
+        Expr::CompoundIdentifier(ids) => out.push(ids.iter().map(|id| id.to_string()).collect()),
+        Expr::BinaryOp { left, right, .. } => {
+            column_refs(left, out);
+            column_refs(right, out);
+        }
+        Expr::UnaryOp { expr, .. }
+        | Expr::Nested(expr)
+        | Expr::IsNull(expr)
+        | Expr::IsNotNull(expr) => column_refs(expr, out),
+        Expr::InList { expr, list, .. } => {
+            column_refs(expr, out);
+            list.iter().for_each(|e| column_refs(e, out));
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            column_refs(expr, out);
+            column_refs(low, out);
+            column_refs(high, out);
+        }
+        Expr::Function(f) => f.args.iter().for_each(|e| column_refs(e, out)),
+        _ => (),
+    }
+}
+
+fn check_select(relations: &HashMap<String, Vec<String>>, select: &Select) -> Result<(), String> {
+    // name or alias --> columns of the relations in FROM
+    let mut scope: Vec<(String, &Vec<String>)> = vec![];
+    let mut exprs: Vec<&Expr> = vec![];
+    let mut add_relation = |factor: &TableFactor| -> Result<(), String> {
+        match factor {
+            TableFactor::Table { name, alias, .. } => {
+                let name = name.to_string();
+                let cols = relations
+                    .get(&name)
+                    .ok_or_else(|| format!("unknown table or view \"{}\"", name))?;
+                scope.push((name.clone(), cols));
+                if let Some(alias) = alias {
+                    scope.push((alias.name.to_string(), cols));
+                }
+                Ok(())
+            }
+            _ => Err("only plain table references are supported in FROM".to_string()),
+        }
+    };
+    for twj in &select.from {
+        add_relation(&twj.relation)?;
+        for join in &twj.joins {
+            add_relation(&join.relation)?;
+            match &join.join_operator {
+                JoinOperator::Inner(JoinConstraint::On(e))
+                | JoinOperator::LeftOuter(JoinConstraint::On(e))
+                | JoinOperator::RightOuter(JoinConstraint::On(e))
+                | JoinOperator::FullOuter(JoinConstraint::On(e)) => exprs.push(e),
+                _ => (),
+            }
+        }
+    }
+
+    for item in &select.projection {
+        match item {
+            SelectItem::UnnamedExpr(e) | SelectItem::ExprWithAlias { expr: e, .. } => exprs.push(e),
+            SelectItem::QualifiedWildcard(name) => {
+                let name = name.to_string();
+                if !scope.iter().any(|(n, _)| *n == name) {
+                    return Err(format!("\"{}.*\" does not refer to a table in FROM", name));
+                }
+            }
+            SelectItem::Wildcard => (),
+        }
+    }
+    exprs.extend(select.selection.iter());
+    exprs.extend(select.group_by.iter());
+    exprs.extend(select.having.iter());
+
+    let aliases = output_columns(select);
+    let mut refs = vec![];
+    exprs.into_iter().for_each(|e| column_refs(e, &mut refs));
+    for r in refs {
+        match r.as_slice() {
+            [col] => {
+                if !aliases.contains(col) && !scope.iter().any(|(_, cols)| cols.contains(col)) {
+                    return Err(format!("unknown column \"{}\"", col));
+                }
+            }
+            [rel, col] => match scope.iter().find(|(n, _)| n == rel) {
+                Some((_, cols)) if cols.contains(col) => (),
+                Some(_) => return Err(format!("\"{}\" has no column \"{}\"", rel, col)),
+                None => return Err(format!("\"{}\" does not refer to a table in FROM", rel)),
+            },
+            _ => return Err(format!("unsupported column reference \"{}\"", r.join("."))),
+        }
+    }
+    Ok(())
+}
+
+/// Compiles every template under `dir`, and checks that the ones routes
+/// render are among them.
+fn check_templates(dir: &Path, report: &mut Report) {
+    let mut hbs = Handlebars::new();
+    let mut files = vec![];
+    if let Err(e) = template_files(dir, &mut files) {
+        report.add(
+            format!("template directory {}", dir.display()),
+            Err(e.to_string()),
+        );
+        return;
+    }
+    for path in files {
+        let name = path
+            .strip_prefix(dir)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .trim_end_matches(TEMPLATE_EXT)
+            .to_string();
+        let res = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| {
+                hbs.register_template_string(&name, text)
+                    .map_err(|e| e.to_string())
+            });
+        if res.is_err() {
+            report.add(format!("template {}", name), res);
+        }
+    }
+    for name in templates::ALL {
+        let res = if hbs.get_template(name).is_some() {
+            Ok(())
+        } else {
+            Err(format!(
+                "{}/{}{} is missing or failed to compile",
+                dir.display(),
+                name,
+                TEMPLATE_EXT
+            ))
+        };
+        report.add(format!("template {}", name), res);
+    }
+}
+
+fn template_files(dir: &Path, out: &mut Vec<std::path::PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            template_files(&path, out)?;
+        } else if path.to_string_lossy().ends_with(TEMPLATE_EXT) {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLES: &str = "
+        CREATE TABLE users (email varchar(255), is_admin tinyint, PRIMARY KEY (email));
+        CREATE TABLE answers (email varchar(255), lec int, answer text, PRIMARY KEY (email, lec));
+        CREATE VIEW counts as SELECT answers.email, COUNT(answers.lec) AS n FROM answers \
+            GROUP BY answers.email;
+    ";
+
+    fn failures(report: &Report) -> Vec<String> {
+        report
+            .checks
+            .iter()
+            .filter_map(|(what, res)| res.as_ref().err().map(|e| format!("{}: {}", what, e)))
+            .collect()
+    }
+
+    fn schema_failures(queries: &str) -> Vec<String> {
+        let mut report = Report::default();
+        let schema = Schema::parse(&format!("{}{}", TABLES, queries)).unwrap();
+        check_schema(&schema, &mut report);
+        failures(&report)
+    }
+
+    #[test]
+    fn built_in_schema_and_templates_pass() {
+        let report = run(None, "templates");
+        assert!(report.is_ok(), "{:?}", failures(&report));
+    }
+
+    #[test]
+    fn accepts_joins_aliases_and_views() {
+        let queries = "
+            QUERY admins: SELECT email FROM users WHERE is_admin = 1 AND email = ?;
+            QUERY answered: SELECT u.email, counts.n AS answered FROM users u \
+                LEFT JOIN counts ON (u.email = counts.email) ORDER BY answered;
+            QUERY mine: SELECT answers.* FROM answers WHERE answers.email IN (?, ?);
+        ";
+        assert_eq!(schema_failures(queries), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_unknown_tables_and_columns() {
+        let queries = "
+            QUERY a: SELECT * FROM nope;
+            QUERY b: SELECT email FROM users WHERE lec = ?;
+            QUERY c: SELECT users.answer FROM users;
+            QUERY d: SELECT answers.email FROM users;
+            QUERY e: SELECT answers.* FROM users;
+            QUERY f: DELETE FROM users;
+        ";
+        assert_eq!(
+            schema_failures(queries),
+            vec![
+                "query a: unknown table or view \"nope\"",
+                "query b: unknown column \"lec\"",
+                "query c: \"users\" has no column \"answer\"",
+                "query d: \"answers\" does not refer to a table in FROM",
+                "query e: \"answers.*\" does not refer to a table in FROM",
+                "query f: not a SELECT query",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_missing_and_broken_templates() {
+        let dir = std::env::temp_dir().join(format!("websubmit-templates-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("layout.html.hbs"), "{{> page}}").unwrap();
+        std::fs::write(dir.join("login.html.hbs"), "{{#if CLASS_ID}}unclosed").unwrap();
+        let mut report = Report::default();
+        check_templates(&dir, &mut report);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let failures = failures(&report);
+        assert!(failures.iter().any(|f| f.starts_with("template login:")));
+        assert!(failures.iter().any(|f| f.starts_with("template leclist:")));
+        assert!(!failures.iter().any(|f| f.starts_with("template layout:")));
+    }
+
+    #[test]
+    fn reports_missing_template_directory() {
+        let mut report = Report::default();
+        check_templates(Path::new("no/such/directory"), &mut report);
+        let failures = failures(&report);
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].starts_with("template directory no/such/directory:"));
+    }
+}
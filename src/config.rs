@@ -1,5 +1,7 @@
+use std::env;
 use std::fs;
 use std::io::{Error, ErrorKind, Read};
+use std::net::IpAddr;
 use toml;
 
 #[derive(Debug, Clone)]
@@ -14,20 +16,230 @@ pub struct Config {
     pub template_dir: String,
     /// Web resource root directory
     pub resource_dir: String,
-    /// Secret (for API key generation)
+    /// Server secret (API keys issued before keys were random were derived
+    /// from it)
     pub secret: String,
     /// Whether to send emails
     pub send_emails: bool,
-    /// Whether to reset and prime db 
-    pub prime: bool,
+    /// Directory emails are written to instead of being sent (for testing)
+    pub mail_dir: Option<String>,
+    /// URL the site is reached at, for links in emails
+    pub base_url: String,
+    /// Storage backend ("mysql" or "sqlite")
+    pub backend: String,
+    /// SQLite database file (in-memory if not set)
+    pub sqlite_path: Option<String>,
+    /// Schema file to use instead of the built-in `schema.sql`
+    pub schema_path: Option<String>,
+    /// Queries taking at least this many milliseconds are logged as slow
+    pub slow_query_ms: u64,
+    /// Named queries whose results are kept in memory until their tables
+    /// are written to
+    pub cached_queries: Vec<String>,
+    /// Sessions end after this many minutes without a request
+    pub session_idle_minutes: u64,
+    /// Sessions end this many hours after login, however active they are
+    pub session_max_hours: u64,
+    /// API keys expire this many days after they are issued (never if 0)
+    pub apikey_lifetime_days: u64,
+    /// Login links can be used for this many minutes after they are sent
+    pub login_link_minutes: u64,
+    /// Only addresses on the roster may register (admins always may)
+    pub registration_roster_only: bool,
+    /// Addresses in these domains may register; if set, registration is
+    /// restricted to them (and to the roster, with `registration_roster_only`)
+    pub registration_domains: Vec<String>,
+    /// Whether cookies are only sent over HTTPS
+    pub secure_cookies: bool,
+    /// Limits on how often a client or address may use the login and
+    /// submission routes
+    pub rate_limits: RateLimitConfig,
+    /// Reverse proxies whose `X-Real-IP` header is trusted to name the client
+    pub trusted_proxies: Vec<IpAddr>,
+    /// MySQL connection settings
+    pub database: DatabaseConfig,
+}
+
+/// At most `max` requests per `window_minutes`; no limit if `max` is 0.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub max: u32,
+    pub window_minutes: u64,
+}
+
+/// Rate limits, read from the `[rate_limits]` section of the config file.
+/// Each applies separately to every client IP address and to every email
+/// address, API key prefix or login link a request is for.
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Requests for an API key or a login link, each of which sends an email
+    pub generate: RateLimit,
+    /// Attempts to log in with an API key or a login link
+    pub check: RateLimit,
+    /// Answer submissions
+    pub submit: RateLimit,
+}
+
+/// Connection settings for the MySQL backend, read from the `[database]`
+/// section of the config file. Each setting can be overridden with a
+/// `WEBSUBMIT_DB_*` environment variable (e.g., `WEBSUBMIT_DB_PASSWORD`).
+#[derive(Debug, Clone)]
+pub struct DatabaseConfig {
+    /// Server host name or IP address
+    pub host: String,
+    /// Server TCP port
+    pub port: u16,
+    /// Database user
+    pub user: String,
+    /// Database password
+    pub password: Option<String>,
+    /// File to read the database password from (instead of `password`)
+    pub password_file: Option<String>,
+    /// Unix socket path (used instead of host and port if set)
+    pub socket: Option<String>,
+    /// Database name (defaults to the class ID)
+    pub name: Option<String>,
+    /// Whether to connect over TLS
+    pub tls: bool,
+    /// CA certificate to verify the server against when using TLS
+    pub tls_ca: Option<String>,
+    /// Whether to accept invalid server certificates (for development)
+    pub tls_accept_invalid_certs: bool,
+    /// Minimum number of pooled connections
+    pub pool_min: usize,
+    /// Maximum number of pooled connections
+    pub pool_max: usize,
+    /// Number of prepared statements cached per connection
+    pub stmt_cache_size: usize,
+}
+
+impl DatabaseConfig {
+    /// Returns the configured password, reading it from `password_file` if
+    /// one is set.
+    pub fn password(&self) -> Result<Option<String>, Error> {
+        match self.password_file {
+            Some(ref path) => Ok(Some(fs::read_to_string(path)?.trim_end().to_string())),
+            None => Ok(self.password.clone()),
+        }
+    }
+}
+
+fn env_or(var: &str, default: Option<String>) -> Option<String> {
+    env::var(format!("WEBSUBMIT_DB_{}", var)).ok().or(default)
+}
+
+fn parse_database(value: Option<&toml::Table>) -> Result<DatabaseConfig, Error> {
+    let get_str = |key: &str| -> Option<String> {
+        value
+            .and_then(|t| t.get(key))
+            .and_then(|v| v.as_str())
+            .map(String::from)
+    };
+    let get_bool = |key: &str| -> Option<bool> {
+        value.and_then(|t| t.get(key)).and_then(|v| v.as_bool())
+    };
+    let invalid = |key: &str| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid database setting \"{}\"", key),
+        )
+    };
+
+    let port = match env::var("WEBSUBMIT_DB_PORT") {
+        Ok(p) => p.parse().map_err(|_| invalid("port"))?,
+        Err(_) => match value.and_then(|t| t.get("port")) {
+            Some(p) => p
+                .as_integer()
+                .filter(|p| *p > 0 && *p <= u16::MAX as i64)
+                .ok_or(invalid("port"))? as u16,
+            None => 3306,
+        },
+    };
+    let get_usize = |key: &str, default: usize| -> Result<usize, Error> {
+        match value.and_then(|t| t.get(key)) {
+            Some(v) => v
+                .as_integer()
+                .filter(|v| *v >= 0)
+                .map(|v| v as usize)
+                .ok_or(invalid(key)),
+            None => Ok(default),
+        }
+    };
+    let tls = match env::var("WEBSUBMIT_DB_TLS") {
+        Ok(t) => t.parse().map_err(|_| invalid("tls"))?,
+        Err(_) => get_bool("tls").unwrap_or(false),
+    };
+
+    Ok(DatabaseConfig {
+        host: env_or("HOST", get_str("host")).unwrap_or(String::from("127.0.0.1")),
+        port: port,
+        user: env_or("USER", get_str("user")).unwrap_or(String::from("root")),
+        password: env_or("PASSWORD", get_str("password")),
+        password_file: env_or("PASSWORD_FILE", get_str("password_file")),
+        socket: env_or("SOCKET", get_str("socket")),
+        name: env_or("NAME", get_str("name")),
+        tls: tls,
+        tls_ca: env_or("TLS_CA", get_str("tls_ca")),
+        tls_accept_invalid_certs: get_bool("tls_accept_invalid_certs").unwrap_or(false),
+        pool_min: get_usize("pool_min", 10)?,
+        pool_max: get_usize("pool_max", 100)?,
+        stmt_cache_size: get_usize("stmt_cache_size", 32)?,
+    })
+}
+
+fn parse_rate_limits(value: Option<&toml::Table>) -> Result<RateLimitConfig, Error> {
+    let get = |key: &str, default: u64| -> Result<u64, Error> {
+        match value.and_then(|t| t.get(key)) {
+            Some(v) => v.as_integer().filter(|v| *v >= 0).map(|v| v as u64).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid rate limit setting \"{}\"", key),
+                )
+            }),
+            None => Ok(default),
+        }
+    };
+    let limit = |name: &str, max: u64, window_minutes: u64| -> Result<RateLimit, Error> {
+        Ok(RateLimit {
+            max: get(&format!("{}_max", name), max)? as u32,
+            window_minutes: get(&format!("{}_window_minutes", name), window_minutes)?,
+        })
+    };
+
+    Ok(RateLimitConfig {
+        generate: limit("generate", 5, 60)?,
+        check: limit("check", 20, 15)?,
+        submit: limit("submit", 60, 60)?,
+    })
+}
+
+fn parse_trusted_proxies(value: Option<&toml::Value>) -> Result<Vec<IpAddr>, Error> {
+    let proxies = match value {
+        Some(v) => v.as_slice().unwrap(),
+        None => return Ok(vec![]),
+    };
+    proxies
+        .iter()
+        .map(|v| {
+            v.as_str().and_then(|s| s.parse().ok()).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid trusted proxy address {}", v),
+                )
+            })
+        })
+        .collect()
 }
 
 pub(crate) fn parse(path: &str) -> Result<Config, Error> {
     let mut f = fs::File::open(path)?;
     let mut buf = String::new();
     f.read_to_string(&mut buf)?;
+    parse_str(&buf)
+}
 
-    let value = match toml::Parser::new(&buf).parse() {
+fn parse_str(buf: &str) -> Result<Config, Error> {
+    let value = match toml::Parser::new(buf).parse() {
         None => {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
@@ -59,6 +271,165 @@ pub(crate) fn parse(path: &str) -> Result<Config, Error> {
         resource_dir: value.get("resource_dir").unwrap().as_str().unwrap().into(),
         secret: value.get("secret").unwrap().as_str().unwrap().into(),
         send_emails: value.get("send_emails").unwrap().as_bool().unwrap().into(),
-        prime: value.get("prime").unwrap().as_bool().unwrap().into(),
+        mail_dir: value.get("mail_dir").map(|v| v.as_str().unwrap().into()),
+        base_url: value
+            .get("base_url")
+            .map(|v| v.as_str().unwrap().trim_end_matches('/').into())
+            .unwrap_or(String::from("http://localhost:8000")),
+        backend: value
+            .get("backend")
+            .map(|v| v.as_str().unwrap().into())
+            .unwrap_or(String::from("mysql")),
+        sqlite_path: value
+            .get("sqlite_path")
+            .map(|v| v.as_str().unwrap().into()),
+        schema_path: value
+            .get("schema_path")
+            .map(|v| v.as_str().unwrap().into()),
+        slow_query_ms: value
+            .get("slow_query_ms")
+            .map(|v| v.as_integer().unwrap() as u64)
+            .unwrap_or(100),
+        cached_queries: match value.get("cached_queries") {
+            Some(v) => v
+                .as_slice()
+                .unwrap()
+                .into_iter()
+                .map(|v| v.as_str().unwrap().into())
+                .collect(),
+            None => vec!["leclist".into(), "qs_by_lec".into()],
+        },
+        session_idle_minutes: value
+            .get("session_idle_minutes")
+            .map(|v| v.as_integer().unwrap() as u64)
+            .unwrap_or(60),
+        session_max_hours: value
+            .get("session_max_hours")
+            .map(|v| v.as_integer().unwrap() as u64)
+            .unwrap_or(12),
+        apikey_lifetime_days: value
+            .get("apikey_lifetime_days")
+            .map(|v| v.as_integer().unwrap() as u64)
+            .unwrap_or(180),
+        login_link_minutes: value
+            .get("login_link_minutes")
+            .map(|v| v.as_integer().unwrap() as u64)
+            .unwrap_or(15),
+        registration_roster_only: value
+            .get("registration_roster_only")
+            .map(|v| v.as_bool().unwrap())
+            .unwrap_or(false),
+        registration_domains: match value.get("registration_domains") {
+            Some(v) => v
+                .as_slice()
+                .unwrap()
+                .into_iter()
+                .map(|v| v.as_str().unwrap().trim_start_matches('@').to_lowercase())
+                .collect(),
+            None => vec![],
+        },
+        secure_cookies: value
+            .get("secure_cookies")
+            .map(|v| v.as_bool().unwrap())
+            .unwrap_or(true),
+        rate_limits: parse_rate_limits(value.get("rate_limits").and_then(|v| v.as_table()))?,
+        trusted_proxies: parse_trusted_proxies(value.get("trusted_proxies"))?,
+        database: parse_database(value.get("database").and_then(|v| v.as_table()))?,
     })
 }
+
+/// The settings every config file must have.
+#[cfg(test)]
+const REQUIRED_SETTINGS: &str = r#"
+    class = "CSCI 0000"
+    admins = ["admin@example.com"]
+    staff = ["staff@example.com"]
+    template_dir = "templates"
+    resource_dir = "resources"
+    secret = "secret"
+    send_emails = false
+"#;
+
+#[cfg(test)]
+impl Config {
+    /// The configuration of a course with only the required settings, which
+    /// uses the defaults for everything else.
+    pub(crate) fn for_tests() -> Config {
+        parse_str(REQUIRED_SETTINGS).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_with(settings: &str) -> Result<Config, Error> {
+        parse_str(&format!("{}{}", REQUIRED_SETTINGS, settings))
+    }
+
+    #[test]
+    fn database_settings_have_defaults() {
+        let db = Config::for_tests().database;
+        assert_eq!((db.host.as_str(), db.port, db.user.as_str()), ("127.0.0.1", 3306, "root"));
+        assert_eq!(db.password().unwrap(), None);
+        assert_eq!(db.name, None);
+        assert!(!db.tls);
+    }
+
+    #[test]
+    fn reads_database_settings() {
+        let config = parse_with(
+            r#"
+            [database]
+            host = "db.example.com"
+            port = 3307
+            user = "websubmit"
+            password = "hunter2"
+            name = "course"
+            tls = true
+            tls_ca = "/etc/ca.pem"
+            "#,
+        )
+        .unwrap();
+        let db = config.database;
+        assert_eq!(db.host, "db.example.com");
+        assert_eq!(db.port, 3307);
+        assert_eq!(db.user, "websubmit");
+        assert_eq!(db.password().unwrap().as_deref(), Some("hunter2"));
+        assert_eq!(db.name.as_deref(), Some("course"));
+        assert!(db.tls);
+        assert_eq!(db.tls_ca.as_deref(), Some("/etc/ca.pem"));
+    }
+
+    #[test]
+    fn rejects_bad_ports() {
+        assert!(parse_with("[database]\nport = 0\n").is_err());
+        assert!(parse_with("[database]\nport = 65536\n").is_err());
+        assert!(parse_with("[database]\nport = \"3306\"\n").is_err());
+    }
+
+    #[test]
+    fn reads_password_from_file() {
+        let path = std::env::temp_dir().join(format!("websubmit-password-{}", std::process::id()));
+        fs::write(&path, "hunter2\n").unwrap();
+        let config = parse_with(&format!(
+            "[database]\npassword = \"ignored\"\npassword_file = {:?}\n",
+            path.to_str().unwrap()
+        ))
+        .unwrap();
+        let password = config.database.password();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(password.unwrap().as_deref(), Some("hunter2"));
+    }
+
+    #[test]
+    fn reads_pool_settings() {
+        let db = Config::for_tests().database;
+        assert_eq!((db.pool_min, db.pool_max, db.stmt_cache_size), (10, 100, 32));
+        let config = parse_with("[database]\npool_min = 1\npool_max = 4\nstmt_cache_size = 0\n")
+            .unwrap();
+        let db = config.database;
+        assert_eq!((db.pool_min, db.pool_max, db.stmt_cache_size), (1, 4, 0));
+        assert!(parse_with("[database]\npool_max = -1\n").is_err());
+    }
+}
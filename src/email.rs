@@ -1,14 +1,16 @@
 //use lettre::sendmail::SendmailTransport;
-//use lettre::Transport;
+use lettre::{FileTransport, Transport};
 use lettre_email::Email;
 
+/// Sends an email, or writes it to a file in `mail_dir` if one is given.
 pub(crate) fn send(
     log: slog::Logger,
+    mail_dir: Option<&str>,
     sender: String,
     recipients: Vec<String>,
     subject: String,
     text: String,
-) -> Result<(), lettre::sendmail::error::Error> {
+) -> Result<(), Box<dyn std::error::Error>> {
 
     //let mut mailer = SendmailTransport::new();
 
@@ -16,12 +18,22 @@ pub(crate) fn send(
             .from(sender.clone())
             .subject(subject.clone())
             .text(text.clone());
+    // not the text, which holds login links and API keys
+    debug!(log, "Email to {}: {}", recipients.join(", "), subject);
+
     for recipient in recipients {
         builder = builder.to(recipient);
     }
-    
-    debug!(log, "Email: Subject {}\nText: {}!", subject, text);
-    
+
+    if let Some(dir) = mail_dir {
+        let mut mailer = FileTransport::new(dir);
+        match builder.build() {
+            Ok(result) => mailer.send(result.into())?,
+            Err(e) => error!(log, "couldn't construct email: {}", e),
+        }
+        return Ok(());
+    }
+
     /*let email = builder.build();
     match email {
         Ok(result) => mailer.send(result.into())?,
@@ -1,12 +1,349 @@
+use crate::admin::User;
+use crate::apikey::{self, ApiKey};
+use crate::backend::{self, AsyncBackend, Backend, Value};
 use crate::config::Config;
+use crate::email;
+use crate::ratelimit::{Action, Rejection, Target, Throttle};
+use crate::roster;
+use crate::session::{self, SESSION_COOKIE};
+use crate::templates;
+use chrono::naive::NaiveDateTime;
+use chrono::{Duration, Local};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rocket::form::Form;
+use rocket::http::{Cookie, CookieJar};
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
 use rocket::State;
 use rocket_dyn_templates::Template;
 use std::collections::HashMap;
 
 #[get("/")]
-pub(crate) fn login(config: &State<Config>) -> Template {
+pub(crate) fn login(flash: Option<FlashMessage<'_>>, config: &State<Config>) -> Template {
     let mut ctx = HashMap::new();
     ctx.insert("CLASS_ID", config.class.clone());
-    ctx.insert("parent", String::from("layout"));
-    Template::render("login", &ctx)
+    if let Some(flash) = flash {
+        ctx.insert("flash", flash.message().to_string());
+    }
+    ctx.insert("parent", String::from(templates::LAYOUT));
+    Template::render(templates::LOGIN, &ctx)
+}
+
+/// Ends the session, so that its cookie can no longer be used to log in.
+#[post("/logout")]
+pub(crate) async fn logout(
+    apikey: ApiKey,
+    cookies: &CookieJar<'_>,
+    backend: &State<AsyncBackend>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let id = apikey.session.clone();
+    backend
+        .acting_as(&apikey.user)
+        .run(move |be| session::end(be, &id))
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to("/leclist")))?;
+    cookies.remove(Cookie::named(SESSION_COOKIE));
+    Ok(Flash::success(Redirect::to("/login"), "You have been logged out."))
+}
+
+/// Random bytes in a login link's token.
+const LINK_TOKEN_BYTES: usize = 32;
+
+#[derive(Debug, FromForm)]
+pub(crate) struct LoginLinkRequest {
+    email: String,
+}
+
+#[derive(Debug, FromForm)]
+pub(crate) struct LoginLinkSubmit {
+    token: String,
+    sig: String,
+}
+
+/// A row of the `login_links` table.
+pub(crate) struct LoginLinkRow {
+    pub id: String,
+    pub email: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+crate::from_row!(LoginLinkRow {
+    id: "id",
+    email: "email",
+    created_at: "created_at",
+    expires_at: "expires_at",
+    used_at: "used_at",
+});
+
+/// The id a login link is stored under, so that the table cannot be used to
+/// log in.
+fn link_id(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(token);
+    hasher.result_str()
+}
+
+/// The signature of a login link's token, so that links not sent by this
+/// server are turned away without looking them up.
+fn link_signature(secret: &str, token: &str) -> String {
+    let mut mac = Hmac::new(Sha256::new(), secret.as_bytes());
+    mac.input(token.as_bytes());
+    mac.result()
+        .code()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn signature_valid(config: &Config, token: &str, sig: &str) -> bool {
+    fixed_time_eq(link_signature(&config.secret, token).as_bytes(), sig.as_bytes())
+}
+
+/// Emails a one-time login link to a registered address. The response is the
+/// same whether or not the address is registered.
+#[post("/link", data = "<data>")]
+pub(crate) async fn send_link(
+    data: Form<LoginLinkRequest>,
+    throttle: Throttle<'_>,
+    backend: &State<AsyncBackend>,
+    config: &State<Config>,
+) -> Result<Flash<Redirect>, Rejection> {
+    let email = roster::normalize(&data.email);
+    throttle.check(Action::Generate, Some(Target::Address(&email)))?;
+    let minutes = config.login_link_minutes;
+    let addr = email.clone();
+    let token = backend
+        .acting_as(&email)
+        .with_transaction(move |be| {
+            if be
+                .query_as::<User>("user_by_email", vec![addr.as_str().into()])?
+                .is_empty()
+            {
+                return Ok(None);
+            }
+            let token = apikey::random_hex(&mut rand::thread_rng(), LINK_TOKEN_BYTES);
+            let now = Local::now().naive_local();
+            be.insert(
+                "login_links",
+                vec![
+                    link_id(&token).into(),
+                    addr.into(),
+                    now.into(),
+                    (now + Duration::minutes(minutes as i64)).into(),
+                    Value::NULL,
+                ],
+            )?;
+            Ok(Some(token))
+        })
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to("/login")))?;
+
+    match token {
+        Some(token) if config.send_emails => {
+            let url = format!(
+                "{}/login/link?token={}&sig={}",
+                config.base_url,
+                token,
+                link_signature(&config.secret, &token)
+            );
+            let res = email::send(
+                backend.log().clone(),
+                config.mail_dir.as_deref(),
+                "no-reply@csci2390-submit.cs.brown.edu".into(),
+                vec![email.clone()],
+                format!("{} login link", config.class),
+                format!(
+                    "To log in to the {} submission system, open this link within {} \
+                     minutes:\n\n{}\n\nThe link can only be used once. If you did not ask \
+                     to log in, you can ignore this email.\n",
+                    config.class, minutes, url,
+                ),
+            );
+            if let Err(e) = res {
+                error!(backend.log(), "failed to send login link to {}: {}", email, e);
+                return Err(Flash::error(
+                    Redirect::to("/login"),
+                    "The login link could not be sent. Please try again later.",
+                )
+                .into());
+            }
+        }
+        Some(_) => {}
+        None => info!(backend.log(), "login link requested for unknown address {}", email),
+    }
+    Ok(Flash::success(
+        Redirect::to("/login"),
+        format!(
+            "If {} is registered, a login link has been sent to it. \
+             It can be used once, within {} minutes.",
+            email, minutes
+        ),
+    ))
+}
+
+/// Asks the user to confirm a login link, so that mail scanners that fetch
+/// the links in emails do not use it up.
+#[get("/link?<token>&<sig>")]
+pub(crate) fn link(
+    token: String,
+    sig: String,
+    config: &State<Config>,
+) -> Result<Template, Flash<Redirect>> {
+    if !signature_valid(config, &token, &sig) {
+        return Err(Flash::error(Redirect::to("/login"), "Invalid login link."));
+    }
+    let mut ctx = HashMap::new();
+    ctx.insert("CLASS_ID", config.class.clone());
+    ctx.insert("token", token);
+    ctx.insert("sig", sig);
+    ctx.insert("parent", String::from(templates::LAYOUT));
+    Ok(Template::render(templates::LOGIN_LINK, &ctx))
+}
+
+/// Uses up the login link with id `id` if it is still valid at time `now`,
+/// and starts a session for its address. Returns the address and the token
+/// of the session, or `None` if the link cannot be used.
+fn claim_link(
+    be: &dyn Backend,
+    id: &str,
+    now: NaiveDateTime,
+) -> backend::Result<Option<(String, String)>> {
+    let link = match be
+        .query_as::<LoginLinkRow>("login_link_by_id", vec![id.into()])?
+        .pop()
+    {
+        Some(l) if l.expires_at > now => l,
+        _ => return Ok(None),
+    };
+    // only one of several concurrent requests with the link claims it
+    let claimed = be.exec_affected(
+        "UPDATE login_links SET used_at = ? WHERE id = ? AND used_at IS NULL;",
+        vec![now.into(), id.into()],
+    )?;
+    if claimed != 1 {
+        return Ok(None);
+    }
+    let token = session::create(be, &link.email, None)?;
+    Ok(Some((link.email, token)))
+}
+
+/// Uses up a login link and starts a session for its address, without an
+/// API key.
+#[post("/link/use", data = "<data>")]
+pub(crate) async fn use_link(
+    data: Form<LoginLinkSubmit>,
+    throttle: Throttle<'_>,
+    cookies: &CookieJar<'_>,
+    backend: &State<AsyncBackend>,
+    config: &State<Config>,
+) -> Result<Redirect, Rejection> {
+    let id = link_id(&data.token);
+    throttle.check(Action::Check, Some(Target::Link(&id)))?;
+    let invalid = || {
+        Flash::error(
+            Redirect::to("/login"),
+            "This login link is invalid, has expired or has already been used.",
+        )
+    };
+    if !signature_valid(config, &data.token, &data.sig) {
+        warn!(backend.log(), "login with badly signed link");
+        return Err(invalid().into());
+    }
+
+    let res = backend
+        .with_transaction(move |be| claim_link(be, &id, Local::now().naive_local()))
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to("/login")))?;
+
+    match res {
+        Some((email, token)) => {
+            info!(backend.log(), "{} logged in with a login link", email);
+            cookies.add(session::cookie(config, token));
+            Ok(Redirect::to("/leclist"))
+        }
+        None => Err(invalid().into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SqliteBackend;
+
+    fn at(minutes: i64) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str("2021-01-01 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap()
+            + Duration::minutes(minutes)
+    }
+
+    /// A backend with a login link for a@example.com with token "token",
+    /// sent at `at(0)` and valid for 15 minutes.
+    fn backend() -> SqliteBackend {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        let link = vec![
+            link_id("token").into(),
+            "a@example.com".into(),
+            at(0).into(),
+            at(15).into(),
+            Value::NULL,
+        ];
+        be.insert("login_links", link).unwrap();
+        be
+    }
+
+    fn sessions(be: &dyn Backend) -> usize {
+        be.query_raw("SELECT * FROM sessions;", vec![]).unwrap().len()
+    }
+
+    #[test]
+    fn links_are_stored_under_their_hash() {
+        let id = link_id("token");
+        assert_ne!(id, "token");
+        assert_eq!(id, link_id("token"));
+        assert_ne!(id, link_id("other"));
+    }
+
+    #[test]
+    fn only_signed_links_are_valid() {
+        let config = Config::for_tests();
+        let sig = link_signature(&config.secret, "token");
+        assert!(signature_valid(&config, "token", &sig));
+        assert!(!signature_valid(&config, "other", &sig));
+        assert!(!signature_valid(&config, "token", &sig[1..]));
+        assert!(!signature_valid(&config, "token", ""));
+        assert!(!signature_valid(&config, "token", &link_signature("other", "token")));
+    }
+
+    #[test]
+    fn links_can_be_used_once() {
+        let be = backend();
+        let db: &dyn Backend = &be;
+        let (email, token) = claim_link(db, &link_id("token"), at(1)).unwrap().unwrap();
+        assert_eq!(email, "a@example.com");
+        assert!(!token.is_empty());
+        assert_eq!(sessions(db), 1);
+        assert!(claim_link(db, &link_id("token"), at(2)).unwrap().is_none());
+        assert_eq!(sessions(db), 1);
+        let link = db
+            .query_as::<LoginLinkRow>("login_link_by_id", vec![link_id("token").into()])
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!(link.used_at, Some(at(1)));
+    }
+
+    #[test]
+    fn expired_and_unknown_links_are_refused() {
+        let be = backend();
+        assert!(claim_link(&be, &link_id("token"), at(15)).unwrap().is_none());
+        assert!(claim_link(&be, &link_id("other"), at(1)).unwrap().is_none());
+        assert!(claim_link(&be, "token", at(1)).unwrap().is_none());
+        assert_eq!(sessions(&be), 0);
+    }
 }
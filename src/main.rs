@@ -15,18 +15,23 @@ extern crate beaver_derive;
 
 mod admin;
 mod apikey;
+mod archive;
 mod args;
 mod backend;
+mod check;
 mod config;
 mod email;
 mod login;
+mod migrations;
 mod questions;
+mod ratelimit;
+mod roster;
+mod session;
+mod templates;
 
-use backend::MySqlBackend;
+use backend::{check_columns, AsyncBackend, Backend};
 //use rocket::fs::FileServer;
-use rocket::http::CookieJar;
 use rocket::response::Redirect;
-use rocket::State;
 use rocket_dyn_templates::Template;
 use std::sync::{Arc, Mutex};
 
@@ -37,17 +42,33 @@ pub fn new_logger() -> slog::Logger {
     Logger::root(Mutex::new(term_full()).fuse(), o!())
 }
 
+/// Checks that every named query returns the columns its row type reads, so
+/// that schema mistakes surface at startup rather than on first request.
+fn check_row_types(be: &dyn Backend) -> backend::Result<()> {
+    check_columns::<questions::LectureListRow>(be, "leclist")?;
+    check_columns::<questions::QuestionRow>(be, "qs_by_lec")?;
+    check_columns::<questions::LectureAnswer>(be, "answers_by_lec")?;
+    check_columns::<questions::AnswerRow>(be, "my_answers_for_lec")?;
+    check_columns::<apikey::KeyCandidate>(be, "apikeys_by_prefix")?;
+    check_columns::<admin::User>(be, "user_by_email")?;
+    check_columns::<admin::UserListRow>(be, "all_users")?;
+    check_columns::<apikey::KeyRow>(be, "apikeys_by_email")?;
+    check_columns::<session::SessionRow>(be, "session_by_id")?;
+    check_columns::<session::SessionRow>(be, "active_sessions")?;
+    check_columns::<session::SessionRow>(be, "sessions_by_email")?;
+    check_columns::<login::LoginLinkRow>(be, "login_link_by_id")?;
+    check_columns::<admin::Lecture>(be, "lecture")?;
+    check_columns::<admin::AuditEntry>(be, "audit_log")?;
+    check_columns::<roster::RosterEntry>(be, "roster_by_email")?;
+    check_columns::<roster::RosterStatusRow>(be, "roster_status")?;
+    Ok(())
+}
+
 #[get("/")]
-fn index(cookies: &CookieJar<'_>, backend: &State<Arc<Mutex<MySqlBackend>>>) -> Redirect {
-    if let Some(cookie) = cookies.get("apikey") {
-        let apikey: String = cookie.value().parse().ok().unwrap();
-        // TODO validate API key
-        match apikey::check_api_key(&*backend, &apikey) {
-            Ok(_user) => Redirect::to("/leclist"),
-            Err(_) => Redirect::to("/login"),
-        }
-    } else {
-        Redirect::to("/login")
+async fn index(apikey: Option<apikey::ApiKey>) -> Redirect {
+    match apikey {
+        Some(_) => Redirect::to("/leclist"),
+        None => Redirect::to("/login"),
     }
 }
 
@@ -56,37 +77,146 @@ async fn main() {
     let args = args::parse_args();
     let config = args.config;
 
-    let backend = Arc::new(Mutex::new(
-        MySqlBackend::new(&format!("{}", args.class), Some(new_logger()), config.prime).unwrap(),
-    ));
+    match args.command {
+        args::Command::Check => {
+            let report = check::run(config.schema_path.as_deref(), &config.template_dir);
+            report.print();
+            std::process::exit(if report.is_ok() { 0 } else { 1 });
+        }
+        args::Command::Serve => {
+            let report = check::run(config.schema_path.as_deref(), &config.template_dir);
+            report.print();
+            if !report.is_ok() {
+                eprintln!("Refusing to start: self-check failed");
+                std::process::exit(1);
+            }
+        }
+        args::Command::Migrate(_) | args::Command::Export(_) | args::Command::Import(_) => (),
+    }
+
+    let backend: Arc<dyn Backend> =
+        match backend::open(&config, &format!("{}", args.class), Some(new_logger())) {
+            Ok(backend) => backend.into(),
+            Err(e) => {
+                eprintln!("Failed to connect to the database: {}", e);
+                std::process::exit(1);
+            }
+        };
+
+    match args.command {
+        args::Command::Migrate(cmd) => {
+            let res = match cmd {
+                args::MigrateCommand::Up(target) => migrations::up(&*backend, target),
+                args::MigrateCommand::Down(target) => {
+                    migrations::current_version(&*backend).and_then(|current| {
+                        migrations::down(&*backend, target.unwrap_or(current.saturating_sub(1)))
+                    })
+                }
+                args::MigrateCommand::Status => migrations::status(&*backend),
+            };
+            if let Err(e) = res {
+                eprintln!("Migration failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        args::Command::Export(path) => {
+            match archive::export(&*backend, &path) {
+                Ok(n) => println!("Exported {} row(s) to {}", n, path.display()),
+                Err(e) => {
+                    eprintln!("Export failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        args::Command::Import(path) => {
+            if let Err(e) = migrations::check_version(&*backend) {
+                eprintln!("Refusing to import: {}", e);
+                std::process::exit(1);
+            }
+            match archive::import(&*backend, &path) {
+                Ok(n) => println!("Imported {} row(s) from {}", n, path.display()),
+                Err(e) => {
+                    eprintln!("Import failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        args::Command::Serve => {
+            if let Err(e) = migrations::check_version(&*backend) {
+                eprintln!("Refusing to start: {}", e);
+                std::process::exit(1);
+            }
+            if let Err(e) = check_row_types(&*backend) {
+                eprintln!("Refusing to start: {}", e);
+                std::process::exit(1);
+            }
+        }
+        args::Command::Check => unreachable!(),
+    }
+    let backend = AsyncBackend::new(backend);
 
-    //let template_dir = config.template_dir.clone();
     //let resource_dir = config.resource_dir.clone();
 
-    if let Err(e) = rocket::build()
+    // templates are served from the directory the self-check looked at
+    let figment = rocket::Config::figment().merge(("template_dir", config.template_dir.clone()));
+    if let Err(e) = rocket::custom(figment)
         .attach(Template::fairing())
         .manage(backend)
         .manage(config)
+        .manage(ratelimit::RateLimiter::new())
         //.mount("/css", FileServer::from(format!("{}/css", resource_dir)))
         //.mount("/js", FileServer::from(format!("{}/js", resource_dir)))
-        .mount("/", routes![index])
+        .mount("/", routes![index, login::logout])
         .mount(
             "/questions",
             routes![questions::questions, questions::questions_submit],
         )
         .mount("/apikey/check", routes![apikey::check])
         .mount("/apikey/generate", routes![apikey::generate])
+        .mount("/apikey", routes![apikey::keys, apikey::rotate, apikey::revoke])
         .mount("/answers", routes![questions::answers])
         .mount("/leclist", routes![questions::leclist])
-        .mount("/login", routes![login::login])
+        .mount(
+            "/login",
+            routes![login::login, login::send_link, login::link, login::use_link],
+        )
         .mount(
             "/admin/lec/add",
             routes![admin::lec_add, admin::lec_add_submit],
         )
-        .mount("/admin/users", routes![admin::get_registered_users])
+        .mount(
+            "/admin/users",
+            routes![
+                admin::get_registered_users,
+                admin::delete_user,
+                admin::revoke_keys
+            ],
+        )
+        .mount("/admin/stats", routes![admin::query_stats])
+        .mount("/admin/audit", routes![admin::audit_log])
+        .mount("/admin/roster", routes![admin::roster, admin::upload_roster])
+        .mount(
+            "/admin/sessions",
+            routes![
+                admin::sessions,
+                admin::end_session,
+                admin::end_user_sessions
+            ],
+        )
         .mount(
             "/admin/lec",
-            routes![admin::lec, admin::addq, admin::editq, admin::editq_submit],
+            routes![
+                admin::lec,
+                admin::addq,
+                admin::editq,
+                admin::editq_submit,
+                admin::lec_delete,
+                admin::lec_delete_submit,
+                admin::delq
+            ],
         )
         .launch()
         .await
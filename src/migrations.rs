@@ -0,0 +1,497 @@
+use crate::apikey;
+use crate::backend::{
+    policy_id, redact_recorded, split_statements, upgrade_policy, Backend, BackendError, Result,
+    Value,
+};
+use chrono::naive::NaiveDateTime;
+use chrono::Local;
+use mysql::{from_value, from_value_opt};
+use std::collections::HashMap;
+
+/// A numbered schema migration, embedded from `migrations/`.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    up: &'static str,
+    down: &'static str,
+    /// Converts existing data after the `up` statements have run, for
+    /// changes that cannot be expressed in portable SQL
+    convert: Option<fn(&dyn Backend) -> Result<()>>,
+}
+
+macro_rules! migration {
+    ($version:expr, $name:expr, $file:expr) => {
+        migration!($version, $name, $file, None)
+    };
+    ($version:expr, $name:expr, $file:expr, $convert:expr) => {
+        Migration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("../migrations/", $file, ".up.sql")),
+            down: include_str!(concat!("../migrations/", $file, ".down.sql")),
+            convert: $convert,
+        }
+    };
+}
+
+/// All migrations, in order. The last one is the schema version this binary
+/// expects.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "initial", "0001_initial"),
+    migration!(2, "policies", "0002_policies", Some(convert_policies)),
+    migration!(3, "audit", "0003_audit"),
+    migration!(4, "apikeys", "0004_apikeys"),
+    migration!(5, "hashed_keys", "0005_hashed_keys", Some(convert_hashed_keys)),
+    migration!(6, "sessions", "0006_sessions"),
+    migration!(7, "login_links", "0007_login_links"),
+    migration!(8, "roster", "0008_roster"),
+];
+
+/// Moves the policies stored inline in `users` and `answers` into the
+/// `policies` table, replacing them with their ids. They are stored in the
+/// current format, so that they share ids with policies written later. Rows
+/// without a policy keep a NULL one, and are logged.
+fn convert_policies(backend: &dyn Backend) -> Result<()> {
+    let mut ids = HashMap::new();
+    for table in &["users", "answers"] {
+        let sql = format!("SELECT DISTINCT policy FROM {};", table);
+        for r in backend.query_raw(&sql, vec![])? {
+            let json = match from_value_opt::<Option<String>>(r[0].clone()) {
+                Ok(Some(json)) => json,
+                Ok(None) => {
+                    warn!(
+                        backend.log(),
+                        "rows of {} without a policy are left as they are", table
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    return Err(BackendError::Database(format!(
+                        "{} has a policy that is not text: {}",
+                        table, e
+                    )))
+                }
+            };
+            let stored = upgrade_policy(&json)?;
+            let id = policy_id(&stored);
+            backend.exec_raw(
+                &format!("UPDATE {} SET policy = ? WHERE policy = ?;", table),
+                vec![id.as_str().into(), json.as_str().into()],
+            )?;
+            ids.insert(id, stored);
+        }
+    }
+    for (id, json) in ids {
+        backend.exec_raw(
+            "INSERT INTO policies VALUES (?, ?);",
+            vec![id.into(), json.into()],
+        )?;
+    }
+    Ok(())
+}
+
+/// Moves the API keys into `apikeys_hashed`, storing each as its prefix and
+/// a salted hash, and makes that the `apikeys` table. The plaintext keys
+/// recorded in the audit log so far are redacted.
+fn convert_hashed_keys(backend: &dyn Backend) -> Result<()> {
+    let keys = backend.query_raw(
+        "SELECT apikey, email, created_at, revoked_at FROM apikeys;",
+        vec![],
+    )?;
+    for r in keys {
+        let key: String = from_value(r[0].clone());
+        let (hash, salt) = apikey::hash_new_key(&key);
+        backend.exec_raw(
+            "INSERT INTO apikeys_hashed VALUES (?, ?, ?, ?, ?, ?);",
+            vec![
+                apikey::key_prefix(&key).into(),
+                hash.into(),
+                salt.into(),
+                r[1].clone(),
+                r[2].clone(),
+                r[3].clone(),
+            ],
+        )?;
+    }
+    backend.exec_raw("DROP TABLE apikeys;", vec![])?;
+    backend.exec_raw("ALTER TABLE apikeys_hashed RENAME TO apikeys;", vec![])?;
+
+    let entries = backend.query_raw(
+        "SELECT id, row_key, old_values, new_values FROM audit_log WHERE tbl IN (?, ?);",
+        vec!["users".into(), "apikeys".into()],
+    )?;
+    for r in entries {
+        let mut vals: Vec<Value> = r[1..]
+            .iter()
+            .map(|v| {
+                from_value::<Option<String>>(v.clone())
+                    .map(|json| redact_recorded(&json, &["apikey"]))
+                    .into()
+            })
+            .collect();
+        vals.push(r[0].clone());
+        backend.exec_raw(
+            "UPDATE audit_log SET row_key = ?, old_values = ?, new_values = ? WHERE id = ?;",
+            vals,
+        )?;
+    }
+    Ok(())
+}
+
+/// Tables created by the initial migration. Databases set up before there
+/// were migrations (with the old `prime` flag) have these, but no
+/// `schema_version` table.
+const INITIAL_TABLES: &[&str] = &["users", "lectures", "questions", "answers"];
+
+pub fn expected_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+fn ensure_version_table(backend: &dyn Backend) -> Result<()> {
+    backend.exec_raw(
+        "CREATE TABLE IF NOT EXISTS schema_version (version int, name varchar(255), applied_at datetime, PRIMARY KEY (version));",
+        vec![],
+    )
+}
+
+/// Returns the applied migrations as (version, time applied).
+fn applied(backend: &dyn Backend) -> Result<Vec<(u32, Option<NaiveDateTime>)>> {
+    ensure_version_table(backend)?;
+    Ok(backend
+        .query_raw(
+            "SELECT version, applied_at FROM schema_version ORDER BY version;",
+            vec![],
+        )?
+        .into_iter()
+        .map(|r| {
+            let applied_at = if let Value::Date(..) = r[1] {
+                Some(from_value::<NaiveDateTime>(r[1].clone()))
+            } else {
+                None
+            };
+            (from_value(r[0].clone()), applied_at)
+        })
+        .collect())
+}
+
+/// Returns the schema version of the database (0 for an empty database).
+pub fn current_version(backend: &dyn Backend) -> Result<u32> {
+    Ok(applied(backend)?.last().map(|(v, _)| *v).unwrap_or(0))
+}
+
+/// Records the initial migration as applied if the database already has its
+/// tables, and returns whether it did.
+fn adopt_existing(backend: &dyn Backend) -> Result<bool> {
+    let existing: Vec<&str> = INITIAL_TABLES
+        .iter()
+        .filter(|t| {
+            backend
+                .query_raw(&format!("SELECT COUNT(*) FROM {};", t), vec![])
+                .is_ok()
+        })
+        .cloned()
+        .collect();
+    if existing.is_empty() {
+        return Ok(false);
+    }
+    if existing.len() < INITIAL_TABLES.len() {
+        return Err(BackendError::Database(format!(
+            "database has no schema version, but some of the initial tables ({}); \
+             it was not created by this application or a previous version of it",
+            existing.join(", ")
+        )));
+    }
+    let m = &MIGRATIONS[0];
+    info!(
+        backend.log(),
+        "Database predates migrations; recording migration {} ({}) as applied", m.version, m.name
+    );
+    backend.exec_raw(
+        "INSERT INTO schema_version VALUES (?, ?, ?);",
+        vec![
+            m.version.into(),
+            m.name.into(),
+            Local::now().naive_local().into(),
+        ],
+    )?;
+    Ok(true)
+}
+
+/// Splits a migration file into its statements.
+fn statements(m: &Migration, sql: &str) -> Result<Vec<String>> {
+    Ok(split_statements(sql)
+        .map_err(|e| {
+            BackendError::Database(format!("migration {} ({}): {}", m.version, m.name, e))
+        })?
+        .into_iter()
+        .map(|s| s.text)
+        .collect())
+}
+
+/// Applies all migrations up to and including version `target` (or all of
+/// them, if no target is given). A database created before there were
+/// migrations is first marked as being at version 1.
+pub fn up(backend: &dyn Backend, target: Option<u32>) -> Result<()> {
+    let mut current = current_version(backend)?;
+    if current == 0 && adopt_existing(backend)? {
+        current = 1;
+    }
+    let target = target.unwrap_or(expected_version());
+    for m in MIGRATIONS
+        .iter()
+        .filter(|m| m.version > current && m.version <= target)
+    {
+        info!(backend.log(), "Applying migration {} ({})", m.version, m.name);
+        // atomic on SQLite; MySQL commits after each DDL statement
+        backend.with_transaction(|be| {
+            for stmt in statements(m, m.up)? {
+                be.exec_raw(&stmt, vec![])?;
+            }
+            if let Some(convert) = m.convert {
+                convert(be)?;
+            }
+            be.exec_raw(
+                "INSERT INTO schema_version VALUES (?, ?, ?);",
+                vec![
+                    m.version.into(),
+                    m.name.into(),
+                    Local::now().naive_local().into(),
+                ],
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Reverts migrations until the database is at version `target`.
+pub fn down(backend: &dyn Backend, target: u32) -> Result<()> {
+    let current = current_version(backend)?;
+    for m in MIGRATIONS
+        .iter()
+        .rev()
+        .filter(|m| m.version <= current && m.version > target)
+    {
+        info!(backend.log(), "Reverting migration {} ({})", m.version, m.name);
+        backend.with_transaction(|be| {
+            for stmt in statements(m, m.down)? {
+                be.exec_raw(&stmt, vec![])?;
+            }
+            be.exec_raw(
+                "DELETE FROM schema_version WHERE version = ?;",
+                vec![m.version.into()],
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Prints each known migration and whether it has been applied.
+pub fn status(backend: &dyn Backend) -> Result<()> {
+    let applied = applied(backend)?;
+    for m in MIGRATIONS {
+        match applied.iter().find(|(v, _)| *v == m.version) {
+            Some((_, Some(t))) => println!("{:04} {:<20} applied {}", m.version, m.name, t),
+            Some((_, None)) => println!("{:04} {:<20} applied", m.version, m.name),
+            None => println!("{:04} {:<20} pending", m.version, m.name),
+        }
+    }
+    println!(
+        "database is at version {}, binary expects version {}",
+        current_version(backend)?,
+        expected_version()
+    );
+    Ok(())
+}
+
+/// Checks that the database is at the schema version this binary expects.
+pub fn check_version(backend: &dyn Backend) -> std::result::Result<(), String> {
+    let current = current_version(backend).map_err(|e| e.to_string())?;
+    let expected = expected_version();
+    if current < expected {
+        Err(format!(
+            "database schema is at version {}, but this binary expects version {}; run `websubmit migrate up`",
+            current, expected
+        ))
+    } else if current > expected {
+        Err(format!(
+            "database schema is at version {}, which is newer than version {} expected by this binary",
+            current, expected
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SqliteBackend;
+
+    fn count(be: &dyn Backend, table: &str) -> u64 {
+        let rows = be
+            .query_raw(&format!("SELECT COUNT(*) FROM {};", table), vec![])
+            .unwrap();
+        from_value(rows[0][0].clone())
+    }
+
+    #[test]
+    fn up_migrates_empty_database() {
+        let be = SqliteBackend::for_tests();
+        up(&be, None).unwrap();
+        assert_eq!(current_version(&be).unwrap(), expected_version());
+    }
+
+    #[test]
+    fn up_and_down_stop_at_target() {
+        let be = SqliteBackend::for_tests();
+        up(&be, Some(3)).unwrap();
+        assert_eq!(current_version(&be).unwrap(), 3);
+        assert!(check_version(&be).unwrap_err().contains("migrate up"));
+        up(&be, None).unwrap();
+        assert!(check_version(&be).is_ok());
+
+        be.exec_raw(
+            "INSERT INTO lectures VALUES (?, ?);",
+            vec![1.into(), "Introduction".into()],
+        )
+        .unwrap();
+        down(&be, 2).unwrap();
+        assert_eq!(current_version(&be).unwrap(), 2);
+        assert_eq!(count(&be, "lectures"), 1);
+        assert!(be.query_raw("SELECT * FROM audit_log;", vec![]).is_err());
+    }
+
+    #[test]
+    fn down_reverts_every_migration() {
+        let be = SqliteBackend::for_tests();
+        up(&be, None).unwrap();
+        down(&be, 0).unwrap();
+        assert_eq!(current_version(&be).unwrap(), 0);
+        for table in INITIAL_TABLES {
+            assert!(be
+                .query_raw(&format!("SELECT * FROM {};", table), vec![])
+                .is_err());
+        }
+        up(&be, None).unwrap();
+        assert_eq!(current_version(&be).unwrap(), expected_version());
+    }
+
+    #[test]
+    fn up_adopts_database_without_schema_version() {
+        let be = SqliteBackend::for_tests();
+        // as created by the old `prime` flag
+        for stmt in statements(&MIGRATIONS[0], MIGRATIONS[0].up).unwrap() {
+            be.exec_raw(&stmt, vec![]).unwrap();
+        }
+        be.exec_raw(
+            "INSERT INTO lectures VALUES (?, ?);",
+            vec![1.into(), "Introduction".into()],
+        )
+        .unwrap();
+
+        up(&be, None).unwrap();
+        assert_eq!(current_version(&be).unwrap(), expected_version());
+        assert_eq!(count(&be, "lectures"), 1);
+    }
+
+    #[test]
+    fn up_refuses_database_with_some_initial_tables() {
+        let be = SqliteBackend::for_tests();
+        be.exec_raw(
+            "CREATE TABLE lectures (id int, label varchar(255), PRIMARY KEY (id));",
+            vec![],
+        )
+        .unwrap();
+
+        assert!(up(&be, None).is_err());
+        assert_eq!(current_version(&be).unwrap(), 0);
+    }
+
+    #[test]
+    fn up_redacts_audited_keys() {
+        let be = SqliteBackend::for_tests();
+        up(&be, Some(4)).unwrap();
+        let key = "0123456789abcdefghij";
+        be.exec_raw(
+            "INSERT INTO apikeys VALUES (?, ?, NULL, NULL);",
+            vec![key.into(), "a@example.com".into()],
+        )
+        .unwrap();
+        let recorded = format!(r#"{{"apikey":"{}","email":"a@example.com"}}"#, key);
+        be.exec_raw(
+            "INSERT INTO audit_log VALUES (?, ?, ?, ?, ?, ?, NULL, ?, NULL);",
+            vec![
+                "1".into(),
+                "2021-01-01 00:00:00".into(),
+                "system".into(),
+                "insert".into(),
+                "apikeys".into(),
+                format!(r#"{{"apikey":"{}"}}"#, key).into(),
+                recorded.into(),
+            ],
+        )
+        .unwrap();
+
+        up(&be, None).unwrap();
+        let rows = be
+            .query_raw("SELECT row_key, new_values FROM audit_log;", vec![])
+            .unwrap();
+        let values: Vec<String> = rows[0].iter().map(|v| from_value(v.clone())).collect();
+        assert_eq!(
+            values,
+            vec![
+                r#"{"apikey":"[redacted]"}"#.to_string(),
+                r#"{"apikey":"[redacted]","email":"a@example.com"}"#.to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn up_keeps_rows_without_policy() {
+        let be = SqliteBackend::for_tests();
+        up(&be, Some(1)).unwrap();
+        be.exec_raw(
+            "INSERT INTO users VALUES (?, ?, ?, NULL);",
+            vec!["a@example.com".into(), "key".into(), 0.into()],
+        )
+        .unwrap();
+
+        up(&be, Some(2)).unwrap();
+        let rows = be.query_raw("SELECT policy FROM users;", vec![]).unwrap();
+        assert_eq!(rows, vec![vec![Value::NULL]]);
+        assert_eq!(count(&be, "policies"), 0);
+    }
+
+    #[test]
+    fn up_stores_inline_policies_in_current_format() {
+        let be = SqliteBackend::for_tests();
+        up(&be, Some(1)).unwrap();
+        // the same policy, as stored before and after versioning
+        let current = r#"{"version":1,"policy":{"AnswerPolicy":{"student_id":"a@example.com"}}}"#;
+        let inline = [
+            r#"{"AnswerPolicy":{"student_id":"a@example.com"}}"#,
+            current,
+        ];
+        for (q, json) in inline.iter().enumerate() {
+            be.exec_raw(
+                "INSERT INTO answers VALUES (?, ?, ?, ?, ?, ?);",
+                vec![
+                    "a@example.com".into(),
+                    1.into(),
+                    (q as u32).into(),
+                    "yes".into(),
+                    "2021-01-01 00:00:00".into(),
+                    (*json).into(),
+                ],
+            )
+            .unwrap();
+        }
+
+        up(&be, Some(2)).unwrap();
+        let rows = be
+            .query_raw("SELECT DISTINCT policy FROM answers;", vec![])
+            .unwrap();
+        assert_eq!(rows, vec![vec![Value::from(policy_id(current))]]);
+        assert_eq!(count(&be, "policies"), 1);
+    }
+}
@@ -1,16 +1,17 @@
 use crate::admin::Admin;
 use crate::apikey::ApiKey;
-use crate::backend::{MySqlBackend, Value};
+use crate::backend::{encode_policy, AsyncBackend, BackendError, Page, PolicyUpdate, Value};
 use crate::config::Config;
 use crate::email;
+use crate::ratelimit::{Action, Rejection, Target, Throttle};
+use crate::templates;
 use chrono::naive::NaiveDateTime;
 use chrono::Local;
-use mysql::from_value;
 use rocket::form::{Form, FromForm};
-use rocket::response::Redirect;
+use rocket::request::FlashMessage;
+use rocket::response::{Flash, Redirect};
 use rocket::State;
 use rocket_dyn_templates::Template;
-use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 
 extern crate beaver_derive;
@@ -19,6 +20,9 @@ use beaver::policy::PoliciedString;
 use beaver::policy::Policied;
 use beaver::generic_policied::*;
 
+/// Answers shown per page on the answers view.
+const ANSWERS_PER_PAGE: u64 = 50;
+
 //pub(crate) enum LectureQuestionFormError {
 //   Invalid,
 //}
@@ -84,17 +88,47 @@ impl GPoliciedLectureQuestionExt for GPolicied<LectureQuestion> {
 pub(crate) struct LectureQuestionsContext {
     pub lec_id: u8,
     pub questions: Vec<LectureQuestion>,
+    pub flash: Option<String>,
     pub parent: &'static str,
 }
 
 #[derive(Serialize, Clone, Deserialize)]
-struct LectureAnswer {
+pub(crate) struct LectureAnswer {
     id: u64,
     user: String,
     answer: String,
     time: Option<NaiveDateTime>,
 }
 
+crate::from_row!(LectureAnswer {
+    id: "q",
+    user: "email",
+    answer: "answer",
+    time: "submitted_at",
+});
+
+/// A row of the `questions` table.
+pub(crate) struct QuestionRow {
+    pub q: u64,
+    pub question: String,
+}
+
+crate::from_row!(QuestionRow {
+    q: "q",
+    question: "question",
+});
+
+/// A student's answer to one question, as stored in `answers`.
+pub(crate) struct AnswerRow {
+    q: u64,
+    answer: String,
+}
+
+crate::from_row!(AnswerRow {
+    q: "q",
+    answer: "answer",
+});
+
 trait GPoliciedLectureAnswerExt {
     fn get_id(&self) -> &u64;
     fn get_user(&self) -> &String;
@@ -121,6 +155,9 @@ impl GPoliciedLectureAnswerExt for GPolicied<LectureAnswer> {
 struct LectureAnswersContext {
     lec_id: u8,
     answers: Vec<LectureAnswer>,
+    page: u64,
+    prev_page: Option<u64>,
+    next_page: Option<u64>,
     parent: &'static str,
 }
 
@@ -132,22 +169,34 @@ struct LectureListEntry {
     num_answered: u64,
 }
 
+pub(crate) struct LectureListRow {
+    id: u64,
+    label: String,
+    qcount: Option<u64>,
+}
+
+crate::from_row!(LectureListRow {
+    id: "id",
+    label: "label",
+    qcount: "qcount",
+});
+
 #[derive(Serialize)]
 struct LectureListContext {
     admin: bool,
     lectures: Vec<LectureListEntry>,
+    flash: Option<String>,
     parent: &'static str,
 }
 
 #[get("/")]
-pub(crate) fn leclist(
+pub(crate) async fn leclist(
     apikey: ApiKey,
-    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    flash: Option<FlashMessage<'_>>,
+    backend: &State<AsyncBackend>,
     config: &State<Config>,
-) -> Template {
-    let mut bg = backend.lock().unwrap();
-    let res = bg.query_exec("leclist", vec![]);//vec![(0 as u64).into()]);
-    drop(bg);
+) -> Result<Template, BackendError> {
+    let res = backend.query_as::<LectureListRow>("leclist", vec![]).await?;
 
     let user = apikey.user.clone();
     let admin = config.admins.contains(&user);
@@ -155,13 +204,9 @@ pub(crate) fn leclist(
     let lecs: Vec<_> = res
         .into_iter()
         .map(|r| LectureListEntry {
-            id: from_value(r[0].clone()),
-            label: from_value(r[1].clone()),
-            num_qs: if r[2] == Value::NULL {
-                0u64
-            } else {
-                from_value(r[2].clone())
-            },
+            id: r.id,
+            label: r.label,
+            num_qs: r.qcount.unwrap_or(0),
             num_answered: 0u64,
         })
         .collect();
@@ -169,77 +214,73 @@ pub(crate) fn leclist(
     let ctx = LectureListContext {
         admin: admin,
         lectures: lecs,
-        parent: "layout",
+        flash: flash.map(|f| f.message().to_string()),
+        parent: templates::LAYOUT,
     };
 
-    Template::render("leclist", &ctx)
+    Ok(Template::render(templates::LECLIST, &ctx))
 }
 
-#[get("/<num>")]
-pub(crate) fn answers(
+#[get("/<num>?<page>")]
+pub(crate) async fn answers(
     _admin: Admin,
     num: u8,
-    backend: &State<Arc<Mutex<MySqlBackend>>>,
-) -> Template {
-    let mut bg = backend.lock().unwrap();
+    page: Option<u64>,
+    backend: &State<AsyncBackend>,
+) -> Result<Template, BackendError> {
     let key: Value = (num as u64).into();
-    let answers = bg.query_exec_policied("answers_by_lec", vec![key], 
-        |r| LectureAnswer {
-                id: from_value(r[2].clone()),
-                user: from_value(r[0].clone()),
-                answer: from_value(r[3].clone()),
-                time: if let Value::Time(..) = r[4] {
-                    Some(from_value::<NaiveDateTime>(r[4].clone()))
-                } else {
-                    None
-                },
-            }
-        );
-    drop(bg);
+    let page_num = page.unwrap_or(1).max(1);
+    let page = Page::number(page_num, ANSWERS_PER_PAGE);
+    let mut answers = backend
+        .query_page_as_policied::<LectureAnswer>("answers_by_lec", vec![key], page)
+        .await?;
+    let has_more = page.truncate(&mut answers);
 
     let ctx = LectureAnswersContext {
         lec_id: num,
         answers: answers.externalize_policy().export_check(&beaver::filter::Context::CustomContext(Box::new(_admin))).unwrap(),
-        parent: "layout",
+        page: page_num,
+        prev_page: if page_num > 1 { Some(page_num - 1) } else { None },
+        next_page: if has_more { Some(page_num + 1) } else { None },
+        parent: templates::LAYOUT,
     };
-    Template::render("answers", &ctx)
+    Ok(Template::render(templates::ANSWERS, &ctx))
 }
 
 #[get("/<num>")]
-pub(crate) fn questions(
+pub(crate) async fn questions(
     apikey: ApiKey,
     num: u8,
-    backend: &State<Arc<Mutex<MySqlBackend>>>,
-) -> Template {
+    flash: Option<FlashMessage<'_>>,
+    backend: &State<AsyncBackend>,
+) -> Result<Template, BackendError> {
     use std::collections::HashMap;
 
-    let mut bg = backend.lock().unwrap();
     let key: Value = (num as u64).into();
 
-    let answers_res = bg.query_exec_policied(
-        "my_answers_for_lec",
-        vec![(num as u64).into(), apikey.user.clone().into()],
-        |e| 
-            (from_value(e[2].clone()),
-            from_value(e[3].clone())),
-    );
+    let res = backend.query_as::<QuestionRow>("qs_by_lec", vec![key]).await?;
+    let answers_res = backend
+        .query_as_policied::<AnswerRow>(
+            "my_answers_for_lec",
+            vec![(num as u64).into(), apikey.user.clone().into()],
+        )
+        .await?;
     let mut answers = HashMap::new().policied();
 
     for r in answers_res {
-        answers.insert_kv(r);
+        // keyed by question number, keeping the row's policy
+        let (a, policy) = r.unsafe_decompose();
+        answers.insert_kv((a.q, a.answer).policied_with(policy));
     }
-    let res = bg.query_exec("qs_by_lec", vec![key]);
-    drop(bg);
     let mut qs: Vec<_> = res
         .into_iter()
         .map(|r| {
-            let id: u64 = from_value(r[1].clone());
-            let answer = answers.get(&id).map(|p| p.map(|s: &String| s.to_owned()));
+            let answer = answers.get(&r.q).map(|p| p.map(|s: &String| s.to_owned()));
             GPolicied::make_default(
                 |answer|
                     LectureQuestion {
-                        id: id,
-                        prompt: from_value(r[2].clone()),
+                        id: r.q,
+                        prompt: r.question.clone(),
                         answer: answer,
                     }
             ).apply(answer.externalize_policy())
@@ -250,48 +291,77 @@ pub(crate) fn questions(
     let ctx = LectureQuestionsContext {
         lec_id: num,
         questions: qs.externalize_policy().export_check(&kv_ctx!("user" => apikey.user.clone(), "method" => "website")).unwrap(),
-        parent: "layout",
+        flash: flash.map(|f| f.message().to_string()),
+        parent: templates::LAYOUT,
     };
-    Template::render("questions", &ctx)
+    Ok(Template::render(templates::QUESTIONS, &ctx))
 }
 
 #[post("/<num>", data = "<data>")]
-pub(crate) fn questions_submit(
+pub(crate) async fn questions_submit(
     apikey: ApiKey,
     num: u8,
     data: Form<LectureQuestionSubmission>,
-    backend: &State<Arc<Mutex<MySqlBackend>>>,
+    throttle: Throttle<'_>,
+    backend: &State<AsyncBackend>,
     config: &State<Config>,
-) -> Redirect {
-    let mut bg = backend.lock().unwrap();
+) -> Result<Redirect, Rejection> {
+    throttle.check(Action::Submit, Some(Target::Address(&apikey.user)))?;
     let vnum: Value = (num as u64).into();
     let ts: Value = Local::now().naive_local().into();
-    let data = data.policied_with(Box::new(AnswerPolicy { student_id: apikey.user.clone().into() }));
-    let answers : HashMap<u64, GPolicied<String>> = data.map(|d| d.into_inner().answers).internalize_policy_2_1();
-
-    for (id, answer) in &answers {
-        let (answer, policy) = answer.unsafe_borrow_decompose();
-        let rec: Vec<Value> = vec![
-            apikey.user.clone().into(),
-            vnum.clone(),
-            (*id).into(),
-            answer.clone().into(),
-            ts.clone(),
-        ];
-        bg.insert_or_update_policied(
-            "answers",
-            rec,
-            vec![(3, answer.clone().into()), (4, ts.clone())],
-            policy.as_ref()
-        );
-    }
 
-    let answer_log =
-        answers.iter()
-            .map(|(i, t)| t.as_ref().map(|t| format!("Question {}:\n{}", i, t)))
-            .collect::<Vec<_>>()
-            .externalize_policy()
-            .map(|v| v.join("\n-----\n"));
+    // policied values stay in this block, so that they are not held across
+    // the await below
+    let (recs, answer_log) = {
+        let data = data.policied_with(Box::new(AnswerPolicy { student_id: apikey.user.clone().into() }));
+        let answers : HashMap<u64, GPolicied<String>> = data.map(|d| d.into_inner().answers).internalize_policy_2_1();
+
+        let recs: Result<Vec<_>, BackendError> = answers.iter().map(|(id, answer)| {
+            let (answer, policy) = answer.unsafe_borrow_decompose();
+            let rec: Vec<Value> = vec![
+                apikey.user.clone().into(),
+                vnum.clone(),
+                (*id).into(),
+                answer.clone().into(),
+                ts.clone(),
+            ];
+            let update_vals = vec![(3, answer.clone().into()), (4, ts.clone())];
+            encode_policy(policy.as_ref()).map(|policy| (rec, update_vals, policy))
+        }).collect();
+
+        let answer_log =
+            answers.iter()
+                .map(|(i, t)| t.as_ref().map(|t| format!("Question {}:\n{}", i, t)))
+                .collect::<Vec<_>>()
+                .externalize_policy()
+                .map(|v| v.join("\n-----\n"))
+                .export_check(&kv_ctx!("method" => "email-notify", "role" => "staff"))
+                .unwrap();
+        (recs, answer_log)
+    };
+
+    let recs = recs
+        .map_err(|e| e.flash(backend.log(), Redirect::to(format!("/questions/{}", num))))?;
+
+    // either all answers are recorded, or none are
+    backend
+        .acting_as(&apikey.user)
+        .with_transaction(move |be| {
+            for (rec, update_vals, policy) in recs {
+                // a resubmitted answer belongs to the same student
+                be.insert_or_update_policied(
+                    "answers",
+                    rec,
+                    update_vals,
+                    policy,
+                    PolicyUpdate::Replace,
+                )?;
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|e| e.flash(backend.log(), Redirect::to(format!("/questions/{}", num))))?;
+
     if config.send_emails {
         let recipients = if num < 90 {
             config.staff.clone()
@@ -299,16 +369,23 @@ pub(crate) fn questions_submit(
             config.admins.clone()
         };
 
-        email::send(
-            bg.log.clone(),
+        let res = email::send(
+            backend.log().clone(),
+            config.mail_dir.as_deref(),
             apikey.user.clone(),
             recipients,
             format!("{} meeting {} questions", config.class, num),
-            answer_log.export_check(&kv_ctx!("method" => "email-notify", "role" => "staff")).unwrap(),
-        )
-        .expect("failed to send email");
+            answer_log,
+        );
+        if let Err(e) = res {
+            error!(backend.log(), "failed to send answers of {} to staff: {}", apikey.user, e);
+            return Err(Flash::error(
+                Redirect::to("/leclist"),
+                "Your answers were saved, but the staff could not be notified.",
+            )
+            .into());
+        }
     }
-    drop(bg);
 
-    Redirect::to("/leclist")
+    Ok(Redirect::to("/leclist"))
 }
@@ -0,0 +1,342 @@
+use crate::backend::AsyncBackend;
+use crate::config::{Config, RateLimit};
+use crate::roster;
+use crate::templates;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{Flash, Redirect};
+use rocket::State;
+use rocket_dyn_templates::Template;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Once there are more counters than this, the ones whose window has passed
+/// are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Most counters kept at once. Past this, the quarter of them whose windows
+/// end first are dropped, so that requests for ever new targets cannot grow
+/// the map without bound.
+const MAX_WINDOWS: usize = 100_000;
+
+/// The kinds of request that are rate limited, each with its own limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Action {
+    Generate,
+    Check,
+    Submit,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Generate => write!(f, "generate"),
+            Action::Check => write!(f, "check"),
+            Action::Submit => write!(f, "submit"),
+        }
+    }
+}
+
+/// What a request is for, besides the client making it. Requests are also
+/// counted per target, so that spreading them over many clients does not
+/// get around a limit.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Target<'a> {
+    /// The email address a key or link is requested for, or that answers
+    /// are submitted as
+    Address(&'a str),
+    /// The prefix of an API key someone tries to log in with
+    KeyPrefix(&'a str),
+    /// The id of a login link someone tries to log in with
+    Link(&'a str),
+}
+
+impl fmt::Display for Target<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Address(email) => write!(f, "address {}", roster::normalize(email)),
+            Target::KeyPrefix(prefix) => write!(f, "key {}", prefix),
+            Target::Link(id) => write!(f, "link {}", id),
+        }
+    }
+}
+
+/// Requests counted for one action and client or target until `ends`.
+struct Window {
+    ends: Instant,
+    count: u32,
+}
+
+/// Counts requests per action and client IP or target, in fixed windows.
+/// Counts are only kept in memory, so they start over when the server
+/// restarts.
+pub(crate) struct RateLimiter {
+    windows: Mutex<HashMap<(Action, String), Window>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter {
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request made at `now` for `action` against each of `keys`,
+    /// in order. Once one of them is over `limit`, returns how long until its
+    /// window ends, without counting the request against the keys after it;
+    /// the client's key comes first, so that a client that is locked out
+    /// cannot add counters for new targets.
+    fn hit(
+        &self,
+        log: &slog::Logger,
+        action: Action,
+        limit: RateLimit,
+        keys: Vec<String>,
+        now: Instant,
+    ) -> Option<Duration> {
+        if limit.max == 0 {
+            return None;
+        }
+        let len = Duration::from_secs(limit.window_minutes * 60);
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+        if windows.len() > PRUNE_THRESHOLD {
+            windows.retain(|_, w| w.ends > now);
+        }
+        if windows.len() >= MAX_WINDOWS {
+            let mut ends: Vec<Instant> = windows.values().map(|w| w.ends).collect();
+            let (_, cutoff, _) = ends.select_nth_unstable(MAX_WINDOWS / 4);
+            let cutoff = *cutoff;
+            windows.retain(|_, w| w.ends > cutoff);
+        }
+
+        for key in keys {
+            let w = windows.entry((action, key.clone())).or_insert(Window {
+                ends: now + len,
+                count: 0,
+            });
+            if w.ends <= now {
+                *w = Window {
+                    ends: now + len,
+                    count: 0,
+                };
+            }
+            w.count = w.count.saturating_add(1);
+            if w.count > limit.max {
+                let left = w.ends - now;
+                // log the lockout once, not every request made during it
+                if w.count == limit.max + 1 {
+                    warn!(
+                        log,
+                        "rate limit: {} locked out of {} for {}s after {} requests",
+                        key,
+                        action,
+                        left.as_secs(),
+                        limit.max
+                    );
+                }
+                return Some(left);
+            }
+        }
+        None
+    }
+}
+
+/// The response to a request that was turned away.
+#[derive(Responder)]
+pub(crate) enum Rejection {
+    Flash(Flash<Redirect>),
+    #[response(status = 403)]
+    Forbidden(Template),
+    #[response(status = 429)]
+    TooManyRequests(Template),
+}
+
+impl From<Flash<Redirect>> for Rejection {
+    fn from(f: Flash<Redirect>) -> Self {
+        Rejection::Flash(f)
+    }
+}
+
+/// Checks requests against the configured rate limits, by the client's IP
+/// address and what they are for. The client address is the peer's, or if
+/// the peer is a trusted proxy, the one in its `X-Real-IP` header.
+pub(crate) struct Throttle<'r> {
+    limiter: &'r RateLimiter,
+    config: &'r Config,
+    log: &'r slog::Logger,
+    ip: Option<IpAddr>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Throttle<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let limiter = request.guard::<&State<RateLimiter>>().await.unwrap();
+        let config = request.guard::<&State<Config>>().await.unwrap();
+        let backend = request.guard::<&State<AsyncBackend>>().await.unwrap();
+        let remote = request.remote().map(|a| a.ip());
+        let ip = match remote {
+            Some(proxy) if config.trusted_proxies.contains(&proxy) => {
+                request.real_ip().or(remote)
+            }
+            _ => remote,
+        };
+        Outcome::Success(Throttle {
+            limiter: limiter.inner(),
+            config: config.inner(),
+            log: backend.inner().log(),
+            ip: ip,
+        })
+    }
+}
+
+impl Throttle<'_> {
+    /// Counts a request for `action`, for `target` if given, and turns it
+    /// away with a 429 page if the client or the target is over its limit.
+    pub fn check(&self, action: Action, target: Option<Target>) -> Result<(), Rejection> {
+        let limits = &self.config.rate_limits;
+        let limit = match action {
+            Action::Generate => limits.generate,
+            Action::Check => limits.check,
+            Action::Submit => limits.submit,
+        };
+        let keys = self
+            .ip
+            .map(|ip| format!("client {}", ip))
+            .into_iter()
+            .chain(target.map(|t| t.to_string()))
+            .collect();
+        match self.limiter.hit(self.log, action, limit, keys, Instant::now()) {
+            None => Ok(()),
+            Some(wait) => {
+                let mut ctx = HashMap::new();
+                ctx.insert("CLASS_ID", self.config.class.clone());
+                // round up, so that "try again in 0 minutes" is never shown
+                ctx.insert("minutes", ((wait.as_secs() + 59) / 60).to_string());
+                ctx.insert("parent", String::from(templates::LAYOUT));
+                Err(Rejection::TooManyRequests(Template::render(
+                    templates::RATELIMITED,
+                    &ctx,
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log() -> slog::Logger {
+        slog::Logger::root(slog::Discard, o!())
+    }
+
+    fn limit(max: u32) -> RateLimit {
+        RateLimit {
+            max: max,
+            window_minutes: 1,
+        }
+    }
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|k| k.to_string()).collect()
+    }
+
+    #[test]
+    fn no_limit_if_max_is_zero() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(limiter.hit(&log(), Action::Check, limit(0), keys(&["c"]), now), None);
+        }
+        assert!(limiter.windows.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn locks_out_after_max_requests() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.hit(&log(), Action::Check, limit(3), keys(&["c"]), now), None);
+        }
+        let wait = limiter.hit(&log(), Action::Check, limit(3), keys(&["c"]), now);
+        assert_eq!(wait, Some(Duration::from_secs(60)));
+        // other actions are counted separately
+        assert_eq!(limiter.hit(&log(), Action::Submit, limit(3), keys(&["c"]), now), None);
+    }
+
+    #[test]
+    fn window_resets_after_it_ends() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        for _ in 0..2 {
+            limiter.hit(&log(), Action::Check, limit(1), keys(&["c"]), now);
+        }
+        let later = now + Duration::from_secs(30);
+        let wait = limiter.hit(&log(), Action::Check, limit(1), keys(&["c"]), later);
+        assert_eq!(wait, Some(Duration::from_secs(30)));
+        let next = now + Duration::from_secs(60);
+        assert_eq!(limiter.hit(&log(), Action::Check, limit(1), keys(&["c"]), next), None);
+    }
+
+    #[test]
+    fn target_is_limited_across_clients() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        assert_eq!(limiter.hit(&log(), Action::Check, limit(2), keys(&["a", "t"]), now), None);
+        assert_eq!(limiter.hit(&log(), Action::Check, limit(2), keys(&["b", "t"]), now), None);
+        assert!(limiter
+            .hit(&log(), Action::Check, limit(2), keys(&["c", "t"]), now)
+            .is_some());
+    }
+
+    #[test]
+    fn locked_out_client_does_not_count_targets() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        limiter.hit(&log(), Action::Check, limit(1), keys(&["c", "t0"]), now);
+        for i in 1..10 {
+            let target = format!("t{}", i);
+            assert!(limiter
+                .hit(&log(), Action::Check, limit(1), keys(&["c", &target]), now)
+                .is_some());
+        }
+        assert_eq!(limiter.windows.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn number_of_windows_is_capped() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+        {
+            let mut windows = limiter.windows.lock().unwrap();
+            for i in 0..MAX_WINDOWS {
+                let w = Window {
+                    ends: now + Duration::from_secs(60 + i as u64),
+                    count: 1,
+                };
+                windows.insert((Action::Check, format!("t{}", i)), w);
+            }
+        }
+        limiter.hit(&log(), Action::Check, limit(1), keys(&["c"]), now);
+        let windows = limiter.windows.lock().unwrap();
+        assert!(windows.len() <= MAX_WINDOWS * 3 / 4 + 1);
+        // the windows that end first go
+        assert!(!windows.contains_key(&(Action::Check, "t0".to_string())));
+        let last = format!("t{}", MAX_WINDOWS - 1);
+        assert!(windows.contains_key(&(Action::Check, last)));
+    }
+
+    #[test]
+    fn addresses_are_normalized() {
+        assert_eq!(
+            Target::Address(" Alice@Example.COM ").to_string(),
+            "address alice@example.com"
+        );
+        assert_eq!(Target::KeyPrefix("AbC").to_string(), "key AbC");
+    }
+}
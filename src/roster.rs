@@ -0,0 +1,286 @@
+use crate::backend::{self, Backend};
+use crate::config::Config;
+use std::collections::HashSet;
+
+/// Columns a roster CSV must have, in any order. Other columns are ignored.
+const COLUMNS: &[&str] = &["email", "name", "section", "role"];
+
+/// Role of roster entries whose role column is empty.
+const DEFAULT_ROLE: &str = "student";
+
+/// A row of the `roster` table.
+#[derive(Debug)]
+pub(crate) struct RosterEntry {
+    pub email: String,
+    pub name: String,
+    pub section: String,
+    pub role: String,
+}
+
+crate::from_row!(RosterEntry {
+    email: "email",
+    name: "name",
+    section: "section",
+    role: "role",
+});
+
+/// A roster entry on the roster view, with the number of registered users
+/// with its address.
+pub(crate) struct RosterStatusRow {
+    pub email: String,
+    pub name: String,
+    pub section: String,
+    pub role: String,
+    pub registered: u64,
+}
+
+crate::from_row!(RosterStatusRow {
+    email: "email",
+    name: "name",
+    section: "section",
+    role: "role",
+    registered: "registered",
+});
+
+/// The form email addresses are stored and compared in.
+pub(crate) fn normalize(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// The domain of `email`, if it is an address: exactly one `@`, with
+/// something before and after it.
+fn domain(email: &str) -> Option<&str> {
+    match email.split_once('@') {
+        Some((local, domain)) if !local.is_empty() && !domain.is_empty() => {
+            Some(domain).filter(|d| !d.contains('@'))
+        }
+        _ => None,
+    }
+}
+
+/// Splits a line of CSV into its fields. Fields may be quoted, with `""`
+/// for a quote inside them, but may not span lines.
+fn split_line(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".to_string());
+    }
+    fields.push(field);
+    Ok(fields.into_iter().map(|f| f.trim().to_string()).collect())
+}
+
+/// Parses a roster CSV, whose first line names the columns. Emails are
+/// lower-cased and must be unique.
+pub(crate) fn parse(csv: &str) -> Result<Vec<RosterEntry>, String> {
+    let mut lines = csv
+        .trim_start_matches('\u{feff}')
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty());
+    let header = match lines.next() {
+        Some((_, l)) => split_line(l).map_err(|e| format!("line 1: {}", e))?,
+        None => return Err("the roster is empty".to_string()),
+    };
+    let cols = COLUMNS
+        .iter()
+        .map(|c| {
+            header
+                .iter()
+                .position(|h| h.eq_ignore_ascii_case(c))
+                .ok_or_else(|| format!("the first line has no \"{}\" column", c))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut seen = HashSet::new();
+    let mut entries = vec![];
+    for (i, line) in lines {
+        let err = |e: &str| format!("line {}: {}", i + 1, e);
+        let fields = split_line(line).map_err(|e| err(&e))?;
+        let field = |c: usize| fields.get(cols[c]).cloned().unwrap_or_default();
+        let email = normalize(&field(0));
+        if domain(&email).is_none() {
+            return Err(err(&format!("\"{}\" is not an email address", email)));
+        }
+        if !seen.insert(email.clone()) {
+            return Err(err(&format!("{} is listed more than once", email)));
+        }
+        let role = match field(3).to_lowercase() {
+            r if r.is_empty() => DEFAULT_ROLE.to_string(),
+            r => r,
+        };
+        entries.push(RosterEntry {
+            email: email,
+            name: field(1),
+            section: field(2),
+            role: role,
+        });
+    }
+    Ok(entries)
+}
+
+/// Replaces the roster with `entries`.
+pub(crate) fn replace(be: &dyn Backend, entries: Vec<RosterEntry>) -> backend::Result<()> {
+    for old in be.query_as::<RosterStatusRow>("roster_status", vec![])? {
+        be.delete("roster", vec![old.email.into()])?;
+    }
+    for e in entries {
+        be.insert(
+            "roster",
+            vec![
+                e.email.into(),
+                e.name.into(),
+                e.section.into(),
+                e.role.into(),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Who may register, from the config.
+#[derive(Debug, Clone)]
+pub(crate) struct Registration {
+    roster_only: bool,
+    domains: Vec<String>,
+    admins: Vec<String>,
+}
+
+impl Registration {
+    pub fn new(config: &Config) -> Self {
+        Registration {
+            roster_only: config.registration_roster_only,
+            domains: config.registration_domains.clone(),
+            admins: config.admins.clone(),
+        }
+    }
+
+    /// Whether `email` may register: anyone may if registration is not
+    /// restricted; otherwise admins, addresses in one of the allowed domains
+    /// and, if only roster members may register, addresses on the roster.
+    pub fn allows(&self, be: &dyn Backend, email: &str) -> backend::Result<bool> {
+        if !self.roster_only && self.domains.is_empty() {
+            return Ok(true);
+        }
+        let email = normalize(email);
+        if self.admins.iter().any(|a| normalize(a) == email) {
+            return Ok(true);
+        }
+        if let Some(domain) = domain(&email) {
+            if self.domains.iter().any(|d| d == domain) {
+                return Ok(true);
+            }
+        }
+        Ok(self.roster_only
+            && !be
+                .query_as::<RosterEntry>("roster_by_email", vec![email.as_str().into()])?
+                .is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SqliteBackend;
+
+    #[test]
+    fn splits_quoted_fields() {
+        assert_eq!(
+            split_line(r#"a, "b, c" ,"say ""hi""",,"#).unwrap(),
+            vec!["a", "b, c", r#"say "hi""#, "", ""]
+        );
+        assert!(split_line(r#"a,"b"#).is_err());
+    }
+
+    #[test]
+    fn parses_columns_in_any_order() {
+        let csv = "\u{feff}Role,Email,Extra,Name,Section\n\
+                   \n\
+                   TA, Alice@Example.com ,x,\"Smith, Alice\",S01\n\
+                   ,bob@example.com,,Bob,S02\n";
+        let entries = parse(csv).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].email, "alice@example.com");
+        assert_eq!(entries[0].name, "Smith, Alice");
+        assert_eq!(entries[0].section, "S01");
+        assert_eq!(entries[0].role, "ta");
+        assert_eq!(entries[1].role, DEFAULT_ROLE);
+    }
+
+    #[test]
+    fn rejects_bad_rosters() {
+        assert!(parse("").is_err());
+        assert!(parse("email,name,section\n").is_err());
+        let err = parse("email,name,section,role\na@example.com,A,1,\nnobody,B,1,\n");
+        assert_eq!(err.unwrap_err(), "line 3: \"nobody\" is not an email address");
+        let dup = "email,name,section,role\na@example.com,A,1,\nA@example.com,A,1,\n";
+        assert!(parse(dup).is_err());
+        assert!(parse("email,name,section,role\nx@evil.com@example.com,A,1,\n").is_err());
+        assert!(parse("email,name,section,role\n\"a@example.com,A,1,\n").is_err());
+    }
+
+    fn registration(roster_only: bool, domains: &[&str]) -> Registration {
+        Registration {
+            roster_only: roster_only,
+            domains: domains.iter().map(|d| d.to_string()).collect(),
+            admins: vec!["Admin@Example.com".to_string()],
+        }
+    }
+
+    fn backend() -> SqliteBackend {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        let entries = parse("email,name,section,role\nalice@example.com,Alice,S01,\n").unwrap();
+        replace(&be, entries).unwrap();
+        be
+    }
+
+    #[test]
+    fn anyone_may_register_by_default() {
+        let be = backend();
+        assert!(registration(false, &[]).allows(&be, "x@elsewhere.org").unwrap());
+    }
+
+    #[test]
+    fn roster_only_allows_roster_and_admins() {
+        let be = backend();
+        let r = registration(true, &[]);
+        assert!(r.allows(&be, " Alice@Example.COM").unwrap());
+        assert!(r.allows(&be, "admin@example.com").unwrap());
+        assert!(!r.allows(&be, "bob@example.com").unwrap());
+    }
+
+    #[test]
+    fn domains_must_match_exactly() {
+        let be = backend();
+        let r = registration(false, &["brown.edu"]);
+        assert!(r.allows(&be, "Bob@Brown.edu").unwrap());
+        assert!(!r.allows(&be, "bob@cs.brown.edu").unwrap());
+        assert!(!r.allows(&be, "x@evil.com@brown.edu").unwrap());
+        assert!(!r.allows(&be, "@brown.edu").unwrap());
+        // either the roster or a domain is enough
+        let r = registration(true, &["brown.edu"]);
+        assert!(r.allows(&be, "alice@example.com").unwrap());
+        assert!(r.allows(&be, "bob@brown.edu").unwrap());
+    }
+}
@@ -1,14 +1,46 @@
-CREATE TABLE users (email varchar(255), apikey varchar(255), is_admin tinyint, policy TEXT, PRIMARY KEY (apikey));
+-- Tables and views are created by the migrations in migrations/. The CREATE
+-- statements here must match the latest migration: backends use them to learn
+-- each table's columns and primary key.
+-- This file is compiled into the binary; set `schema_path` in the config to
+-- use a different one. Named queries are written `QUERY <name>: SELECT ...;`
+-- and may span several lines. Queries that are read a page at a time need
+-- an ORDER BY and must not have a LIMIT of their own.
+CREATE TABLE users (email varchar(255), is_admin tinyint, policy TEXT, PRIMARY KEY (email));
+-- keys are stored as their first characters and a salted hash; `created_at`
+-- is NULL for keys derived from the email address before keys were random,
+-- and a key is valid until `revoked_at` is set or `expires_at` passes
+CREATE TABLE apikeys (prefix varchar(16), hash varchar(64), salt varchar(32), email varchar(255), created_at datetime, revoked_at datetime, expires_at datetime, PRIMARY KEY (prefix));
+-- `id` is the SHA-256 hash of the token in the session cookie; `key_prefix`
+-- is NULL for sessions started with a login link
+CREATE TABLE sessions (id varchar(64), email varchar(255), key_prefix varchar(16), created_at datetime, last_seen datetime, ended_at datetime, PRIMARY KEY (id));
+-- one-time login links, stored under the SHA-256 hash of their token
+CREATE TABLE login_links (id varchar(64), email varchar(255), created_at datetime, expires_at datetime, used_at datetime, PRIMARY KEY (id));
+-- the course roster uploaded by admins, with emails in lower case
+CREATE TABLE roster (email varchar(255), name varchar(255), section varchar(64), role varchar(32), PRIMARY KEY (email));
 CREATE TABLE lectures (id int, label varchar(255), PRIMARY KEY (id));
 CREATE TABLE questions (lec int, q int, question text, PRIMARY KEY (lec, q));
 CREATE TABLE answers (email varchar(255), lec int, q int, answer text, submitted_at datetime, policy TEXT, PRIMARY KEY (email, lec, q));
+-- the `policy` column of policied tables holds the id of a row here
+CREATE TABLE policies (id varchar(64), policy TEXT, PRIMARY KEY (id));
+-- written by the backend on every insert, update and delete
+CREATE TABLE audit_log (id varchar(32), at datetime, actor varchar(255), action varchar(16), tbl varchar(255), row_key text, old_values text, new_values text, policy TEXT, PRIMARY KEY (id));
 
 CREATE VIEW lec_qcount as SELECT questions.lec, COUNT(questions.q) AS qcount FROM questions GROUP BY questions.lec;
 QUERY leclist: SELECT lectures.id, lectures.label, lec_qcount.qcount FROM lectures LEFT JOIN lec_qcount ON (lectures.id = lec_qcount.lec);
 -- WHERE lectures.id = ?;
 QUERY lecture: SELECT * FROM lectures WHERE id = ?;
 QUERY qs_by_lec: SELECT * FROM questions WHERE lec = ?;
-QUERY answers_by_lec: SELECT * FROM answers WHERE lec = ?;
-QUERY users_by_apikey: SELECT * FROM users WHERE apikey = ?;
+QUERY answers_by_lec: SELECT * FROM answers WHERE lec = ? ORDER BY q, email;
+QUERY apikeys_by_prefix: SELECT salt, hash, email, expires_at FROM apikeys WHERE prefix = ? AND revoked_at IS NULL;
+QUERY user_by_email: SELECT email, is_admin FROM users WHERE email = ?;
+QUERY apikeys_by_email: SELECT prefix, created_at, revoked_at, expires_at FROM apikeys WHERE email = ? ORDER BY created_at DESC, prefix;
 QUERY my_answers_for_lec: SELECT answers.* FROM answers WHERE answers.lec = ? AND answers.email = ?;
-QUERY all_users: SELECT email, is_admin, apikey FROM users;
+QUERY all_users: SELECT users.email, users.is_admin, COUNT(apikeys.prefix) AS active_keys, GROUP_CONCAT(apikeys.prefix) AS key_prefixes FROM users LEFT JOIN apikeys ON (apikeys.email = users.email AND apikeys.revoked_at IS NULL) GROUP BY users.email, users.is_admin ORDER BY users.email;
+QUERY audit_log: SELECT * FROM audit_log WHERE (? IS NULL OR tbl = ?) AND (? IS NULL OR actor = ?) ORDER BY at DESC, id;
+QUERY session_by_id: SELECT * FROM sessions WHERE id = ?;
+QUERY active_sessions: SELECT * FROM sessions WHERE ended_at IS NULL ORDER BY email, created_at;
+QUERY sessions_by_email: SELECT * FROM sessions WHERE email = ? AND ended_at IS NULL;
+QUERY login_link_by_id: SELECT * FROM login_links WHERE id = ?;
+QUERY roster_by_email: SELECT * FROM roster WHERE email = ?;
+QUERY roster_status: SELECT roster.email, roster.name, roster.section, roster.role, COUNT(users.email) AS registered FROM roster LEFT JOIN users ON (LOWER(users.email) = roster.email) GROUP BY roster.email, roster.name, roster.section, roster.role ORDER BY roster.section, roster.email;
+QUERY policy_by_id: SELECT id, policy FROM policies WHERE id = ?;
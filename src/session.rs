@@ -0,0 +1,328 @@
+use crate::apikey::{ApiKeyError, KeyCandidate};
+use crate::backend::{self, AsyncBackend, Backend, Value};
+use crate::config::Config;
+use chrono::naive::NaiveDateTime;
+use chrono::{Duration, Local};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rand::Rng;
+use rocket::http::{Cookie, SameSite};
+
+/// Name of the cookie holding the session token.
+pub(crate) const SESSION_COOKIE: &str = "session";
+
+/// Random bytes in a session token.
+const TOKEN_BYTES: usize = 32;
+
+/// How often a session's last request time is written back; idle timeouts
+/// are accurate to this.
+const TOUCH_INTERVAL_SECS: i64 = 60;
+
+/// A row of the `sessions` table.
+pub(crate) struct SessionRow {
+    pub id: String,
+    pub email: String,
+    pub key_prefix: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    pub ended_at: Option<NaiveDateTime>,
+}
+
+crate::from_row!(SessionRow {
+    id: "id",
+    email: "email",
+    key_prefix: "key_prefix",
+    created_at: "created_at",
+    last_seen: "last_seen",
+    ended_at: "ended_at",
+});
+
+impl SessionRow {
+    /// Whether the session has ended, been idle too long or lasted too long
+    /// at time `now`.
+    pub fn is_expired(&self, config: &Config, now: NaiveDateTime) -> bool {
+        self.ended_at.is_some()
+            || now - self.last_seen >= Duration::minutes(config.session_idle_minutes as i64)
+            || now - self.created_at >= Duration::hours(config.session_max_hours as i64)
+    }
+
+    /// Whether the session's last request time is due to be written back at
+    /// time `now`.
+    fn needs_touch(&self, now: NaiveDateTime) -> bool {
+        now - self.last_seen >= Duration::seconds(TOUCH_INTERVAL_SECS)
+    }
+}
+
+/// The id a session is stored under. Only the hash of the token is stored,
+/// so the sessions table cannot be used to log in.
+fn session_id(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(token);
+    hasher.result_str()
+}
+
+fn now() -> NaiveDateTime {
+    Local::now().naive_local()
+}
+
+/// The cookie for a new session's token.
+pub(crate) fn cookie(config: &Config, token: String) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(config.secure_cookies)
+        .finish()
+}
+
+/// Starts a session for `email`, logged in with the API key with prefix
+/// `key_prefix` (or with a login link if `None`), and returns the token for
+/// its cookie.
+pub(crate) fn create(
+    be: &dyn Backend,
+    email: &str,
+    key_prefix: Option<&str>,
+) -> backend::Result<String> {
+    let token: String = (0..TOKEN_BYTES)
+        .map(|_| format!("{:02x}", rand::thread_rng().gen::<u8>()))
+        .collect();
+    let now = now();
+    be.insert(
+        "sessions",
+        vec![
+            session_id(&token).into(),
+            email.into(),
+            key_prefix.into(),
+            now.into(),
+            now.into(),
+            Value::NULL,
+        ],
+    )?;
+    Ok(token)
+}
+
+/// Ends the session with id `id`.
+pub(crate) fn end(be: &dyn Backend, id: &str) -> backend::Result<()> {
+    be.update("sessions", vec![id.into()], vec![(5, now().into())])
+}
+
+/// Moves the session with id `id` to the API key with prefix `key_prefix`,
+/// e.g. after the key it was created with is rotated.
+pub(crate) fn set_key(be: &dyn Backend, id: &str, key_prefix: &str) -> backend::Result<()> {
+    be.update("sessions", vec![id.into()], vec![(2, key_prefix.into())])
+}
+
+/// Looks up the session with id `id` unless it has ended, and whether the
+/// API key it was created with, if any, is still valid at time `now`.
+async fn lookup(
+    backend: &AsyncBackend,
+    id: &str,
+    now: NaiveDateTime,
+) -> backend::Result<Option<(SessionRow, bool)>> {
+    let session = match backend
+        .query_as::<SessionRow>("session_by_id", vec![id.into()])
+        .await?
+        .pop()
+    {
+        Some(s) if s.ended_at.is_none() => s,
+        _ => return Ok(None),
+    };
+    let key_valid = match session.key_prefix {
+        Some(ref prefix) => backend
+            .query_as::<KeyCandidate>("apikeys_by_prefix", vec![prefix.as_str().into()])
+            .await?
+            .into_iter()
+            .any(|k| k.email == session.email && !k.is_expired(now)),
+        None => true,
+    };
+    Ok(Some((session, key_valid)))
+}
+
+/// Returns the session for a cookie's token, if it is still valid: it has
+/// not ended or timed out, and the API key it was created with (if any) has
+/// not been revoked or expired. Sessions found to be invalid are ended.
+pub(crate) async fn check(
+    backend: &AsyncBackend,
+    config: &Config,
+    token: &str,
+) -> Result<SessionRow, ApiKeyError> {
+    check_at(backend, config, token, now()).await
+}
+
+/// `check`, at time `now`.
+async fn check_at(
+    backend: &AsyncBackend,
+    config: &Config,
+    token: &str,
+    now: NaiveDateTime,
+) -> Result<SessionRow, ApiKeyError> {
+    let id = session_id(token);
+    let (session, key_valid) = match lookup(backend, &id, now).await {
+        Ok(Some(s)) => s,
+        Ok(None) => return Err(ApiKeyError::Missing),
+        Err(e) => {
+            error!(backend.log(), "failed to look up session: {}", e);
+            return Err(ApiKeyError::BackendFailure);
+        }
+    };
+
+    if session.is_expired(config, now) || !key_valid {
+        info!(backend.log(), "session of {} expired", session.email);
+        if let Err(e) = backend.run(move |be| end(be, &id)).await {
+            error!(backend.log(), "failed to end session: {}", e);
+        }
+        return Err(ApiKeyError::Expired);
+    }
+    if session.needs_touch(now) {
+        let res = backend
+            .run(move |be| be.update("sessions", vec![id.into()], vec![(4, now.into())]))
+            .await;
+        if let Err(e) = res {
+            error!(backend.log(), "failed to update session: {}", e);
+            return Err(ApiKeyError::BackendFailure);
+        }
+    }
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SqliteBackend;
+    use std::sync::Arc;
+
+    const KEY_PREFIX: &str = "abcdefghijkl";
+
+    fn at(minutes: i64) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str("2021-01-01 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap()
+            + Duration::minutes(minutes)
+    }
+
+    fn session(created_at: NaiveDateTime, last_seen: NaiveDateTime) -> SessionRow {
+        SessionRow {
+            id: session_id("token"),
+            email: "a@example.com".to_string(),
+            key_prefix: Some(KEY_PREFIX.to_string()),
+            created_at: created_at,
+            last_seen: last_seen,
+            ended_at: None,
+        }
+    }
+
+    /// A backend with an API key for a@example.com, revoked and expiring at
+    /// the given times, and a session started with it at `started`.
+    fn backend(
+        revoked: Option<NaiveDateTime>,
+        expires: Option<NaiveDateTime>,
+        started: NaiveDateTime,
+    ) -> AsyncBackend {
+        let be = SqliteBackend::for_tests();
+        crate::migrations::up(&be, None).unwrap();
+        let key = vec![
+            KEY_PREFIX.into(),
+            "hash".into(),
+            "salt".into(),
+            "a@example.com".into(),
+            at(0).into(),
+            revoked.into(),
+            expires.into(),
+        ];
+        be.insert("apikeys", key).unwrap();
+        let s = session(started, started);
+        let row = vec![
+            s.id.into(),
+            s.email.into(),
+            s.key_prefix.into(),
+            s.created_at.into(),
+            s.last_seen.into(),
+            Value::NULL,
+        ];
+        be.insert("sessions", row).unwrap();
+        AsyncBackend::new(Arc::new(be))
+    }
+
+    async fn stored(backend: &AsyncBackend) -> SessionRow {
+        let id = session_id("token");
+        backend
+            .query_as::<SessionRow>("session_by_id", vec![id.into()])
+            .await
+            .unwrap()
+            .pop()
+            .unwrap()
+    }
+
+    #[test]
+    fn sessions_expire_when_idle() {
+        let config = Config::for_tests();
+        let idle = config.session_idle_minutes as i64;
+        let s = session(at(0), at(0));
+        assert!(!s.is_expired(&config, at(idle - 1)));
+        assert!(s.is_expired(&config, at(idle)));
+    }
+
+    #[test]
+    fn sessions_expire_after_max_hours_however_active() {
+        let config = Config::for_tests();
+        let max = config.session_max_hours as i64 * 60;
+        let s = session(at(0), at(max - 1));
+        assert!(!s.is_expired(&config, at(max - 1)));
+        assert!(s.is_expired(&config, at(max)));
+    }
+
+    #[test]
+    fn ended_sessions_are_expired() {
+        let config = Config::for_tests();
+        let mut s = session(at(0), at(0));
+        s.ended_at = Some(at(0));
+        assert!(s.is_expired(&config, at(0)));
+    }
+
+    #[test]
+    fn touches_after_interval() {
+        let s = session(at(0), at(0));
+        let interval = Duration::seconds(TOUCH_INTERVAL_SECS);
+        assert!(!s.needs_touch(at(0) + interval - Duration::seconds(1)));
+        assert!(s.needs_touch(at(0) + interval));
+    }
+
+    #[rocket::async_test]
+    async fn check_accepts_valid_session_and_touches_it() {
+        let config = Config::for_tests();
+        let backend = backend(None, Some(at(60 * 24)), at(0));
+        assert!(check_at(&backend, &config, "token", at(0)).await.is_ok());
+        assert_eq!(stored(&backend).await.last_seen, at(0));
+        assert!(check_at(&backend, &config, "token", at(5)).await.is_ok());
+        assert_eq!(stored(&backend).await.last_seen, at(5));
+        assert!(matches!(
+            check_at(&backend, &config, "other", at(5)).await,
+            Err(ApiKeyError::Missing)
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn check_rejects_revoked_key() {
+        let config = Config::for_tests();
+        let backend = backend(Some(at(1)), None, at(0));
+        assert!(matches!(
+            check_at(&backend, &config, "token", at(2)).await,
+            Err(ApiKeyError::Expired)
+        ));
+        assert!(stored(&backend).await.ended_at.is_some());
+    }
+
+    #[rocket::async_test]
+    async fn check_rejects_expired_key() {
+        let config = Config::for_tests();
+        let backend = backend(None, Some(at(10)), at(5));
+        assert!(check_at(&backend, &config, "token", at(9)).await.is_ok());
+        assert!(matches!(
+            check_at(&backend, &config, "token", at(10)).await,
+            Err(ApiKeyError::Expired)
+        ));
+        // the session has ended, so it stays rejected
+        assert!(matches!(
+            check_at(&backend, &config, "token", at(11)).await,
+            Err(ApiKeyError::Missing)
+        ));
+    }
+}
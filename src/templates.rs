@@ -0,0 +1,35 @@
+//! Names of the templates rendered by route handlers. Handlers refer to
+//! templates only through these constants, so that the self-check in
+//! `check.rs` covers every template that can be rendered.
+
+macro_rules! templates {
+    ($($(#[$doc:meta])* $name:ident = $file:expr,)*) => {
+        $($(#[$doc])* pub(crate) const $name: &str = $file;)*
+
+        /// Every template above.
+        pub(crate) const ALL: &[&str] = &[$($name),*];
+    };
+}
+
+templates! {
+    /// The page layout, passed to the other templates as their `parent`
+    LAYOUT = "layout",
+    LOGIN = "login",
+    LOGIN_LINK = "login_link",
+    RATELIMITED = "ratelimited",
+    LECLIST = "leclist",
+    QUESTIONS = "questions",
+    ANSWERS = "answers",
+    APIKEY_GENERATE = "apikey/generate",
+    APIKEY_KEYS = "apikey/keys",
+    APIKEY_REJECTED = "apikey/rejected",
+    ADMIN_LEC = "admin/lec",
+    ADMIN_LECADD = "admin/lecadd",
+    ADMIN_LEC_EDIT = "admin/lec_edit",
+    ADMIN_LEC_DELETE = "admin/lec_delete",
+    ADMIN_USERS = "admin/users",
+    ADMIN_STATS = "admin/stats",
+    ADMIN_AUDIT = "admin/audit",
+    ADMIN_SESSIONS = "admin/sessions",
+    ADMIN_ROSTER = "admin/roster",
+}